@@ -0,0 +1,562 @@
+use std::env;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where a library's headers and libraries were found.
+#[derive(Debug)]
+struct Package {
+    include_paths: Vec<PathBuf>,
+    link_paths: Vec<PathBuf>,
+    /// The libraries to link, including private dependencies when static,
+    /// if pkg-config said. Otherwise the caller has to know.
+    libs: Vec<String>,
+    version: Option<String>,
+}
+
+/// How to look for a library. An explicit root in `env_var` overrides
+/// everything else, then pkg-config is tried, then an installed CMake
+/// package config.
+struct Search {
+    name: &'static str,
+    env_var: &'static str,
+    pkg_config: &'static [&'static str],
+    cmake_package: &'static str,
+}
+
+// Not searched for when OSL is built from source
+#[cfg_attr(feature = "vendored", allow(dead_code))]
+const OSL: Search = Search {
+    name: "OSL",
+    env_var: "OSL_ROOT",
+    pkg_config: &["oslexec"],
+    cmake_package: "OSL",
+};
+
+const OIIO: Search = Search {
+    name: "OpenImageIO",
+    env_var: "OIIO_ROOT",
+    pkg_config: &["OpenImageIO"],
+    cmake_package: "OpenImageIO",
+};
+
+// Only the headers are needed, and newer OIIO installs usually bring them
+// along, so this one is optional
+const OPENEXR: Search = Search {
+    name: "OpenEXR",
+    env_var: "OPENEXR_ROOT",
+    pkg_config: &["Imath", "IlmBase", "OpenEXR"],
+    cmake_package: "OpenEXR",
+};
+
+/// The libraries OSL itself is made of.
+const OSL_LIBS: &[&str] = &["oslexec", "oslcomp", "oslquery"];
+
+/// The clang libraries oslcomp uses to preprocess shader source, in link
+/// order. Which of them exist depends on the LLVM version, e.g.
+/// clangSupport is new in LLVM 15, so missing ones are skipped.
+const CLANG_LIBS: &[&str] = &[
+    "clangFrontend",
+    "clangDriver",
+    "clangSerialization",
+    "clangParse",
+    "clangSema",
+    "clangAPINotes",
+    "clangAnalysis",
+    "clangASTMatchers",
+    "clangAST",
+    "clangEdit",
+    "clangLex",
+    "clangSupport",
+    "clangBasic",
+];
+
+/// What OpenImageIO may need when linked statically, if pkg-config can't
+/// say. Those that can't be found as static libraries are assumed to be
+/// system libraries, except for OpenImageIO itself.
+const OIIO_STATIC_LIBS: &[&str] = &[
+    "OpenImageIO",
+    "OpenImageIO_Util",
+    "OpenEXRUtil",
+    "OpenEXR",
+    "OpenEXRCore",
+    "IlmImf",
+    "IlmThread",
+    "Iex",
+    "Imath",
+    "Half",
+    "pugixml",
+    "tiff",
+    "png",
+    "jpeg",
+    "z",
+];
+
+pub fn main() {
+    println!("cargo:rerun-if-changed=osl_capi/osl_capi.cpp");
+    println!("cargo:rerun-if-changed=osl_capi/osl_capi.h");
+
+    let batched = env::var("CARGO_FEATURE_BATCHED").is_ok();
+    // Link OSL's dependencies (OpenImageIO, OpenEXR, LLVM...) statically
+    let static_deps = env::var("CARGO_FEATURE_STATIC").is_ok();
+    // Build OSL from source. It is always built as static libraries.
+    let vendored = cfg!(feature = "vendored");
+
+    let oiio = OIIO
+        .find(static_deps)
+        .unwrap_or_else(|tried| not_found(&OIIO, &tried));
+    let openexr = OPENEXR.find(static_deps).ok();
+    #[cfg(feature = "vendored")]
+    let osl = build_osl(&oiio, batched);
+    #[cfg(not(feature = "vendored"))]
+    let osl = OSL
+        .find(static_deps)
+        .unwrap_or_else(|tried| not_found(&OSL, &tried));
+
+    let version = osl_version(&osl).unwrap_or_else(|| {
+        panic!(
+            "\n\nFound OSL headers in {:?} but could not determine the OSL version \
+             from OSL/oslversion.h. Set OSL_ROOT to the prefix OSL was installed to.\n\n",
+            osl.include_paths
+        )
+    });
+    // Passed on to the build scripts of crates that depend on this one as
+    // DEP_OSL_CAPI_VERSION
+    println!("cargo:version={}.{}.{}", version.0, version.1, version.2);
+
+    let mut build = cc::Build::new();
+    build
+        .cpp(true)
+        .file("osl_capi/osl_capi.cpp")
+        .flag_if_supported("-std=c++14")
+        .flag_if_supported("-Wno-deprecated-register")
+        .flag_if_supported("-Wno-deprecated");
+    for package in [Some(&osl), Some(&oiio), openexr.as_ref()].iter().flatten() {
+        build.includes(&package.include_paths);
+    }
+    if batched {
+        build.define("OSL_CAPI_BATCHED", None);
+    }
+    build.compile("osl_capi");
+
+    // The header is plain C, so the bindings don't need any of the OSL
+    // headers to generate
+    let mut bindings = bindgen::Builder::default()
+        .header("osl_capi/osl_capi.h")
+        .blocklist_type("TypeDesc")
+        .blocklist_type("ROI")
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks));
+    if batched {
+        bindings = bindings.clang_arg("-DOSL_CAPI_BATCHED");
+    }
+    bindings
+        .generate()
+        .expect("Could not generate bindings from osl_capi.h")
+        .write_to_file(PathBuf::from(env::var("OUT_DIR").unwrap()).join("osl_capi.rs"))
+        .expect("Could not write bindings");
+
+    let mut link_paths = osl.link_paths.clone();
+    for package in [Some(&oiio), openexr.as_ref()].iter().flatten() {
+        link_paths.extend(package.link_paths.iter().cloned());
+    }
+    for path in &link_paths {
+        println!("cargo:rustc-link-search=native={}", path.display());
+    }
+
+    let osl_kind = if static_deps || vendored {
+        "static"
+    } else {
+        "dylib"
+    };
+    for lib in OSL_LIBS {
+        println!("cargo:rustc-link-lib={}={}", osl_kind, lib);
+    }
+    // Whatever else pkg-config says OSL needs, e.g. its private dependencies
+    // when static
+    for lib in osl.libs.iter().filter(|l| !OSL_LIBS.contains(&l.as_str())) {
+        if osl_kind == "static" && find_static_lib(&link_paths, lib).is_some() {
+            println!("cargo:rustc-link-lib=static={}", lib);
+        } else {
+            println!("cargo:rustc-link-lib=dylib={}", lib);
+        }
+    }
+
+    // A shared OSL brings its own dependencies along, static ones don't
+    if osl_kind == "static" {
+        link_llvm(static_deps);
+    }
+
+    if static_deps {
+        let libs = if oiio.libs.is_empty() {
+            OIIO_STATIC_LIBS.iter().map(|l| l.to_string()).collect()
+        } else {
+            oiio.libs.clone()
+        };
+        for lib in &libs {
+            if let Some(name) = find_static_lib(&link_paths, lib) {
+                println!("cargo:rustc-link-lib=static={}", name);
+            } else if lib == "OpenImageIO" {
+                panic!(
+                    "\n\nThe static feature is enabled but there is no static libOpenImageIO \
+                     in {:?}. Build OpenImageIO with BUILD_SHARED_LIBS=OFF and set OIIO_ROOT \
+                     to where it was installed.\n\n",
+                    link_paths
+                );
+            } else if oiio.libs.contains(lib) {
+                // Not something we can link statically, probably a system
+                // library like libm or libdl
+                println!("cargo:rustc-link-lib=dylib={}", lib);
+            }
+        }
+    } else {
+        println!("cargo:rustc-link-lib=dylib=OpenImageIO");
+    }
+}
+
+/// Build OSL from the source tarball named by OSL_SOURCE_TARBALL, against
+/// the OpenImageIO that was found, and return where it was installed.
+#[cfg(feature = "vendored")]
+fn build_osl(oiio: &Package, batched: bool) -> Package {
+    println!("cargo:rerun-if-env-changed=OSL_SOURCE_TARBALL");
+    let tarball = env::var_os("OSL_SOURCE_TARBALL")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            panic!(
+                "\n\nThe vendored feature builds OSL from source, but OSL_SOURCE_TARBALL is not \
+             set. Set it to the path of an OpenShadingLanguage source release, e.g. \
+             OpenShadingLanguage-1.12.14.0.tar.gz.\n\n"
+            )
+        });
+    println!("cargo:rerun-if-changed={}", tarball.display());
+
+    // Each tarball gets its own directory to unpack, build and install in,
+    // so that pointing OSL_SOURCE_TARBALL at another release rebuilds from
+    // that instead of reusing what was unpacked before
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let build_dir = out_dir.join(format!("osl-{:016x}", tarball_key(&tarball)));
+    let src_dir = build_dir.join("src");
+    if !src_dir.is_dir() {
+        // Builds of other tarballs are of no more use
+        for entry in fs::read_dir(&out_dir).unwrap().filter_map(|e| e.ok()) {
+            if entry.file_name().to_string_lossy().starts_with("osl-") {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+        let file = fs::File::open(&tarball)
+            .unwrap_or_else(|e| panic!("Could not open {}: {}", tarball.display(), e));
+        tar::Archive::new(flate2::read::GzDecoder::new(file))
+            .unpack(&src_dir)
+            .unwrap_or_else(|e| panic!("Could not unpack {}: {}", tarball.display(), e));
+    }
+
+    // Releases unpack to a single OpenShadingLanguage-x.y.z directory
+    let source = fs::read_dir(&src_dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .find(|path| path.join("CMakeLists.txt").is_file())
+        .unwrap_or_else(|| {
+            panic!(
+                "{} doesn't look like an OSL source release: no CMakeLists.txt found",
+                tarball.display()
+            )
+        });
+
+    let oiio_root = oiio
+        .include_paths
+        .first()
+        .and_then(|include| include.parent())
+        .expect("Could not determine the OpenImageIO prefix");
+
+    let mut config = cmake::Config::new(&source);
+    config
+        .out_dir(&build_dir)
+        .define("BUILD_SHARED_LIBS", "OFF")
+        .define("OSL_BUILD_TESTS", "OFF")
+        .define("OSL_BUILD_MATERIALX", "OFF")
+        .define("USE_QT", "OFF")
+        .define("USE_PARTIO", "OFF")
+        .define("INSTALL_DOCS", "OFF")
+        .define("OpenImageIO_ROOT", oiio_root);
+    if let Some(prefix_path) = env::var_os("CMAKE_PREFIX_PATH") {
+        config.define("CMAKE_PREFIX_PATH", prefix_path);
+    }
+    if batched {
+        config.define("USE_BATCHED", "b8_AVX2,b16_AVX512");
+    }
+    let prefix = config.build();
+
+    Package::from_prefix(&prefix)
+}
+
+/// Identifies the contents of a source tarball by its path, size and
+/// modification time, which is cheaper than hashing the whole thing.
+#[cfg(feature = "vendored")]
+fn tarball_key(tarball: &Path) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let metadata = fs::metadata(tarball)
+        .unwrap_or_else(|e| panic!("Could not open {}: {}", tarball.display(), e));
+    let mut hasher = DefaultHasher::new();
+    tarball.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    metadata.modified().ok().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Link the clang and LLVM libraries that a static OSL needs, as reported
+/// by llvm-config (or the one named by LLVM_CONFIG, which should be the
+/// LLVM OSL was built against).
+fn link_llvm(static_llvm: bool) {
+    println!("cargo:rerun-if-env-changed=LLVM_CONFIG");
+    let llvm_config = env::var("LLVM_CONFIG").unwrap_or_else(|_| "llvm-config".into());
+    let link = if static_llvm {
+        "--link-static"
+    } else {
+        "--link-shared"
+    };
+
+    let run = |args: &[&str]| -> String {
+        let output = Command::new(&llvm_config)
+            .arg(link)
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| {
+                panic!(
+                    "\n\nLinking OSL statically needs the LLVM it was built against, but \
+                     running {} failed: {}. Set LLVM_CONFIG to the path of its llvm-config.\n\n",
+                    llvm_config, e
+                )
+            });
+        if !output.status.success() {
+            panic!(
+                "{} {} failed:\n{}",
+                llvm_config,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let libdir = PathBuf::from(run(&["--libdir"]).trim());
+    println!("cargo:rustc-link-search=native={}", libdir.display());
+
+    // llvm-config doesn't know about clang, which installs its libraries
+    // alongside LLVM's. They have to come first as they depend on LLVM.
+    let kind = if static_llvm { "static" } else { "dylib" };
+    let shared = |name: &str| {
+        libdir
+            .join(format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX))
+            .is_file()
+    };
+    if !static_llvm && shared("clang-cpp") {
+        // Shared builds of clang usually come as this one library
+        println!("cargo:rustc-link-lib=dylib=clang-cpp");
+    } else {
+        let dirs = [libdir.clone()];
+        let clang_libs: Vec<_> = CLANG_LIBS
+            .iter()
+            .filter(|&&lib| {
+                if static_llvm {
+                    find_static_lib(&dirs, lib).is_some()
+                } else {
+                    shared(lib)
+                }
+            })
+            .collect();
+        if clang_libs.is_empty() {
+            panic!(
+                "\n\nLinking OSL statically needs the clang libraries of the LLVM it was \
+                 built against, but none were found in {}. Install clang's development \
+                 libraries, or set LLVM_CONFIG to the llvm-config of an LLVM that has them.\n\n",
+                libdir.display()
+            );
+        }
+        for lib in clang_libs {
+            println!("cargo:rustc-link-lib={}={}", kind, lib);
+        }
+    }
+
+    for lib in run(&["--libs"]).split_whitespace() {
+        if let Some(name) = lib.strip_prefix("-l") {
+            println!("cargo:rustc-link-lib={}={}", kind, name);
+        }
+    }
+    for lib in run(&["--system-libs"]).split_whitespace() {
+        if let Some(name) = lib.strip_prefix("-l") {
+            println!("cargo:rustc-link-lib=dylib={}", name);
+        }
+    }
+}
+
+/// The name to link a static library called `name` in one of `paths` by,
+/// if there is one. Some libraries (e.g. OpenEXR) put their version in the
+/// file name, so libOpenEXR-3_1.a is found as OpenEXR-3_1.
+fn find_static_lib(paths: &[PathBuf], name: &str) -> Option<String> {
+    let versioned = format!("lib{}-", name);
+    paths.iter().find_map(|path| {
+        if path.join(format!("lib{}.a", name)).is_file() {
+            return Some(name.to_string());
+        }
+        fs::read_dir(path).ok()?.find_map(|entry| {
+            let file = entry.ok()?.file_name().into_string().ok()?;
+            if file.starts_with(&versioned) && file.ends_with(".a") {
+                Some(file["lib".len()..file.len() - ".a".len()].to_string())
+            } else {
+                None
+            }
+        })
+    })
+}
+
+impl Search {
+    /// Find the package, or return a description of each place that was
+    /// tried.
+    fn find(&self, statik: bool) -> Result<Package, Vec<String>> {
+        let mut tried = Vec::new();
+
+        println!("cargo:rerun-if-env-changed={}", self.env_var);
+        if let Some(root) = env::var_os(self.env_var) {
+            let root = PathBuf::from(root);
+            if root.join("include").is_dir() {
+                return Ok(Package::from_prefix(&root));
+            }
+            // Don't quietly fall back to something else if the user asked
+            // for a particular install
+            panic!(
+                "\n\n{} is set to {}, but {} does not exist. Set it to the prefix {} was \
+                 installed to, i.e. the directory containing include/ and lib/.\n\n",
+                self.env_var,
+                root.display(),
+                root.join("include").display(),
+                self.name
+            );
+        }
+        tried.push(format!("{} is not set", self.env_var));
+
+        for name in self.pkg_config {
+            match pkg_config::Config::new()
+                .cargo_metadata(false)
+                .env_metadata(true)
+                .statik(statik)
+                .probe(name)
+            {
+                Ok(lib) => {
+                    return Ok(Package {
+                        include_paths: lib.include_paths,
+                        link_paths: lib.link_paths,
+                        libs: lib.libs,
+                        version: Some(lib.version),
+                    })
+                }
+                Err(e) => tried.push(format!("pkg-config {}: {}", name, first_line(&e))),
+            }
+        }
+
+        println!("cargo:rerun-if-env-changed=CMAKE_PREFIX_PATH");
+        for prefix in cmake_prefixes() {
+            for lib in &["lib", "lib64"] {
+                let config = prefix
+                    .join(lib)
+                    .join("cmake")
+                    .join(self.cmake_package)
+                    .join(format!("{}Config.cmake", self.cmake_package));
+                if config.is_file() {
+                    return Ok(Package::from_prefix(&prefix));
+                }
+            }
+        }
+        tried.push(format!(
+            "no {}Config.cmake under CMAKE_PREFIX_PATH or the default prefixes",
+            self.cmake_package
+        ));
+
+        Err(tried)
+    }
+}
+
+impl Package {
+    fn from_prefix(prefix: &Path) -> Package {
+        let link_paths = ["lib", "lib64"]
+            .iter()
+            .map(|lib| prefix.join(lib))
+            .filter(|p| p.is_dir())
+            .collect();
+        Package {
+            include_paths: vec![prefix.join("include")],
+            link_paths,
+            libs: Vec::new(),
+            version: None,
+        }
+    }
+}
+
+/// The OSL version as (major, minor, patch), read from oslversion.h or
+/// failing that from the pkg-config version.
+fn osl_version(osl: &Package) -> Option<(u32, u32, u32)> {
+    for include in &osl.include_paths {
+        if let Ok(header) = fs::read_to_string(include.join("OSL").join("oslversion.h")) {
+            let define = |name: &str| {
+                header.lines().find_map(|line| {
+                    let mut words = line.split_whitespace();
+                    match (words.next(), words.next(), words.next()) {
+                        (Some("#define"), Some(n), Some(value)) if n == name => {
+                            value.parse::<u32>().ok()
+                        }
+                        _ => None,
+                    }
+                })
+            };
+            if let (Some(major), Some(minor), Some(patch)) = (
+                define("OSL_LIBRARY_VERSION_MAJOR"),
+                define("OSL_LIBRARY_VERSION_MINOR"),
+                define("OSL_LIBRARY_VERSION_PATCH"),
+            ) {
+                return Some((major, minor, patch));
+            }
+        }
+    }
+
+    let mut parts = osl
+        .version
+        .as_ref()?
+        .split('.')
+        .map(|p| p.parse::<u32>().ok());
+    Some((
+        parts.next()??,
+        parts.next()??,
+        parts.next().flatten().unwrap_or(0),
+    ))
+}
+
+/// The prefixes to look for CMake package configs in: those given in
+/// CMAKE_PREFIX_PATH, then the usual system ones.
+fn cmake_prefixes() -> Vec<PathBuf> {
+    let mut prefixes = env::var_os("CMAKE_PREFIX_PATH")
+        .map(|p| env::split_paths(&p).collect::<Vec<_>>())
+        .unwrap_or_default();
+    prefixes.extend(
+        ["/usr/local", "/usr", "/opt/local"]
+            .iter()
+            .map(PathBuf::from),
+    );
+    prefixes
+}
+
+fn first_line(e: &pkg_config::Error) -> String {
+    e.to_string().lines().next().unwrap_or("").to_string()
+}
+
+fn not_found(search: &Search, tried: &[String]) -> ! {
+    panic!(
+        "\n\nCould not find {name}. Tried:\n    {tried}\n\n\
+         Either set {var} to the prefix {name} was installed to (the directory \
+         containing include/ and lib/), add its lib/pkgconfig directory to \
+         PKG_CONFIG_PATH, or add its prefix to CMAKE_PREFIX_PATH.\n\n",
+        name = search.name,
+        tried = tried.join("\n    "),
+        var = search.env_var,
+    )
+}
@@ -0,0 +1,931 @@
+#include <OpenImageIO/imagebufalgo.h>
+
+#include <OSL/oslcomp.h>
+#include <OSL/oslexec.h>
+#include <OSL/oslnoise.h>
+#include <OSL/oslquery.h>
+#include <OpenImageIO/errorhandler.h>
+#include <OpenImageIO/imagebuf.h>
+
+#include <algorithm>
+#include <cstdlib>
+#include <cstring>
+#include <vector>
+
+#ifdef OSL_CAPI_BATCHED
+#include <OSL/batched_rendererservices.h>
+#include <OSL/batched_shaderglobals.h>
+#endif
+
+typedef struct OSL::ShadingSystem* ShadingSystem;
+typedef struct OSL::RendererServices* RendererServicesBase;
+typedef struct OSL::TextureSystem* TextureSystem;
+typedef struct OSL::ShaderGlobals* ShaderGlobals;
+typedef struct OSL::PerThreadInfo* PerThreadInfo;
+typedef struct OSL::ShadingContext* ShadingContext;
+typedef struct OSL::ShaderGlobals* ShaderGlobalsPtr;
+typedef const struct OSL::ShaderGlobals* ConstShaderGlobalsPtr;
+typedef const struct OSL::ShaderSymbol* ShaderSymbolPtr;
+typedef struct OSL::ClosureColor* ClosureColorPtr;
+typedef struct OIIO::ImageBuf* ImageBufPtr;
+typedef OSL::OSLCompiler* OSLCompiler;
+typedef OSL::OSLQuery* OSLQuery;
+typedef const OSL::OSLQuery::Parameter* OSLQueryParameter;
+
+typedef const char* ustring;
+
+typedef void (*ErrorHandlerImpl)(int, const char*);
+typedef void (*ErrorHandlerDataImpl)(void*, int, const char*);
+
+class ErrorHandlerWrapper : public OIIO::ErrorHandler {
+    ErrorHandlerImpl _error_handler_impl = nullptr;
+    ErrorHandlerDataImpl _error_handler_data_impl = nullptr;
+    void* _data = nullptr;
+
+public:
+    ErrorHandlerWrapper(ErrorHandlerImpl impl) : _error_handler_impl(impl) {}
+    ErrorHandlerWrapper(ErrorHandlerDataImpl impl, void* data)
+        : _error_handler_data_impl(impl), _data(data) {}
+
+    virtual void operator()(int error_code, const std::string& msg) {
+        if (_error_handler_data_impl) {
+            _error_handler_data_impl(_data, error_code, msg.c_str());
+        } else {
+            _error_handler_impl(error_code, msg.c_str());
+        }
+    }
+};
+
+typedef struct ErrorHandlerWrapper* ErrorHandler;
+
+typedef const void* TransformationPtr;
+
+typedef int (*RSFn_supports)(void* rs_obj, const char* feature);
+typedef int (*RSFn_get_matrix)(void* rs_obj, ShaderGlobals sg,
+                               OSL::Matrix44* result, TransformationPtr xform);
+typedef int (*RSFn_get_userdata)(void* rs_obj, bool derivatives,
+                                 const char* name, OIIO::TypeDesc type,
+                                 ShaderGlobals sg, void* val);
+typedef int (*RSFn_texture)(void* rs_obj, const char* filename,
+                            ShaderGlobals sg, const float* s, const float* t,
+                            int nchannels, float* result, float* dresultds,
+                            float* dresultdt);
+
+#ifdef OSL_CAPI_BATCHED
+typedef unsigned int (*RSFn_batched_get_matrix)(
+    void* rs_obj, int width, void* renderstate, const TransformationPtr* xforms,
+    const float* times, unsigned int mask, float* result);
+
+// Forwards the batched callbacks OSL makes for a batch of width W to the
+// function pointers set on the owning RendererServicesWrapperApi
+template <int W>
+class BatchedRendererServicesWrapper : public OSL::BatchedRendererServices<W> {
+public:
+    void** _rs_obj;
+    RSFn_batched_get_matrix* _get_matrix;
+
+    BatchedRendererServicesWrapper(void** rs_obj,
+                                   RSFn_batched_get_matrix* get_matrix)
+        : OSL::BatchedRendererServices<W>(nullptr), _rs_obj(rs_obj),
+          _get_matrix(get_matrix) {}
+
+    virtual OSL::Mask<W>
+    get_matrix(OSL::BatchedShaderGlobals<W>* bsg,
+               OSL::Masked<OSL::Matrix44, W> wresult,
+               OSL::Wide<const TransformationPtr, W> wxform,
+               OSL::Wide<const float, W> wtime) {
+        TransformationPtr xforms[W];
+        float times[W];
+        float result[W][16];
+        for (int i = 0; i < W; ++i) {
+            xforms[i] = wxform[i];
+            times[i] = wtime[i];
+        }
+
+        unsigned int mask = (*_get_matrix)(
+            *_rs_obj, W, bsg->uniform.renderstate, xforms, times,
+            wresult.mask().value(), &result[0][0]);
+
+        OSL::Mask<W> done(mask);
+        for (int i = 0; i < W; ++i) {
+            if (done.is_on(i)) {
+                wresult[i] = *(OSL::Matrix44*)result[i];
+            }
+        }
+        return done;
+    }
+};
+#endif
+
+class RendererServicesWrapperApi : public OSL::RendererServices {
+public:
+    void* _rs_obj;
+    RSFn_supports _supports = nullptr;
+    RSFn_get_matrix _get_matrix = nullptr;
+    RSFn_get_userdata _get_userdata = nullptr;
+    RSFn_texture _texture = nullptr;
+
+#ifdef OSL_CAPI_BATCHED
+    RSFn_batched_get_matrix _batched_get_matrix = nullptr;
+    BatchedRendererServicesWrapper<8> _batched8{&_rs_obj,
+                                                &_batched_get_matrix};
+    BatchedRendererServicesWrapper<16> _batched16{&_rs_obj,
+                                                  &_batched_get_matrix};
+
+    virtual OSL::BatchedRendererServices<8>* batched(OSL::WidthOf<8>) {
+        return _batched_get_matrix ? &_batched8 : nullptr;
+    }
+
+    virtual OSL::BatchedRendererServices<16>* batched(OSL::WidthOf<16>) {
+        return _batched_get_matrix ? &_batched16 : nullptr;
+    }
+#endif
+
+    // RendererServicesWrapper(void* rs_obj) : _rs_obj(rs_obj) {}
+
+    virtual int supports(const char* feature) {
+        if (_supports) {
+            return _supports(_rs_obj, feature);
+        } else {
+            return OSL::RendererServices::supports(feature);
+        }
+    }
+
+    virtual bool get_matrix(ShaderGlobals sg, OSL::Matrix44& result,
+                            TransformationPtr xform) {
+        if (_get_matrix) {
+            return _get_matrix(_rs_obj, sg, &result, xform);
+        } else {
+            return OSL::RendererServices::get_matrix(sg, result, xform);
+        }
+    }
+
+    virtual bool get_userdata(bool derivatives, OSL::ustring name,
+                              OIIO::TypeDesc type, ShaderGlobals sg,
+                              void* val) {
+        if (_get_userdata) {
+            return _get_userdata(_rs_obj, derivatives, name.c_str(), type, sg,
+                                 val);
+        } else {
+            return OSL::RendererServices::get_userdata(derivatives, name, type,
+                                                       sg, val);
+        }
+    }
+
+    virtual bool texture(OSL::ustring filename, TextureHandle* texture_handle,
+                         TexturePerthread* texture_thread_info,
+                         OSL::TextureOpt& options, ShaderGlobals sg, float s,
+                         float t, float dsdx, float dtdx, float dsdy,
+                         float dtdy, int nchannels, float* result,
+                         float* dresultds, float* dresultdt,
+                         OSL::ustring* errormessage) {
+        if (_texture) {
+            const float sd[3] = {s, dsdx, dsdy};
+            const float td[3] = {t, dtdx, dtdy};
+            int found = _texture(_rs_obj, filename.c_str(), sg, sd, td,
+                                 nchannels, result, dresultds, dresultdt);
+            if (found >= 0) {
+                return found;
+            }
+        }
+        return OSL::RendererServices::texture(
+            filename, texture_handle, texture_thread_info, options, sg, s, t,
+            dsdx, dtdx, dsdy, dtdy, nchannels, result, dresultds, dresultdt,
+            errormessage);
+    }
+};
+
+typedef RendererServicesWrapperApi* RendererServicesWrapper;
+
+struct ShaderGroupRefApi {
+    ShaderGroupRefApi(std::shared_ptr<OSL::ShaderGroup> group) : group(group) {}
+    std::shared_ptr<OSL::ShaderGroup> group;
+};
+
+typedef ShaderGroupRefApi* ShaderGroupRef;
+
+// The handles above are what osl_capi.h's declarations refer to here
+#define OSL_CAPI_IMPLEMENTATION
+#include "osl_capi.h"
+
+#include <cstddef>
+
+// osl_capi.h declares C mirrors of these structs for the Rust bindings to be
+// generated from. Make sure they haven't drifted from the real thing.
+#define OSL_CAPI_CHECK_FIELD(mirror, field, real, real_field)                  \
+    static_assert(offsetof(mirror, field) == offsetof(real, real_field),       \
+                  #mirror "::" #field " does not match " #real "::" #real_field)
+
+static_assert(sizeof(TypeDesc) == sizeof(OIIO::TypeDesc) &&
+                  sizeof(TypeDesc) == OSL_CAPI_TYPEDESC_SIZE,
+              "TypeDesc does not match OIIO::TypeDesc");
+OSL_CAPI_CHECK_FIELD(TypeDesc, basetype, OIIO::TypeDesc, basetype);
+OSL_CAPI_CHECK_FIELD(TypeDesc, aggregate, OIIO::TypeDesc, aggregate);
+OSL_CAPI_CHECK_FIELD(TypeDesc, vecsemantics, OIIO::TypeDesc, vecsemantics);
+OSL_CAPI_CHECK_FIELD(TypeDesc, arraylen, OIIO::TypeDesc, arraylen);
+
+static_assert(sizeof(ROI) == sizeof(OIIO::ROI) &&
+                  sizeof(ROI) == OSL_CAPI_ROI_SIZE,
+              "ROI does not match OIIO::ROI");
+OSL_CAPI_CHECK_FIELD(ROI, xbegin, OIIO::ROI, xbegin);
+OSL_CAPI_CHECK_FIELD(ROI, chend, OIIO::ROI, chend);
+
+static_assert(sizeof(Vec3) == sizeof(OSL::Vec3),
+              "Vec3 does not match OSL::Vec3");
+OSL_CAPI_CHECK_FIELD(Vec3, x, OSL::Vec3, x);
+OSL_CAPI_CHECK_FIELD(Vec3, z, OSL::Vec3, z);
+
+static_assert(sizeof(Color3) == sizeof(OSL::Color3),
+              "Color3 does not match OSL::Color3");
+OSL_CAPI_CHECK_FIELD(Color3, r, OSL::Color3, x);
+OSL_CAPI_CHECK_FIELD(Color3, b, OSL::Color3, z);
+
+static_assert(sizeof(Matrix44) == sizeof(OSL::Matrix44),
+              "Matrix44 does not match OSL::Matrix44");
+OSL_CAPI_CHECK_FIELD(Matrix44, x, OSL::Matrix44, x);
+
+static_assert(sizeof(ClosureParam) == sizeof(OSL::ClosureParam),
+              "ClosureParam does not match OSL::ClosureParam");
+OSL_CAPI_CHECK_FIELD(ClosureParam, typedesc, OSL::ClosureParam, type);
+OSL_CAPI_CHECK_FIELD(ClosureParam, offset, OSL::ClosureParam, offset);
+OSL_CAPI_CHECK_FIELD(ClosureParam, key, OSL::ClosureParam, key);
+OSL_CAPI_CHECK_FIELD(ClosureParam, field_size, OSL::ClosureParam, field_size);
+
+#define OSL_CAPI_CHECK_SG_FIELD(field)                                         \
+    OSL_CAPI_CHECK_FIELD(ShaderGlobalsApi, field, OSL::ShaderGlobals, field)
+
+static_assert(sizeof(ShaderGlobalsApi) == sizeof(OSL::ShaderGlobals) &&
+                  alignof(ShaderGlobalsApi) == alignof(OSL::ShaderGlobals),
+              "ShaderGlobalsApi does not match OSL::ShaderGlobals");
+OSL_CAPI_CHECK_SG_FIELD(P);
+OSL_CAPI_CHECK_SG_FIELD(dPdx);
+OSL_CAPI_CHECK_SG_FIELD(dPdy);
+OSL_CAPI_CHECK_SG_FIELD(dPdz);
+OSL_CAPI_CHECK_SG_FIELD(I);
+OSL_CAPI_CHECK_SG_FIELD(dIdx);
+OSL_CAPI_CHECK_SG_FIELD(dIdy);
+OSL_CAPI_CHECK_SG_FIELD(N);
+OSL_CAPI_CHECK_SG_FIELD(Ng);
+OSL_CAPI_CHECK_SG_FIELD(u);
+OSL_CAPI_CHECK_SG_FIELD(dudx);
+OSL_CAPI_CHECK_SG_FIELD(dudy);
+OSL_CAPI_CHECK_SG_FIELD(v);
+OSL_CAPI_CHECK_SG_FIELD(dvdx);
+OSL_CAPI_CHECK_SG_FIELD(dvdy);
+OSL_CAPI_CHECK_SG_FIELD(dPdu);
+OSL_CAPI_CHECK_SG_FIELD(dPdv);
+OSL_CAPI_CHECK_SG_FIELD(time);
+OSL_CAPI_CHECK_SG_FIELD(dtime);
+OSL_CAPI_CHECK_SG_FIELD(dPdtime);
+OSL_CAPI_CHECK_SG_FIELD(Ps);
+OSL_CAPI_CHECK_SG_FIELD(dPsdx);
+OSL_CAPI_CHECK_SG_FIELD(dPsdy);
+OSL_CAPI_CHECK_SG_FIELD(renderstate);
+OSL_CAPI_CHECK_SG_FIELD(tracedata);
+OSL_CAPI_CHECK_SG_FIELD(objdata);
+OSL_CAPI_CHECK_SG_FIELD(context);
+OSL_CAPI_CHECK_SG_FIELD(renderer);
+OSL_CAPI_CHECK_SG_FIELD(object2common);
+OSL_CAPI_CHECK_SG_FIELD(shader2common);
+OSL_CAPI_CHECK_SG_FIELD(Ci);
+OSL_CAPI_CHECK_SG_FIELD(surfacearea);
+OSL_CAPI_CHECK_SG_FIELD(raytype);
+OSL_CAPI_CHECK_SG_FIELD(flipHandedness);
+OSL_CAPI_CHECK_SG_FIELD(backfacing);
+
+extern "C" {
+
+// FIXME: texture system
+ShadingSystem ShadingSystem_create(RendererServicesWrapper renderer) {
+    return new OSL::ShadingSystem(renderer, NULL, NULL);
+}
+
+ShadingSystem
+ShadingSystem_create_with_error_handler(RendererServicesWrapper renderer,
+                                        ErrorHandler eh) {
+    return new OSL::ShadingSystem(renderer, NULL, eh);
+}
+
+void ShadingSystem_destroy(ShadingSystem ss) { delete ss; }
+
+void ShadingSystem_register_closure(ShadingSystem ss, const char* name, int id,
+                                    const ClosureParam* params) {
+    ss->register_closure(name, id, (const OSL::ClosureParam*)params, NULL,
+                         NULL);
+}
+
+bool ShadingSystem_attribute(ShadingSystem ss, const char* name,
+                             TypeDesc typedesc, const void* val) {
+    return ss->attribute(name, *(OIIO::TypeDesc*)&typedesc, val);
+}
+
+bool ShadingSystem_getattribute(ShadingSystem ss, const char* name,
+                                TypeDesc typedesc, void* val) {
+    return ss->getattribute(name, *(OIIO::TypeDesc*)&typedesc, val);
+}
+
+bool ShadingSystem_group_attribute(ShadingSystem ss, ShaderGroupRef group,
+                                   const char* name, TypeDesc typedesc,
+                                   const void* val) {
+    return ss->attribute(group->group.get(), name, *(OIIO::TypeDesc*)&typedesc,
+                         val);
+}
+
+bool ShadingSystem_group_getattribute(ShadingSystem ss, ShaderGroupRef group,
+                                      const char* name, TypeDesc typedesc,
+                                      void* val) {
+    return ss->getattribute(group->group.get(), name,
+                            *(OIIO::TypeDesc*)&typedesc, val);
+}
+
+// For the group attributes that OSL returns as a pointer to an array owned by
+// the group (textures_needed, closures_needed, globals_needed,
+// userdata_names, userdata_types...)
+bool ShadingSystem_group_getattribute_ptr(ShadingSystem ss,
+                                          ShaderGroupRef group,
+                                          const char* name, const void** val) {
+    return ss->getattribute(group->group.get(), name, OIIO::TypeDesc::PTR,
+                            val);
+}
+
+// The number of elements in the array attribute `name` of the group, or of
+// the shading system if group is NULL, which OSL doesn't report directly.
+// Returns -1 if it isn't an array attribute.
+int ShadingSystem_getattribute_arraylen(ShadingSystem ss, ShaderGroupRef group,
+                                        const char* name) {
+    OIIO::string_view attr(name);
+    if (group) {
+        // Group arrays each have an attribute counting them
+        const char* count = nullptr;
+        if (attr == "layer_names" || attr == "layer_osofiles")
+            count = "num_layers";
+        else if (attr == "entry_layers")
+            count = "num_entry_layers";
+        else if (attr == "renderer_outputs")
+            count = "num_renderer_outputs";
+        int n = -1;
+        if (!count
+            || !ss->getattribute(group->group.get(), count, OIIO::TypeInt, &n))
+            return -1;
+        return n;
+    }
+
+    // The shading system's arrays are lists of names, and asking for more
+    // elements than there are leaves the rest empty
+    if (attr != "raytypes" && attr != "renderer_outputs")
+        return -1;
+    std::vector<OIIO::ustring> names(16);
+    for (;;) {
+        OIIO::TypeDesc type(OIIO::TypeDesc::STRING, int(names.size()));
+        if (!ss->getattribute(name, type, names.data()))
+            return -1;
+        auto end = std::find(names.begin(), names.end(), OIIO::ustring());
+        if (end != names.end())
+            return int(end - names.begin());
+        names.assign(names.size() * 2, OIIO::ustring());
+    }
+}
+
+bool ShadingSystem_load_memory_compiled_shader(ShadingSystem ss,
+                                               const char* shadername,
+                                               const char* buffer) {
+    return ss->LoadMemoryCompiledShader(shadername, buffer);
+}
+
+ShaderGroupRef ShadingSystem_shader_group_begin(ShadingSystem ss,
+                                                const char* groupname) {
+    OSL::ShaderGroupRef group = ss->ShaderGroupBegin(groupname);
+    return group ? new ShaderGroupRefApi(group) : nullptr;
+}
+
+bool ShadingSystem_shader_group_end(ShadingSystem ss, ShaderGroupRef group) {
+    return ss->ShaderGroupEnd(*group->group);
+}
+
+bool ShadingSystem_shader(ShadingSystem ss, ShaderGroupRef group,
+                          const char* shaderusage, const char* shadername,
+                          const char* layername) {
+    return ss->Shader(*group->group, shaderusage, shadername, layername);
+}
+
+PerThreadInfo ShadingSystem_create_thread_info(ShadingSystem ss) {
+    return ss->create_thread_info();
+}
+
+void ShadingSystem_destroy_thread_info(ShadingSystem ss,
+                                       PerThreadInfo tinfo) {
+    ss->destroy_thread_info(tinfo);
+}
+
+ShadingContext ShadingSystem_get_context(ShadingSystem ss,
+                                            PerThreadInfo tinfo) {
+    return ss->get_context(tinfo);
+}
+
+void ShadingSystem_release_context(ShadingSystem ss,
+                                   ShadingContext context) {
+    ss->release_context(context);
+}
+
+void ShadingSystem_optimize_group(ShadingSystem ss, ShaderGroupRef group,
+                                  int raytypes_on, int raytypes_off,
+                                  ShadingContext ctx) {
+    ss->optimize_group(group->group.get(), raytypes_on, raytypes_off, ctx);
+}
+
+void ShadingSystem_optimize_all_groups(ShadingSystem ss, int nthreads,
+                                       int mythread, int totalthreads) {
+    ss->optimize_all_groups(nthreads, mythread, totalthreads);
+}
+
+bool ShadingSystem_execute(ShadingSystem ss, ShadingContext ctx,
+                           ShaderGroupRef group, ShaderGlobalsPtr sg,
+                           bool run) {
+    return ss->execute(*ctx, *group->group, *sg, run);
+}
+
+bool ShadingSystem_execute_init(ShadingSystem ss, ShadingContext ctx,
+                                ShaderGroupRef group, ShaderGlobalsPtr sg,
+                                bool run) {
+    return ss->execute_init(*ctx, *group->group, *sg, run);
+}
+
+bool ShadingSystem_execute_layer_index(ShadingSystem ss, ShadingContext ctx,
+                                       ShaderGlobalsPtr sg, int layernumber) {
+    return ss->execute_layer(*ctx, *sg, layernumber);
+}
+
+bool ShadingSystem_execute_layer_name(ShadingSystem ss, ShadingContext ctx,
+                                      ShaderGlobalsPtr sg, ustring layername) {
+    return ss->execute_layer(*ctx, *sg, *(OIIO::ustring*)&layername);
+}
+
+bool ShadingSystem_execute_layer_symbol(ShadingSystem ss,
+                                        ShadingContext ctx,
+                                        ShaderGlobalsPtr sg,
+                                        ShaderSymbolPtr symbol) {
+    return ss->execute_layer(*ctx, *sg, symbol);
+}
+
+bool ShadingSystem_execute_cleanup(ShadingSystem ss, ShadingContext ctx) {
+    return ss->execute_cleanup(*ctx);
+}
+
+int ShadingSystem_find_layer(ShadingSystem ss, ShaderGroupRef group,
+                             ustring layername) {
+    return ss->find_layer(*group->group, *(OIIO::ustring*)&layername);
+}
+
+ShaderSymbolPtr ShadingSystem_find_symbol(ShadingSystem ss,
+                                          ShaderGroupRef group,
+                                          ustring symbolname) {
+    return ss->find_symbol(*group->group, *(OIIO::ustring*)&symbolname);
+}
+
+ShaderSymbolPtr ShadingSystem_find_symbol_in_layer(ShadingSystem ss,
+                                                   ShaderGroupRef group,
+                                                   ustring layername,
+                                                   ustring symbolname) {
+    return ss->find_symbol(*group->group, *(OIIO::ustring*)&layername,
+                           *(OIIO::ustring*)&symbolname);
+}
+
+TypeDesc ShadingSystem_symbol_typedesc(ShadingSystem ss,
+                                       ShaderSymbolPtr symbol) {
+    auto td = ss->symbol_typedesc(symbol);
+    return *(TypeDesc*)&td;
+}
+
+const void* ShadingSystem_symbol_address(ShadingSystem ss,
+                                         ShadingContext ctx,
+                                         ShaderSymbolPtr symbol) {
+    return ss->symbol_address(*ctx, symbol);
+}
+
+float ShadingSystem_luminance(ShadingSystem ss, const Color3* c) {
+    return ss->luminance(*(const OSL::Color3*)c);
+}
+
+void ShaderGroup_destroy(ShaderGroupRef group) { delete group; }
+
+RendererServicesBase RendererServices_create() {
+    return new OSL::RendererServices();
+}
+
+void RendererServices_destroy(RendererServicesBase rs) { delete rs; }
+
+RendererServicesWrapper RendererServicesWrapper_create() {
+    return new RendererServicesWrapperApi();
+}
+
+void RendererServicesWrapper_destroy(RendererServicesWrapper rsw) {
+    delete rsw;
+}
+
+void RendererServicesWrapper_set_rust_object(RendererServicesWrapper rsw,
+                                             void* rs_obj) {
+    rsw->_rs_obj = rs_obj;
+}
+
+void RendererServicesWrapper_setfn_supports(RendererServicesWrapper rsw,
+                                            RSFn_supports supports) {
+    rsw->_supports = supports;
+}
+
+void RendererServicesWrapper_setfn_get_matrix(RendererServicesWrapper rsw,
+                                              RSFn_get_matrix get_matrix) {
+    rsw->_get_matrix = get_matrix;
+}
+
+void RendererServicesWrapper_setfn_get_userdata(
+    RendererServicesWrapper rsw, RSFn_get_userdata get_userdata) {
+    rsw->_get_userdata = get_userdata;
+}
+
+void RendererServicesWrapper_setfn_texture(RendererServicesWrapper rsw,
+                                           RSFn_texture texture) {
+    rsw->_texture = texture;
+}
+
+#ifdef OSL_CAPI_BATCHED
+void RendererServicesWrapper_setfn_batched_get_matrix(
+    RendererServicesWrapper rsw, RSFn_batched_get_matrix get_matrix) {
+    rsw->_batched_get_matrix = get_matrix;
+}
+#endif
+
+ErrorHandler ErrorHandler_create(ErrorHandlerImpl impl) {
+    return new ErrorHandlerWrapper(impl);
+}
+
+ErrorHandler ErrorHandler_create_with_data(ErrorHandlerDataImpl impl,
+                                          void* data) {
+    return new ErrorHandlerWrapper(impl, data);
+}
+
+void ErrorHandler_destroy(ErrorHandler eh) { delete eh; }
+
+OSLCompiler OSLCompiler_create(ErrorHandler eh) {
+    return new OSL::OSLCompiler(eh);
+}
+
+void OSLCompiler_destroy(OSLCompiler compiler) { delete compiler; }
+
+bool OSLCompiler_compile_buffer(OSLCompiler compiler,
+                                const char* sourcecode,
+                                const char* const* options, int noptions,
+                                const char* stdoslpath, char** oso) {
+    std::vector<std::string> opts(options, options + noptions);
+    std::string osobuffer;
+    bool result = compiler->compile_buffer(
+        sourcecode, osobuffer, opts, stdoslpath ? stdoslpath : "");
+    *oso = result ? strdup(osobuffer.c_str()) : nullptr;
+    return result;
+}
+
+void osl_capi_free_string(char* s) { free(s); }
+
+OSLQuery OSLQuery_create() { return new OSL::OSLQuery(); }
+
+void OSLQuery_destroy(OSLQuery query) { delete query; }
+
+bool OSLQuery_open(OSLQuery query, const char* shadername,
+                   const char* searchpath) {
+    return query->open(shadername, searchpath);
+}
+
+bool OSLQuery_open_bytecode(OSLQuery query, const char* buffer) {
+    return query->open_bytecode(buffer);
+}
+
+// Must be freed with osl_capi_free_string
+char* OSLQuery_geterror(OSLQuery query) {
+    return strdup(query->geterror().c_str());
+}
+
+const char* OSLQuery_shadertype(OSLQuery query) {
+    return OIIO::ustring(query->shadertype()).c_str();
+}
+
+const char* OSLQuery_shadername(OSLQuery query) {
+    return OIIO::ustring(query->shadername()).c_str();
+}
+
+int OSLQuery_nparams(OSLQuery query) { return (int)query->nparams(); }
+
+OSLQueryParameter OSLQuery_getparam(OSLQuery query, int i) {
+    return query->getparam(i);
+}
+
+int OSLQuery_nmetadata(OSLQuery query) {
+    return (int)query->metadata().size();
+}
+
+OSLQueryParameter OSLQuery_metadata(OSLQuery query, int i) {
+    return &query->metadata()[i];
+}
+
+void OSLQueryParameter_info(OSLQueryParameter p,
+                            OSLQueryParameterInfo* info) {
+    info->name = OIIO::ustring(p->name).c_str();
+    info->typedesc = *(TypeDesc*)&p->type;
+    info->isoutput = p->isoutput;
+    info->validdefault = p->validdefault;
+    info->varlenarray = p->varlenarray;
+    info->isstruct = p->isstruct;
+    info->isclosure = p->isclosure;
+    info->idefault = p->idefault.data();
+    info->nidefault = (int)p->idefault.size();
+    info->fdefault = p->fdefault.data();
+    info->nfdefault = (int)p->fdefault.size();
+    info->nsdefault = (int)p->sdefault.size();
+    info->nspacename = (int)p->spacename.size();
+    info->nfields = (int)p->fields.size();
+    info->structname = OIIO::ustring(p->structname).c_str();
+    info->nmetadata = (int)p->metadata.size();
+}
+
+const char* OSLQueryParameter_sdefault(OSLQueryParameter p, int i) {
+    return OIIO::ustring(p->sdefault[i]).c_str();
+}
+
+const char* OSLQueryParameter_spacename(OSLQueryParameter p, int i) {
+    return OIIO::ustring(p->spacename[i]).c_str();
+}
+
+const char* OSLQueryParameter_field(OSLQueryParameter p, int i) {
+    return OIIO::ustring(p->fields[i]).c_str();
+}
+
+OSLQueryParameter OSLQueryParameter_metadata(OSLQueryParameter p,
+                                                    int i) {
+    return &p->metadata[i];
+}
+
+void ErrorHandler_set_verbosity(ErrorHandler eh, int verbosity) {
+    eh->verbosity(verbosity);
+}
+
+int ErrorHandler_get_verbosity(ErrorHandler eh) { return eh->verbosity(); }
+
+bool shade_image(ShadingSystem ss, ShaderGroupRef group,
+                 ConstShaderGlobalsPtr defaultsg, ImageBufPtr imagebuf,
+                 const ustring* outputs, int noutputs, int shadelocations,
+                 ROI roi) {
+    return OSL::shade_image(
+        *ss, *group->group, defaultsg, *imagebuf,
+        OIIO::cspan<OIIO::ustring>((const OIIO::ustring*)outputs, noutputs),
+        (OSL::ShadeImageLocations)shadelocations, *(OIIO::ROI*)&roi);
+}
+
+} // extern "C"
+
+// Cell and hash noise are piecewise constant, so OSL gives them zero
+// derivatives rather than implementing them for Dual2.
+template <typename N> struct ConstantNoise : N {
+    using N::operator();
+
+    template <typename T, typename... Args>
+    void operator()(OSL::Dual2<T>& result, const Args&... args) const {
+        T r;
+        N::operator()(r, value(args)...);
+        result = OSL::Dual2<T>(r);
+    }
+
+    static float value(float x) { return x; }
+    static const OSL::Vec3& value(const OSL::Vec3& x) { return x; }
+    template <typename T> static const T& value(const OSL::Dual2<T>& x) {
+        return x.val();
+    }
+};
+
+// Builds noise arguments from the flat floats Noise_eval takes
+struct PlainArgs {
+    static float f(const float* p, int, int i) { return p[i]; }
+    static OSL::Vec3 v(const float* p, int) {
+        return OSL::Vec3(p[0], p[1], p[2]);
+    }
+};
+
+struct DualArgs {
+    static OSL::Dual2<float> f(const float* p, int ndims, int i) {
+        return OSL::Dual2<float>(p[i], p[ndims + i], p[2 * ndims + i]);
+    }
+    static OSL::Dual2<OSL::Vec3> v(const float* p, int ndims) {
+        return OSL::Dual2<OSL::Vec3>(
+            OSL::Vec3(p[0], p[1], p[2]),
+            OSL::Vec3(p[ndims], p[ndims + 1], p[ndims + 2]),
+            OSL::Vec3(p[2 * ndims], p[2 * ndims + 1], p[2 * ndims + 2]));
+    }
+};
+
+static void store(float r, float* result) { result[0] = r; }
+
+static void store(const OSL::Vec3& r, float* result) {
+    result[0] = r.x;
+    result[1] = r.y;
+    result[2] = r.z;
+}
+
+template <typename T>
+static void store(const OSL::Dual2<T>& r, float* result) {
+    const int n = sizeof(T) / sizeof(float);
+    store(r.val(), result);
+    store(r.dx(), result + n);
+    store(r.dy(), result + 2 * n);
+}
+
+// The 4D domain is a point and a time, as in the shading language
+template <typename A, typename N, typename R>
+static void noise_eval(const N& noise, int ndims, const float* p,
+                       float* result) {
+    R r;
+    switch (ndims) {
+    case 1: noise(r, A::f(p, ndims, 0)); break;
+    case 2: noise(r, A::f(p, ndims, 0), A::f(p, ndims, 1)); break;
+    case 3: noise(r, A::v(p, ndims)); break;
+    default: noise(r, A::v(p, ndims), A::f(p, ndims, 3)); break;
+    }
+    store(r, result);
+}
+
+template <typename A, typename N, typename R>
+static void pnoise_eval(const N& noise, int ndims, const float* p,
+                        const float* period, float* result) {
+    R r;
+    switch (ndims) {
+    case 1: noise(r, A::f(p, ndims, 0), period[0]); break;
+    case 2:
+        noise(r, A::f(p, ndims, 0), A::f(p, ndims, 1), period[0], period[1]);
+        break;
+    case 3: noise(r, A::v(p, ndims), PlainArgs::v(period, 3)); break;
+    default:
+        noise(r, A::v(p, ndims), A::f(p, ndims, 3),
+              PlainArgs::v(period, 3), period[3]);
+        break;
+    }
+    store(r, result);
+}
+
+template <typename A, typename N, typename PN, typename F, typename V>
+static void noise_dispatch(int ndims, const float* p, const float* period,
+                           bool vresult, float* result) {
+    if (period) {
+        if (vresult) {
+            pnoise_eval<A, PN, V>(PN(), ndims, p, period, result);
+        } else {
+            pnoise_eval<A, PN, F>(PN(), ndims, p, period, result);
+        }
+    } else {
+        if (vresult) {
+            noise_eval<A, N, V>(N(), ndims, p, result);
+        } else {
+            noise_eval<A, N, F>(N(), ndims, p, result);
+        }
+    }
+}
+
+template <typename A, typename F, typename V, template <typename> class C>
+static void noise_type_dispatch(int type, int ndims, const float* p,
+                                const float* period, bool vresult,
+                                float* result) {
+    using namespace OSL::pvt;
+    switch (type) {
+    case OSL_NOISE_PERLIN:
+        noise_dispatch<A, SNoise, PeriodicSNoise, F, V>(ndims, p, period,
+                                                        vresult, result);
+        break;
+    case OSL_NOISE_CELL:
+        noise_dispatch<A, C<CellNoise>, C<PeriodicCellNoise>, F, V>(
+            ndims, p, period, vresult, result);
+        break;
+    case OSL_NOISE_HASH:
+        noise_dispatch<A, C<HashNoise>, C<PeriodicHashNoise>, F, V>(
+            ndims, p, period, vresult, result);
+        break;
+    default:
+        noise_dispatch<A, Noise, PeriodicNoise, F, V>(ndims, p, period,
+                                                      vresult, result);
+        break;
+    }
+}
+
+// Plain evaluation needs no help with cell and hash noise
+template <typename N> using SameNoise = N;
+
+extern "C" {
+
+void Noise_eval(int type, int ndims, const float* p, const float* period,
+                bool vresult, float* result) {
+    noise_type_dispatch<PlainArgs, float, OSL::Vec3, SameNoise>(
+        type, ndims, p, period, vresult, result);
+}
+
+void Noise_eval_dual(int type, int ndims, const float* p, const float* period,
+                     bool vresult, float* result) {
+    noise_type_dispatch<DualArgs, OSL::Dual2<float>, OSL::Dual2<OSL::Vec3>,
+                        ConstantNoise>(type, ndims, p, period, vresult,
+                                       result);
+}
+
+#ifdef OSL_CAPI_BATCHED
+bool ShadingSystem_configure_batch_execution_at(ShadingSystem ss, int width) {
+    return ss->configure_batch_execution_at(width);
+}
+
+} // extern "C"
+
+// The Rust side checks that its BatchedShaderGlobals have the size of these.
+// Check here that they are the plain structure of arrays it assumes, with no
+// padding anywhere that it doesn't have too.
+#define OSL_CAPI_CHECK_BATCH(W)                                                \
+    static_assert(offsetof(BatchedShaderGlobals##W##Api, P) ==                 \
+                          sizeof(UniformShaderGlobalsApi) &&                   \
+                      offsetof(BatchedShaderGlobals##W##Api, object2common) == \
+                          sizeof(UniformShaderGlobalsApi) +                    \
+                              W * 53 * sizeof(float) &&                        \
+                      sizeof(BatchedShaderGlobals##W##Api) ==                  \
+                          sizeof(UniformShaderGlobalsApi) +                    \
+                              W * (54 * sizeof(float) + 3 * sizeof(void*) +    \
+                                   2 * sizeof(int)),                           \
+                  "BatchedShaderGlobals" #W "Api is not a packed SoA")
+
+OSL_CAPI_CHECK_BATCH(8);
+OSL_CAPI_CHECK_BATCH(16);
+
+static inline OSL::Vec3 to_vec3(const float* v) {
+    return OSL::Vec3(v[0], v[1], v[2]);
+}
+
+template <int W, typename BatchedShaderGlobalsApi>
+static bool batched_execute(ShadingSystem ss, ShadingContext ctx,
+                            ShaderGroupRef group, int batch_size,
+                            BatchedShaderGlobalsApi* bsg, bool run) {
+    OSL::BatchedShaderGlobals<W> osg;
+    osg.uniform.renderstate = bsg->uniform.renderstate;
+    osg.uniform.tracedata = bsg->uniform.tracedata;
+    osg.uniform.objdata = bsg->uniform.objdata;
+    osg.uniform.context = bsg->uniform.context;
+    osg.uniform.renderer = bsg->uniform.renderer;
+    osg.uniform.raytype = bsg->uniform.raytype;
+
+    auto& v = osg.varying;
+    for (int i = 0; i < W; ++i) {
+        v.P.set(i, to_vec3(bsg->P[i]));
+        v.dPdx.set(i, to_vec3(bsg->dPdx[i]));
+        v.dPdy.set(i, to_vec3(bsg->dPdy[i]));
+        v.dPdz.set(i, to_vec3(bsg->dPdz[i]));
+        v.I.set(i, to_vec3(bsg->I[i]));
+        v.dIdx.set(i, to_vec3(bsg->dIdx[i]));
+        v.dIdy.set(i, to_vec3(bsg->dIdy[i]));
+        v.N.set(i, to_vec3(bsg->N[i]));
+        v.Ng.set(i, to_vec3(bsg->Ng[i]));
+        v.u.set(i, bsg->u[i]);
+        v.dudx.set(i, bsg->dudx[i]);
+        v.dudy.set(i, bsg->dudy[i]);
+        v.v.set(i, bsg->v[i]);
+        v.dvdx.set(i, bsg->dvdx[i]);
+        v.dvdy.set(i, bsg->dvdy[i]);
+        v.dPdu.set(i, to_vec3(bsg->dPdu[i]));
+        v.dPdv.set(i, to_vec3(bsg->dPdv[i]));
+        v.time.set(i, bsg->time[i]);
+        v.dtime.set(i, bsg->dtime[i]);
+        v.dPdtime.set(i, to_vec3(bsg->dPdtime[i]));
+        v.Ps.set(i, to_vec3(bsg->Ps[i]));
+        v.dPsdx.set(i, to_vec3(bsg->dPsdx[i]));
+        v.dPsdy.set(i, to_vec3(bsg->dPsdy[i]));
+        v.object2common.set(i, bsg->object2common[i]);
+        v.shader2common.set(i, bsg->shader2common[i]);
+        v.Ci.set(i, bsg->Ci[i]);
+        v.surfacearea.set(i, bsg->surfacearea[i]);
+        v.flipHandedness.set(i, bsg->flipHandedness[i]);
+        v.backfacing.set(i, bsg->backfacing[i]);
+    }
+
+    bool result =
+        ss->batched<W>().execute(*ctx, *group->group, batch_size, osg, run);
+
+    for (int i = 0; i < W; ++i) {
+        bsg->Ci[i] = v.Ci.get(i);
+    }
+    return result;
+}
+
+extern "C" {
+
+bool ShadingSystem_batched_execute_8(ShadingSystem ss, ShadingContext ctx,
+                                     ShaderGroupRef group, int batch_size,
+                                     BatchedShaderGlobals8Ptr bsg,
+                                     bool run) {
+    return batched_execute<8>(ss, ctx, group, batch_size, bsg, run);
+}
+
+bool ShadingSystem_batched_execute_16(ShadingSystem ss, ShadingContext ctx,
+                                      ShaderGroupRef group, int batch_size,
+                                      BatchedShaderGlobals16Ptr bsg,
+                                      bool run) {
+    return batched_execute<16>(ss, ctx, group, batch_size, bsg, run);
+}
+#endif
+
+} // extern "C"
\ No newline at end of file
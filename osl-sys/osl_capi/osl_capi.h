@@ -0,0 +1,387 @@
+/* The C API over OSL's C++ interface that the Rust bindings are built on.
+ *
+ * The Rust declarations are generated from this header by bindgen, reading
+ * it as C. osl_capi.cpp includes it too, so a function whose definition has
+ * drifted from its declaration here fails to compile, and static_asserts
+ * there check the structs that mirror OSL's own against the real thing.
+ */
+#ifndef OSL_CAPI_H
+#define OSL_CAPI_H
+
+#include <stdbool.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Sizes of the mirrored structs that the Rust side takes from oiio-rs
+ * rather than from these bindings, so that it can check them too */
+#define OSL_CAPI_TYPEDESC_SIZE 8
+#define OSL_CAPI_ROI_SIZE 32
+
+/* Mirrors OIIO::TypeDesc */
+typedef struct TypeDesc {
+    unsigned char basetype;
+    unsigned char aggregate;
+    unsigned char vecsemantics;
+    unsigned char reserved;
+    int arraylen;
+} TypeDesc;
+
+/* Mirrors OIIO::ROI */
+typedef struct ROI {
+    int xbegin, xend;
+    int ybegin, yend;
+    int zbegin, zend;
+    int chbegin, chend;
+} ROI;
+
+/* Mirrors Imath::V3f, i.e. OSL::Vec3 */
+typedef struct Vec3 {
+    float x, y, z;
+} Vec3;
+
+/* Mirrors Imath::C3f, i.e. OSL::Color3 */
+typedef struct Color3 {
+    float r, g, b;
+} Color3;
+
+/* Mirrors Imath::M44f, i.e. OSL::Matrix44. Row-major, x[row][col], and
+ * applied to row vectors, so a transform's translation is in x[3]. */
+typedef struct Matrix44 {
+    float x[4][4];
+} Matrix44;
+
+/* Mirrors OSL::ClosureParam */
+typedef struct ClosureParam {
+    TypeDesc typedesc;
+    int offset;
+    const char* key;
+    int field_size;
+} ClosureParam;
+
+/* Everything about an OSLQuery::Parameter that isn't a list of strings or
+ * metadata, which are fetched separately by index */
+typedef struct OSLQueryParameterInfo {
+    const char* name;
+    TypeDesc typedesc;
+    bool isoutput;
+    bool validdefault;
+    bool varlenarray;
+    bool isstruct;
+    bool isclosure;
+    const int* idefault;
+    int nidefault;
+    const float* fdefault;
+    int nfdefault;
+    int nsdefault;
+    int nspacename;
+    int nfields;
+    const char* structname;
+    int nmetadata;
+} OSLQueryParameterInfo;
+
+/* The characters of an OIIO::ustring, which is layout-compatible */
+typedef const char* ustring;
+typedef const void* TransformationPtr;
+
+typedef void (*ErrorHandlerImpl)(int, const char*);
+typedef void (*ErrorHandlerDataImpl)(void*, int, const char*);
+
+#ifndef OSL_CAPI_IMPLEMENTATION
+/* Opaque handles. Inside the shim these are pointers to the C++ objects,
+ * which it declares itself before including this header. */
+typedef struct ShadingSystem_api* ShadingSystem;
+typedef struct RendererServices_api* RendererServicesBase;
+typedef struct RendererServicesWrapper_api* RendererServicesWrapper;
+typedef struct ErrorHandler_api* ErrorHandler;
+typedef struct ShaderGroupRef_api* ShaderGroupRef;
+typedef struct PerThreadInfo_api* PerThreadInfo;
+typedef struct ShadingContext_api* ShadingContext;
+typedef struct ClosureColor_api* ClosureColorPtr;
+typedef const struct ShaderSymbol_api* ShaderSymbolPtr;
+typedef struct ImageBuf_api* ImageBufPtr;
+typedef struct OSLCompiler_api* OSLCompiler;
+typedef struct OSLQuery_api* OSLQuery;
+typedef const struct OSLQueryParameter_api* OSLQueryParameter;
+typedef struct ShaderGlobalsApi* ShaderGlobalsPtr;
+typedef const struct ShaderGlobalsApi* ConstShaderGlobalsPtr;
+
+typedef int (*RSFn_supports)(void* rs_obj, const char* feature);
+typedef int (*RSFn_get_matrix)(void* rs_obj, ShaderGlobalsPtr sg,
+                               Matrix44* result, TransformationPtr xform);
+/* If derivatives is true, val has room for the x and y derivatives after the
+ * value */
+typedef int (*RSFn_get_userdata)(void* rs_obj, bool derivatives, ustring name,
+                                 TypeDesc type, ShaderGlobalsPtr sg,
+                                 void* val);
+/* s and t are each a value followed by its x and y derivatives. dresultds
+ * and dresultdt are null unless OSL needs the derivatives of the result.
+ * Returns a negative value to leave the lookup to OSL's texture system. */
+typedef int (*RSFn_texture)(void* rs_obj, ustring filename,
+                            ShaderGlobalsPtr sg, const float* s,
+                            const float* t, int nchannels, float* result,
+                            float* dresultds, float* dresultdt);
+
+#ifdef OSL_CAPI_BATCHED
+typedef unsigned int (*RSFn_batched_get_matrix)(
+    void* rs_obj, int width, void* renderstate, const TransformationPtr* xforms,
+    const float* times, unsigned int mask, float* result);
+#endif
+#endif
+
+/* Mirrors OSL::ShaderGlobals */
+typedef struct ShaderGlobalsApi {
+    float P[3];
+    float dPdx[3];
+    float dPdy[3];
+    float dPdz[3];
+    float I[3];
+    float dIdx[3];
+    float dIdy[3];
+    float N[3];
+    float Ng[3];
+    float u;
+    float dudx;
+    float dudy;
+    float v;
+    float dvdx;
+    float dvdy;
+    float dPdu[3];
+    float dPdv[3];
+    float time;
+    float dtime;
+    float dPdtime[3];
+    float Ps[3];
+    float dPsdx[3];
+    float dPsdy[3];
+    void* renderstate;
+    void* tracedata;
+    void* objdata;
+    ShadingContext context;
+    RendererServicesBase renderer;
+    TransformationPtr object2common;
+    TransformationPtr shader2common;
+    ClosureColorPtr Ci;
+    float surfacearea;
+    int raytype;
+    int flipHandedness;
+    int backfacing;
+} ShaderGlobalsApi;
+
+#ifdef OSL_CAPI_BATCHED
+/* The part of a batch's globals that is the same for every lane */
+typedef struct UniformShaderGlobalsApi {
+    void* renderstate;
+    void* tracedata;
+    void* objdata;
+    ShadingContext context;
+    RendererServicesBase renderer;
+    int raytype;
+} UniformShaderGlobalsApi;
+
+/* A batch of W points as a structure of arrays, mirroring the Rust
+ * BatchedShaderGlobals. The shim copies it into OSL's own, version-dependent
+ * OSL::BatchedShaderGlobals<W>. */
+#define OSL_CAPI_BATCHED_SHADER_GLOBALS(W)                                     \
+    typedef struct BatchedShaderGlobals##W##Api {                              \
+        UniformShaderGlobalsApi uniform;                                       \
+        float P[W][3];                                                         \
+        float dPdx[W][3];                                                      \
+        float dPdy[W][3];                                                      \
+        float dPdz[W][3];                                                      \
+        float I[W][3];                                                         \
+        float dIdx[W][3];                                                      \
+        float dIdy[W][3];                                                      \
+        float N[W][3];                                                         \
+        float Ng[W][3];                                                        \
+        float u[W];                                                            \
+        float dudx[W];                                                         \
+        float dudy[W];                                                         \
+        float v[W];                                                            \
+        float dvdx[W];                                                         \
+        float dvdy[W];                                                         \
+        float dPdu[W][3];                                                      \
+        float dPdv[W][3];                                                      \
+        float time[W];                                                         \
+        float dtime[W];                                                        \
+        float dPdtime[W][3];                                                   \
+        float Ps[W][3];                                                        \
+        float dPsdx[W][3];                                                     \
+        float dPsdy[W][3];                                                     \
+        TransformationPtr object2common[W];                                    \
+        TransformationPtr shader2common[W];                                    \
+        ClosureColorPtr Ci[W];                                                 \
+        float surfacearea[W];                                                  \
+        int flipHandedness[W];                                                 \
+        int backfacing[W];                                                     \
+    } BatchedShaderGlobals##W##Api;                                            \
+    typedef BatchedShaderGlobals##W##Api* BatchedShaderGlobals##W##Ptr;
+
+OSL_CAPI_BATCHED_SHADER_GLOBALS(8)
+OSL_CAPI_BATCHED_SHADER_GLOBALS(16)
+#endif
+
+ShadingSystem ShadingSystem_create(RendererServicesWrapper renderer);
+ShadingSystem
+ShadingSystem_create_with_error_handler(RendererServicesWrapper renderer,
+                                        ErrorHandler eh);
+void ShadingSystem_destroy(ShadingSystem ss);
+void ShadingSystem_register_closure(ShadingSystem ss, const char* name, int id,
+                                    const ClosureParam* params);
+bool ShadingSystem_attribute(ShadingSystem ss, const char* name,
+                             TypeDesc typedesc, const void* val);
+bool ShadingSystem_getattribute(ShadingSystem ss, const char* name,
+                                TypeDesc typedesc, void* val);
+bool ShadingSystem_group_attribute(ShadingSystem ss, ShaderGroupRef group,
+                                   const char* name, TypeDesc typedesc,
+                                   const void* val);
+bool ShadingSystem_group_getattribute(ShadingSystem ss, ShaderGroupRef group,
+                                      const char* name, TypeDesc typedesc,
+                                      void* val);
+bool ShadingSystem_group_getattribute_ptr(ShadingSystem ss,
+                                          ShaderGroupRef group,
+                                          const char* name, const void** val);
+int ShadingSystem_getattribute_arraylen(ShadingSystem ss, ShaderGroupRef group,
+                                        const char* name);
+bool ShadingSystem_load_memory_compiled_shader(ShadingSystem ss,
+                                               const char* shadername,
+                                               const char* buffer);
+ShaderGroupRef ShadingSystem_shader_group_begin(ShadingSystem ss,
+                                                const char* groupname);
+bool ShadingSystem_shader_group_end(ShadingSystem ss, ShaderGroupRef group);
+bool ShadingSystem_shader(ShadingSystem ss, ShaderGroupRef group,
+                          const char* shaderusage, const char* shadername,
+                          const char* layername);
+PerThreadInfo ShadingSystem_create_thread_info(ShadingSystem ss);
+void ShadingSystem_destroy_thread_info(ShadingSystem ss, PerThreadInfo tinfo);
+ShadingContext ShadingSystem_get_context(ShadingSystem ss,
+                                         PerThreadInfo tinfo);
+void ShadingSystem_release_context(ShadingSystem ss, ShadingContext context);
+void ShadingSystem_optimize_group(ShadingSystem ss, ShaderGroupRef group,
+                                  int raytypes_on, int raytypes_off,
+                                  ShadingContext ctx);
+/* With nthreads of 1, optimizes every totalthreads'th group starting at
+ * mythread on the calling thread */
+void ShadingSystem_optimize_all_groups(ShadingSystem ss, int nthreads,
+                                       int mythread, int totalthreads);
+bool ShadingSystem_execute(ShadingSystem ss, ShadingContext ctx,
+                           ShaderGroupRef group, ShaderGlobalsPtr sg,
+                           bool run);
+bool ShadingSystem_execute_init(ShadingSystem ss, ShadingContext ctx,
+                                ShaderGroupRef group, ShaderGlobalsPtr sg,
+                                bool run);
+bool ShadingSystem_execute_layer_index(ShadingSystem ss, ShadingContext ctx,
+                                       ShaderGlobalsPtr sg, int layernumber);
+bool ShadingSystem_execute_layer_name(ShadingSystem ss, ShadingContext ctx,
+                                      ShaderGlobalsPtr sg, ustring layername);
+bool ShadingSystem_execute_layer_symbol(ShadingSystem ss, ShadingContext ctx,
+                                        ShaderGlobalsPtr sg,
+                                        ShaderSymbolPtr symbol);
+bool ShadingSystem_execute_cleanup(ShadingSystem ss, ShadingContext ctx);
+int ShadingSystem_find_layer(ShadingSystem ss, ShaderGroupRef group,
+                             ustring layername);
+ShaderSymbolPtr ShadingSystem_find_symbol(ShadingSystem ss,
+                                          ShaderGroupRef group,
+                                          ustring symbolname);
+ShaderSymbolPtr ShadingSystem_find_symbol_in_layer(ShadingSystem ss,
+                                                   ShaderGroupRef group,
+                                                   ustring layername,
+                                                   ustring symbolname);
+TypeDesc ShadingSystem_symbol_typedesc(ShadingSystem ss,
+                                       ShaderSymbolPtr symbol);
+const void* ShadingSystem_symbol_address(ShadingSystem ss, ShadingContext ctx,
+                                         ShaderSymbolPtr symbol);
+float ShadingSystem_luminance(ShadingSystem ss, const Color3* c);
+
+void ShaderGroup_destroy(ShaderGroupRef group);
+
+RendererServicesBase RendererServices_create(void);
+void RendererServices_destroy(RendererServicesBase rs);
+
+RendererServicesWrapper RendererServicesWrapper_create(void);
+void RendererServicesWrapper_destroy(RendererServicesWrapper rsw);
+void RendererServicesWrapper_set_rust_object(RendererServicesWrapper rsw,
+                                             void* rs_obj);
+void RendererServicesWrapper_setfn_supports(RendererServicesWrapper rsw,
+                                            RSFn_supports supports);
+void RendererServicesWrapper_setfn_get_matrix(RendererServicesWrapper rsw,
+                                              RSFn_get_matrix get_matrix);
+void RendererServicesWrapper_setfn_get_userdata(RendererServicesWrapper rsw,
+                                                RSFn_get_userdata get_userdata);
+void RendererServicesWrapper_setfn_texture(RendererServicesWrapper rsw,
+                                           RSFn_texture texture);
+
+ErrorHandler ErrorHandler_create(ErrorHandlerImpl impl);
+ErrorHandler ErrorHandler_create_with_data(ErrorHandlerDataImpl impl,
+                                           void* data);
+void ErrorHandler_destroy(ErrorHandler eh);
+void ErrorHandler_set_verbosity(ErrorHandler eh, int verbosity);
+int ErrorHandler_get_verbosity(ErrorHandler eh);
+
+OSLCompiler OSLCompiler_create(ErrorHandler eh);
+void OSLCompiler_destroy(OSLCompiler compiler);
+/* On success, *oso is set to a copy of the compiled shader that must be
+ * freed with osl_capi_free_string */
+bool OSLCompiler_compile_buffer(OSLCompiler compiler, const char* sourcecode,
+                                const char* const* options, int noptions,
+                                const char* stdoslpath, char** oso);
+void osl_capi_free_string(char* s);
+
+OSLQuery OSLQuery_create(void);
+void OSLQuery_destroy(OSLQuery query);
+bool OSLQuery_open(OSLQuery query, const char* shadername,
+                   const char* searchpath);
+bool OSLQuery_open_bytecode(OSLQuery query, const char* buffer);
+/* Must be freed with osl_capi_free_string */
+char* OSLQuery_geterror(OSLQuery query);
+const char* OSLQuery_shadertype(OSLQuery query);
+const char* OSLQuery_shadername(OSLQuery query);
+int OSLQuery_nparams(OSLQuery query);
+OSLQueryParameter OSLQuery_getparam(OSLQuery query, int i);
+int OSLQuery_nmetadata(OSLQuery query);
+OSLQueryParameter OSLQuery_metadata(OSLQuery query, int i);
+void OSLQueryParameter_info(OSLQueryParameter p, OSLQueryParameterInfo* info);
+const char* OSLQueryParameter_sdefault(OSLQueryParameter p, int i);
+const char* OSLQueryParameter_spacename(OSLQueryParameter p, int i);
+const char* OSLQueryParameter_field(OSLQueryParameter p, int i);
+OSLQueryParameter OSLQueryParameter_metadata(OSLQueryParameter p, int i);
+
+bool shade_image(ShadingSystem ss, ShaderGroupRef group,
+                 ConstShaderGlobalsPtr defaultsg, ImageBufPtr imagebuf,
+                 const ustring* outputs, int noutputs, int shadelocations,
+                 ROI roi);
+
+/* Noise types for Noise_eval, as named in the shading language */
+#define OSL_NOISE_UPERLIN 0 /* "uperlin" or "noise" */
+#define OSL_NOISE_PERLIN 1  /* "perlin" or "snoise" */
+#define OSL_NOISE_CELL 2    /* "cell" */
+#define OSL_NOISE_HASH 3    /* "hash" */
+
+/* Evaluate OSL's noise at p, which has ndims (1 to 4) floats. If period is
+ * not NULL it has ndims floats too and the periodic variant is used. The
+ * result is 3 floats if vresult is set, otherwise 1. */
+void Noise_eval(int type, int ndims, const float* p, const float* period,
+                bool vresult, float* result);
+/* As Noise_eval, with derivatives. p holds the ndims values followed by
+ * their x and then y derivatives, and result is laid out the same way. */
+void Noise_eval_dual(int type, int ndims, const float* p, const float* period,
+                     bool vresult, float* result);
+
+#ifdef OSL_CAPI_BATCHED
+bool ShadingSystem_configure_batch_execution_at(ShadingSystem ss, int width);
+bool ShadingSystem_batched_execute_8(ShadingSystem ss, ShadingContext ctx,
+                                     ShaderGroupRef group, int batch_size,
+                                     BatchedShaderGlobals8Ptr bsg, bool run);
+bool ShadingSystem_batched_execute_16(ShadingSystem ss, ShadingContext ctx,
+                                      ShaderGroupRef group, int batch_size,
+                                      BatchedShaderGlobals16Ptr bsg, bool run);
+void RendererServicesWrapper_setfn_batched_get_matrix(
+    RendererServicesWrapper rsw, RSFn_batched_get_matrix get_matrix);
+#endif
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* OSL_CAPI_H */
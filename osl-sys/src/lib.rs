@@ -0,0 +1,28 @@
+//! Raw bindings to osl_capi, the C API over OpenShadingLanguage's C++
+//! interface that the `osl` crate is built on. The declarations are
+//! generated by bindgen from osl_capi/osl_capi.h, which the shim itself is
+//! compiled against, and the structs the header mirrors from OSL are
+//! checked against the real ones when the shim is built.
+#![allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
+
+// The header mirrors these for C. The bindings use oiio-rs's definitions
+// so they can be passed straight through.
+pub use oiio::imageio::ROI;
+pub use oiio::typedesc::TypeDesc;
+
+include!(concat!(env!("OUT_DIR"), "/osl_capi.rs"));
+
+/// Fail the build if two types don't have the same size and alignment, e.g.
+/// a Rust struct that is passed to the C API as the struct it mirrors.
+#[macro_export]
+macro_rules! assert_layout_eq {
+    ($a:ty, $b:ty) => {
+        const _: [(); std::mem::size_of::<$b>()] = [(); std::mem::size_of::<$a>()];
+        const _: [(); std::mem::align_of::<$b>()] = [(); std::mem::align_of::<$a>()];
+    };
+}
+
+// The shim checks the header's TypeDesc and ROI against OIIO's, and these
+// check oiio-rs's against the header
+const _: [(); OSL_CAPI_TYPEDESC_SIZE as usize] = [(); std::mem::size_of::<TypeDesc>()];
+const _: [(); OSL_CAPI_ROI_SIZE as usize] = [(); std::mem::size_of::<ROI>()];
@@ -1,46 +1,31 @@
-use cmake;
-use std::collections::HashMap;
-use std::path::Path;
+use std::env;
 
-pub fn main() {
-    let mut settings = config::Config::default();
-    settings
-        .merge(config::File::with_name("build-settings").required(false))
-        .unwrap();
-    settings.merge(config::Environment::new()).ok();
-
-    let settings_map = settings
-        .try_into::<HashMap<String, String>>()
-        .unwrap_or(HashMap::new());
-
-    let osl_root = settings_map.get("osl_root").expect("OSL_ROOT is not set");
-    let oiio_root = settings_map.get("oiio_root").expect("OIIO_ROOT is not set");    
-    let openexr_root = settings_map.get("openexr_root").expect("OPENEXR_ROOT is not set");    
+/// The OSL versions that get a cfg flag, e.g. `osl_1_10`. Each flag is set
+/// if the OSL being built against is at least that version, so
+/// version-specific API can be gated with `#[cfg(osl_1_12)]`.
+const OSL_VERSIONS: &[(u32, u32)] = &[(1, 9), (1, 10), (1, 11), (1, 12), (1, 13), (1, 14)];
 
-    let dst_osl_capi = cmake::Config::new("osl_capi")
-        .define("OSL_ROOT", &osl_root)
-        .define("OIIO_ROOT", &oiio_root)
-        .define("OPENEXR_ROOT", &openexr_root)
-        .always_configure(false)
-        .build();
-
-    println!("cargo:rustc-link-search=native={}", dst_osl_capi.display());
-    println!(
-        "cargo:rustc-link-search=native={}",
-        Path::new(&osl_root).join("lib").display()
-    );
-    println!(
-        "cargo:rustc-link-search=native={}",
-        Path::new(&oiio_root).join("lib").display()
-    );
+pub fn main() {
+    // Declare every flag, set or not, so that gating on one isn't an
+    // unexpected_cfgs warning
+    for (v_major, v_minor) in OSL_VERSIONS {
+        println!("cargo::rustc-check-cfg=cfg(osl_{}_{})", v_major, v_minor);
+    }
 
-    #[cfg(target_os = "linux")]
-    println!("cargo:rustc-link-lib=dylib=stdc++");
-    #[cfg(target_os = "macos")]
-    println!("cargo:rustc-link-lib=dylib=c++");
+    // osl-sys finds OSL and tells us its version. Without the oslexec
+    // feature there's no osl-sys, and nothing to do.
+    let version = match env::var("DEP_OSL_CAPI_VERSION") {
+        Ok(version) => version,
+        Err(_) => return,
+    };
 
-    println!("cargo:rustc-link-lib=static=osl_capi");
-    println!("cargo:rustc-link-lib=dylib=oslexec");
-    // println!("cargo:rustc-link-lib=dylib=oslcomp");
-    println!("cargo:rustc-link-lib=dylib=OpenImageIO");
-}
\ No newline at end of file
+    let mut parts = version.split('.').map(|p| p.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    for (v_major, v_minor) in OSL_VERSIONS {
+        if (major, minor) >= (*v_major, *v_minor) {
+            println!("cargo:rustc-cfg=osl_{}_{}", v_major, v_minor);
+        }
+    }
+    println!("cargo:rustc-env=OSL_VERSION={}", version);
+}
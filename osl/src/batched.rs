@@ -0,0 +1,552 @@
+//! Batched (a.k.a. wide) execution, where OSL shades 8 or 16 points at once
+//! using SIMD instructions. This requires OSL to have been built with
+//! batched support (USE_BATCHED) and is only available with the "batched"
+//! feature.
+use crate::error_handler::capture_messages;
+use crate::ffi;
+use crate::ffi::ShadingContext;
+use crate::math::*;
+use crate::shader_globals::ShaderGlobals;
+use crate::shading_system::{ContextRef, ShaderGroupRef, ShaderSymbol, ShadingSystem};
+use crate::symbol_value::SymbolValue;
+use crate::Error;
+
+use std::marker::PhantomData;
+use std::os::raw::c_void;
+
+/// The number of points shaded together by one batched execution. OSL
+/// supports batches of 8 (AVX/AVX2) and 16 (AVX-512) points.
+pub trait BatchWidth {
+    const WIDTH: usize;
+
+    type Floats: Copy + AsRef<[f32]> + AsMut<[f32]>;
+    type Vec3s: Copy + AsRef<[V3f32]> + AsMut<[V3f32]>;
+    type Ints: Copy + AsRef<[i32]> + AsMut<[i32]>;
+    type Ptrs: Copy + AsRef<[*const c_void]> + AsMut<[*const c_void]>;
+    type Closures: Copy + AsRef<[ffi::ClosureColorPtr]> + AsMut<[ffi::ClosureColorPtr]>;
+
+    fn floats() -> Self::Floats;
+    fn vec3s() -> Self::Vec3s;
+    fn ints() -> Self::Ints;
+    fn ptrs() -> Self::Ptrs;
+    fn closures() -> Self::Closures;
+
+    #[doc(hidden)]
+    unsafe fn execute(
+        ss: ffi::ShadingSystem,
+        context: ShadingContext,
+        group: ffi::ShaderGroupRef,
+        batch_size: i32,
+        bsg: *mut c_void,
+        run: bool,
+    ) -> bool;
+}
+
+macro_rules! batch_width {
+    ($name:ident, $width:expr, $execute:ident) => {
+        pub struct $name;
+
+        impl BatchWidth for $name {
+            const WIDTH: usize = $width;
+
+            type Floats = [f32; $width];
+            type Vec3s = [V3f32; $width];
+            type Ints = [i32; $width];
+            type Ptrs = [*const c_void; $width];
+            type Closures = [ffi::ClosureColorPtr; $width];
+
+            fn floats() -> Self::Floats {
+                [0.0; $width]
+            }
+
+            fn vec3s() -> Self::Vec3s {
+                [v3f32(0.0, 0.0, 0.0); $width]
+            }
+
+            fn ints() -> Self::Ints {
+                [0; $width]
+            }
+
+            fn ptrs() -> Self::Ptrs {
+                [std::ptr::null(); $width]
+            }
+
+            fn closures() -> Self::Closures {
+                [std::ptr::null_mut(); $width]
+            }
+
+            unsafe fn execute(
+                ss: ffi::ShadingSystem,
+                context: ShadingContext,
+                group: ffi::ShaderGroupRef,
+                batch_size: i32,
+                bsg: *mut c_void,
+                run: bool,
+            ) -> bool {
+                ffi::$execute(ss, context, group, batch_size, bsg as _, run)
+            }
+        }
+    };
+}
+
+batch_width!(Width8, 8, ShadingSystem_batched_execute_8);
+batch_width!(Width16, 16, ShadingSystem_batched_execute_16);
+
+/// The part of BatchedShaderGlobals that is the same for every point in
+/// the batch.
+#[repr(C)]
+pub struct UniformShaderGlobals {
+    /// Opaque pointers for the renderer's use, see ShaderGlobals.
+    pub renderstate: *const c_void,
+    pub tracedata: *const c_void,
+    pub objdata: *const c_void,
+
+    /// Back-pointer to the ShadingContext (set and used by OSL itself --
+    /// renderers shouldn't mess with this at all).
+    pub context: ShadingContext,
+
+    /// Pointer to the RendererServices object. This is how OSL finds its
+    /// way back to the renderer for callbacks.
+    pub renderer: ffi::RendererServicesWrapper,
+
+    /// Bit field of ray type flags.
+    pub raytype: i32,
+}
+
+/// The state describing a batch of points to be shaded, laid out as a
+/// structure of arrays with one entry per lane. See ShaderGlobals for the
+/// meaning of each field.
+///
+/// Only the first `batch_size` lanes passed to execute_batch are shaded,
+/// the rest are ignored.
+#[repr(C)]
+pub struct BatchedShaderGlobals<'c, W: BatchWidth> {
+    pub uniform: UniformShaderGlobals,
+
+    pub P: W::Vec3s,
+    pub dPdx: W::Vec3s,
+    pub dPdy: W::Vec3s,
+    pub dPdz: W::Vec3s,
+
+    pub I: W::Vec3s,
+    pub dIdx: W::Vec3s,
+    pub dIdy: W::Vec3s,
+
+    pub N: W::Vec3s,
+    pub Ng: W::Vec3s,
+
+    pub u: W::Floats,
+    pub dudx: W::Floats,
+    pub dudy: W::Floats,
+    pub v: W::Floats,
+    pub dvdx: W::Floats,
+    pub dvdy: W::Floats,
+
+    pub dPdu: W::Vec3s,
+    pub dPdv: W::Vec3s,
+
+    pub time: W::Floats,
+    pub dtime: W::Floats,
+    pub dPdtime: W::Vec3s,
+
+    pub Ps: W::Vec3s,
+    pub dPsdx: W::Vec3s,
+    pub dPsdy: W::Vec3s,
+
+    pub object2common: W::Ptrs,
+    pub shader2common: W::Ptrs,
+
+    /// The output closure of each lane is placed here after execution.
+    pub Ci: W::Closures,
+
+    pub surfacearea: W::Floats,
+    pub flipHandedness: W::Ints,
+    pub backfacing: W::Ints,
+
+    /// Ties the globals to the context they were made with
+    _context: PhantomData<ContextRef<'c>>,
+}
+
+// Passed to the C API as the BatchedShaderGlobals8Api and 16Api it declares,
+// which the shim checks are plain structures of arrays like these
+osl_sys::assert_layout_eq!(UniformShaderGlobals, ffi::UniformShaderGlobalsApi);
+osl_sys::assert_layout_eq!(
+    BatchedShaderGlobals<'static, Width8>,
+    ffi::BatchedShaderGlobals8Api
+);
+osl_sys::assert_layout_eq!(
+    BatchedShaderGlobals<'static, Width16>,
+    ffi::BatchedShaderGlobals16Api
+);
+
+impl<'c, W: BatchWidth> BatchedShaderGlobals<'c, W> {
+    pub fn new(
+        context: ContextRef<'c>,
+        renderer: ffi::RendererServicesWrapper,
+    ) -> BatchedShaderGlobals<'c, W> {
+        BatchedShaderGlobals {
+            uniform: UniformShaderGlobals {
+                renderstate: std::ptr::null(),
+                tracedata: std::ptr::null(),
+                objdata: std::ptr::null(),
+                context: context.ctx,
+                renderer,
+                raytype: 0,
+            },
+
+            P: W::vec3s(),
+            dPdx: W::vec3s(),
+            dPdy: W::vec3s(),
+            dPdz: W::vec3s(),
+
+            I: W::vec3s(),
+            dIdx: W::vec3s(),
+            dIdy: W::vec3s(),
+
+            N: W::vec3s(),
+            Ng: W::vec3s(),
+
+            u: W::floats(),
+            dudx: W::floats(),
+            dudy: W::floats(),
+            v: W::floats(),
+            dvdx: W::floats(),
+            dvdy: W::floats(),
+
+            dPdu: W::vec3s(),
+            dPdv: W::vec3s(),
+
+            time: W::floats(),
+            dtime: W::floats(),
+            dPdtime: W::vec3s(),
+
+            Ps: W::vec3s(),
+            dPsdx: W::vec3s(),
+            dPsdy: W::vec3s(),
+
+            object2common: W::ptrs(),
+            shader2common: W::ptrs(),
+
+            Ci: W::closures(),
+
+            surfacearea: W::floats(),
+            flipHandedness: W::ints(),
+            backfacing: W::ints(),
+
+            _context: PhantomData,
+        }
+    }
+
+    /// Copy the varying state of a single-point ShaderGlobals into `lane`.
+    /// The uniform fields of `sg` are ignored.
+    pub fn set_lane(&mut self, lane: usize, sg: &ShaderGlobals) {
+        self.P.as_mut()[lane] = sg.P;
+        self.dPdx.as_mut()[lane] = sg.dPdx;
+        self.dPdy.as_mut()[lane] = sg.dPdy;
+        self.dPdz.as_mut()[lane] = sg.dPdz;
+
+        self.I.as_mut()[lane] = sg.I;
+        self.dIdx.as_mut()[lane] = sg.dIdx;
+        self.dIdy.as_mut()[lane] = sg.dIdy;
+
+        self.N.as_mut()[lane] = sg.N;
+        self.Ng.as_mut()[lane] = sg.Ng;
+
+        self.u.as_mut()[lane] = sg.u;
+        self.dudx.as_mut()[lane] = sg.dudx;
+        self.dudy.as_mut()[lane] = sg.dudy;
+        self.v.as_mut()[lane] = sg.v;
+        self.dvdx.as_mut()[lane] = sg.dvdx;
+        self.dvdy.as_mut()[lane] = sg.dvdy;
+
+        self.dPdu.as_mut()[lane] = sg.dPdu;
+        self.dPdv.as_mut()[lane] = sg.dPdv;
+
+        self.time.as_mut()[lane] = sg.time;
+        self.dtime.as_mut()[lane] = sg.dtime;
+        self.dPdtime.as_mut()[lane] = sg.dPdtime;
+
+        self.Ps.as_mut()[lane] = sg.Ps;
+        self.dPsdx.as_mut()[lane] = sg.dPsdx;
+        self.dPsdy.as_mut()[lane] = sg.dPsdy;
+
+        self.object2common.as_mut()[lane] = sg.object2common;
+        self.shader2common.as_mut()[lane] = sg.shader2common;
+
+        self.surfacearea.as_mut()[lane] = sg.surfacearea;
+        self.flipHandedness.as_mut()[lane] = sg.flipHandedness;
+        self.backfacing.as_mut()[lane] = sg.backfacing;
+    }
+}
+
+/// A type that can be extracted per-lane from a symbol after batched
+/// execution.
+pub trait BatchedSymbolValue: SymbolValue {
+    /// Read the value of `lane` from wide symbol data for a batch of
+    /// `width` lanes.
+    unsafe fn read_lane(ptr: *const u8, width: usize, lane: usize) -> Self;
+}
+
+impl BatchedSymbolValue for f32 {
+    unsafe fn read_lane(ptr: *const u8, _width: usize, lane: usize) -> f32 {
+        *(ptr as *const f32).add(lane)
+    }
+}
+
+impl BatchedSymbolValue for i32 {
+    unsafe fn read_lane(ptr: *const u8, _width: usize, lane: usize) -> i32 {
+        *(ptr as *const i32).add(lane)
+    }
+}
+
+/// Wide triples are stored as all the x values, then all the y's, then all
+/// the z's.
+impl BatchedSymbolValue for V3f32 {
+    unsafe fn read_lane(ptr: *const u8, width: usize, lane: usize) -> V3f32 {
+        let ptr = ptr as *const f32;
+        v3f32(
+            *ptr.add(lane),
+            *ptr.add(width + lane),
+            *ptr.add(2 * width + lane),
+        )
+    }
+}
+
+impl ShadingSystem {
+    /// Set up the shading system for batched execution at the given width.
+    /// This must be called before any shader groups are created, and
+    /// returns an error if OSL or the host CPU does not support it.
+    pub fn configure_batch_execution_at(&mut self, width: usize) -> Result<(), Error> {
+        capture_messages(|| {
+            if unsafe { ffi::ShadingSystem_configure_batch_execution_at(self.ss, width as i32) } {
+                Ok(())
+            } else {
+                Err(Error::BatchConfigurationFailed(width))
+            }
+        })
+    }
+
+    /// Execute the shader group for the first `batch_size` lanes of `bsg`.
+    /// After execution the output closures are in `bsg.Ci`, and output
+    /// symbols can be read with batched_symbol_values.
+    pub fn execute_batch<W: BatchWidth>(
+        &self,
+        context: ContextRef<'_>,
+        group: &ShaderGroupRef,
+        batch_size: usize,
+        bsg: &mut BatchedShaderGlobals<'_, W>,
+        run: bool,
+    ) -> Result<(), Error> {
+        let context = context.ctx;
+        capture_messages(|| {
+            if batch_size == 0 || batch_size > W::WIDTH {
+                return Err(Error::InvalidBatchSize(batch_size, W::WIDTH));
+            }
+
+            if unsafe {
+                W::execute(
+                    self.ss,
+                    context,
+                    group.group,
+                    batch_size as i32,
+                    bsg as *mut BatchedShaderGlobals<'_, W> as *mut c_void,
+                    run,
+                )
+            } {
+                Ok(())
+            } else {
+                Err(Error::ExecuteFailed)
+            }
+        })
+    }
+
+    /// Copy the value of an output symbol for each of the first
+    /// `batch_size` lanes out of a context that has just run execute_batch.
+    ///
+    /// # Safety
+    /// The last execution in `context` must have been execute_batch of the
+    /// group `symbol` was found in, at width `W`. The symbol must also be
+    /// varying, i.e. hold a value per lane. Renderer outputs always are, so
+    /// this should be used for symbols named in the "renderer_outputs"
+    /// attribute. Reading a uniform symbol reads `W::WIDTH` values where
+    /// OSL only stored one.
+    pub unsafe fn batched_symbol_values<W: BatchWidth, T: BatchedSymbolValue>(
+        &self,
+        context: ContextRef<'_>,
+        symbol: &ShaderSymbol,
+        batch_size: usize,
+    ) -> Result<Vec<T>, Error> {
+        let td = self.symbol_typedesc(*symbol);
+        if !T::matches(&td) {
+            return Err(Error::SymbolTypeMismatch(td, T::NAME));
+        }
+
+        let ptr = self.symbol_address(context, *symbol) as *const u8;
+        if ptr.is_null() {
+            return Err(Error::SymbolAddressFailed);
+        }
+
+        Ok((0..batch_size.min(W::WIDTH))
+            .map(|lane| T::read_lane(ptr, W::WIDTH, lane))
+            .collect())
+    }
+}
+
+/// Callbacks made by OSL into the renderer during batched execution. Each
+/// callback is given a whole batch at once, with a bit mask of the lanes
+/// that need computing, and returns the mask of lanes it succeeded for.
+pub trait BatchedRendererServices {
+    /// Compute the 4x4 matrix for each active lane's opaque transformation
+    /// `xforms[lane]` at `times[lane]`, storing it in `result[lane]`.
+    fn get_matrix(
+        &self,
+        _renderstate: *const c_void,
+        _xforms: &[*const c_void],
+        _times: &[f32],
+        _mask: u32,
+        _result: &mut [Matrix44],
+    ) -> u32 {
+        0
+    }
+}
+
+/// Route OSL's batched renderer callbacks through `rsw` to the
+/// BatchedRendererServices implementation of `R`.
+///
+/// # Safety
+/// The rust object set on `rsw` with RendererServicesWrapper_set_rust_object
+/// must be an `R`, and must outlive the wrapper.
+pub unsafe fn set_batched_renderer_services<R: BatchedRendererServices>(
+    rsw: ffi::RendererServicesWrapper,
+) {
+    ffi::RendererServicesWrapper_setfn_batched_get_matrix(rsw, Some(batched_get_matrix::<R>));
+}
+
+extern "C" fn batched_get_matrix<R: BatchedRendererServices>(
+    rs_obj: *mut c_void,
+    width: i32,
+    renderstate: *mut c_void,
+    xforms: *const *const c_void,
+    times: *const f32,
+    mask: u32,
+    result: *mut f32,
+) -> u32 {
+    let width = width as usize;
+    let (renderer, xforms, times, result) = unsafe {
+        (
+            &*(rs_obj as *const R),
+            std::slice::from_raw_parts(xforms, width),
+            std::slice::from_raw_parts(times, width),
+            std::slice::from_raw_parts_mut(result as *mut Matrix44, width),
+        )
+    };
+
+    // Lanes the renderer didn't compute must be left as they were
+    let mut matrices = [Matrix44::identity(); 16];
+    let done = renderer.get_matrix(renderstate, xforms, times, mask, &mut matrices[..width]);
+
+    for (lane, m) in matrices[..width].iter().enumerate() {
+        if done & (1 << lane) != 0 {
+            result[lane] = *m;
+        }
+    }
+
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::renderer_services::RendererServices;
+    use crate::test_renderer::TestRenderer;
+    use crate::Ustring;
+
+    use std::sync::Arc;
+
+    impl BatchedRendererServices for TestRenderer {}
+
+    #[test]
+    fn batch_matches_scalar() {
+        let compiled = Compiler::new()
+            .compile_buffer(
+                "shader lanes(output float Fout = 0, output color Cout = 0) {\n    Fout = u * 2 + sin(v);\n    Cout = color(P) * noise(P);\n}\n",
+            )
+            .expect("Could not compile shader");
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        let rsw = renderer.lock().unwrap().rsw;
+        unsafe { set_batched_renderer_services::<TestRenderer>(rsw) };
+        if let Err(e) = ss.configure_batch_execution_at(Width8::WIDTH) {
+            // Not something this CPU can do
+            eprintln!("Skipping batched test: {}", e);
+            return;
+        }
+        ss.attribute("renderer_outputs", &["Fout", "Cout"][..])
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("lanes", &compiled.oso)
+            .expect("Could not load shader from memory");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "lanes", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+        let fout = ss.find_symbol(&group, Ustring::new("Fout")).unwrap();
+        let cout = ss.find_symbol(&group, Ustring::new("Cout")).unwrap();
+
+        ss.with_context(|ctx| {
+            let mut bsg = BatchedShaderGlobals::<Width8>::new(ctx, rsw);
+            let mut expected = Vec::new();
+            for lane in 0..Width8::WIDTH {
+                let mut sg = ShaderGlobals::new(ctx, rsw);
+                sg.u = lane as f32 / 8.0;
+                sg.v = 1.0 - lane as f32 / 4.0;
+                sg.P = v3f32(lane as f32 * 0.3, -0.7, 2.1 + lane as f32);
+                bsg.set_lane(lane, &sg);
+
+                ss.execute(ctx, &group, &mut sg, true)
+                    .expect("Execute failed");
+                expected.push(unsafe {
+                    (
+                        ss.symbol_value::<f32>(ctx, &fout).unwrap(),
+                        ss.symbol_value::<V3f32>(ctx, &cout).unwrap(),
+                    )
+                });
+            }
+
+            ss.execute_batch(ctx, &group, Width8::WIDTH, &mut bsg, true)
+                .expect("Batched execute failed");
+            // Both are renderer outputs, so varying
+            let (f, c) = unsafe {
+                (
+                    ss.batched_symbol_values::<Width8, f32>(ctx, &fout, Width8::WIDTH)
+                        .unwrap(),
+                    ss.batched_symbol_values::<Width8, V3f32>(ctx, &cout, Width8::WIDTH)
+                        .unwrap(),
+                )
+            };
+            for (lane, (ef, ec)) in expected.into_iter().enumerate() {
+                assert!(
+                    (f[lane] - ef).abs() < 1e-5,
+                    "lane {}: {} != {}",
+                    lane,
+                    f[lane],
+                    ef
+                );
+                assert!(
+                    (c[lane] - ec).norm() < 1e-5,
+                    "lane {}: {} != {}",
+                    lane,
+                    c[lane],
+                    ec
+                );
+            }
+        })
+        .expect("Could not get shading context");
+    }
+}
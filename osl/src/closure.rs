@@ -1,4 +1,5 @@
 use crate::ffi;
+use crate::{cstring, Error};
 use oiio::typedesc::TypeDesc;
 
 pub struct ClosureParam {
@@ -8,21 +9,21 @@ pub struct ClosureParam {
     pub field_size: usize,
 }
 
-impl From<&ClosureParam> for ffi::ClosureParam {
-    fn from(rcp: &ClosureParam) -> ffi::ClosureParam {
-        let key = match &rcp.key {
+impl ClosureParam {
+    pub(crate) fn to_ffi(&self) -> Result<ffi::ClosureParam, Error> {
+        let key = match &self.key {
             // NOTE: We are leaking memory here, but as long as we don't
             // create new closures in an inner loop that shouldn't be an issue
-            Some(s) => std::ffi::CString::new(s.as_str()).unwrap().into_raw(),
+            Some(s) => cstring(s)?.into_raw(),
             None => std::ptr::null(),
         };
 
-        ffi::ClosureParam {
-            typedesc: rcp.typedesc,
-            offset: rcp.offset as i32,
+        Ok(ffi::ClosureParam {
+            typedesc: self.typedesc,
+            offset: self.offset as i32,
             key,
-            field_size: rcp.field_size as i32,
-        }
+            field_size: self.field_size as i32,
+        })
     }
 }
 
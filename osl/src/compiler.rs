@@ -0,0 +1,374 @@
+//! Compiling OSL source to .oso at runtime with liboslcomp, as oslc does.
+use crate::error_handler::ErrorLevel;
+use crate::ffi;
+use crate::{cstring, Error};
+
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
+
+/// Runtime optimization level passed to the compiler as -O0, -O1 or -O2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+}
+
+/// Compiles OSL source code to .oso text. Include paths, defines and the
+/// optimization level are set up front and apply to every compile:
+///
+/// ```ignore
+/// let compiled = Compiler::new()
+///     .include_path("shaders/include")
+///     .define("USE_FAST_NOISE", None)
+///     .optimization(OptLevel::O2)
+///     .compile_file("shaders/noisetest.osl")?;
+/// std::fs::write("noisetest.oso", &compiled.oso)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, Option<String>)>,
+    optimization: Option<OptLevel>,
+    stdosl_path: Option<PathBuf>,
+    options: Vec<String>,
+}
+
+/// The result of a successful compile.
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    /// The compiled shader, as would be written to a .oso file.
+    pub oso: String,
+    /// Any warnings or other messages the compiler produced.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A single message from the compiler.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: ErrorLevel,
+    /// The source file the message refers to, if any.
+    pub file: Option<String>,
+    /// The line in `file` the message refers to, if any.
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+/// All the messages from a failed compile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler {
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+            optimization: None,
+            stdosl_path: None,
+            options: Vec::new(),
+        }
+    }
+
+    /// Add a directory to search for #include'd files (-I).
+    pub fn include_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Compiler {
+        self.include_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Define a preprocessor symbol, optionally with a value (-D).
+    pub fn define(&mut self, name: &str, value: Option<&str>) -> &mut Compiler {
+        self.defines.push((name.into(), value.map(|v| v.into())));
+        self
+    }
+
+    /// Set the compile-time optimization level (-O). If not set, the
+    /// compiler's default is used.
+    pub fn optimization(&mut self, level: OptLevel) -> &mut Compiler {
+        self.optimization = Some(level);
+        self
+    }
+
+    /// Use this stdosl.h rather than the one installed with OSL.
+    pub fn stdosl_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Compiler {
+        self.stdosl_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Pass any other option through to the compiler as-is, exactly as it
+    /// would be given to oslc.
+    pub fn option(&mut self, option: &str) -> &mut Compiler {
+        self.options.push(option.into());
+        self
+    }
+
+    /// Compile OSL source code held in memory.
+    pub fn compile_buffer(&self, source: &str) -> Result<Compiled, Error> {
+        self.compile(source, &self.args())
+    }
+
+    /// Compile the OSL source file at `path`. Includes are also searched
+    /// for in the directory containing the file, and diagnostics refer to
+    /// it by name.
+    pub fn compile_file<P: AsRef<Path>>(&self, path: P) -> Result<Compiled, Error> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| Error::ReadFailed(path.display().to_string(), e))?;
+
+        let mut args = self.args();
+        if let Some(dir) = path.parent() {
+            args.push(format!("-I{}", dir.display()));
+        }
+
+        // Have the preprocessor report locations in the original file
+        let source = format!(
+            "#line 1 \"{}\"\n{}",
+            escape(&path.display().to_string()),
+            source
+        );
+        self.compile(&source, &args)
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for path in &self.include_paths {
+            args.push(format!("-I{}", path.display()));
+        }
+        for (name, value) in &self.defines {
+            match value {
+                Some(value) => args.push(format!("-D{}={}", name, value)),
+                None => args.push(format!("-D{}", name)),
+            }
+        }
+        match self.optimization {
+            Some(OptLevel::O0) => args.push("-O0".into()),
+            Some(OptLevel::O1) => args.push("-O1".into()),
+            Some(OptLevel::O2) => args.push("-O2".into()),
+            None => (),
+        }
+        args.extend(self.options.iter().cloned());
+        args
+    }
+
+    fn compile(&self, source: &str, args: &[String]) -> Result<Compiled, Error> {
+        let source = cstring(source)?;
+        let args = args
+            .iter()
+            .map(|a| cstring(a))
+            .collect::<Result<Vec<_>, _>>()?;
+        let arg_ptrs = args.iter().map(|a| a.as_ptr()).collect::<Vec<_>>();
+        let stdosl_path = self
+            .stdosl_path
+            .as_ref()
+            .map(|p| cstring(&p.display().to_string()))
+            .transpose()?;
+
+        let mut diagnostics = Vec::<Diagnostic>::new();
+        let mut oso: *mut c_char = std::ptr::null_mut();
+        let result = unsafe {
+            let eh = ffi::ErrorHandler_create_with_data(
+                Some(collect_diagnostic),
+                &mut diagnostics as *mut Vec<Diagnostic> as *mut c_void,
+            );
+            let compiler = ffi::OSLCompiler_create(eh);
+            let result = ffi::OSLCompiler_compile_buffer(
+                compiler,
+                source.as_ptr(),
+                arg_ptrs.as_ptr(),
+                arg_ptrs.len() as i32,
+                stdosl_path
+                    .as_ref()
+                    .map_or(std::ptr::null(), |p| p.as_ptr()),
+                &mut oso,
+            );
+            ffi::OSLCompiler_destroy(compiler);
+            ffi::ErrorHandler_destroy(eh);
+            result
+        };
+
+        if result && !oso.is_null() {
+            let text = unsafe {
+                let text = std::ffi::CStr::from_ptr(oso).to_string_lossy().into_owned();
+                ffi::osl_capi_free_string(oso);
+                text
+            };
+            Ok(Compiled {
+                oso: text,
+                diagnostics,
+            })
+        } else {
+            Err(Error::CompileFailed(Diagnostics(diagnostics)))
+        }
+    }
+}
+
+/// Escape `s` for use inside a string literal in OSL source.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+extern "C" fn collect_diagnostic(data: *mut c_void, level: i32, msg: *const c_char) {
+    let (diagnostics, msg) = unsafe {
+        (
+            &mut *(data as *mut Vec<Diagnostic>),
+            std::ffi::CStr::from_ptr(msg).to_string_lossy(),
+        )
+    };
+    diagnostics.push(Diagnostic::parse(ErrorLevel::from_code(level), &msg));
+}
+
+impl Diagnostic {
+    /// Split a compiler message of the form "file:line: error: message"
+    /// into its parts. Messages without a location are kept whole.
+    fn parse(severity: ErrorLevel, msg: &str) -> Diagnostic {
+        let msg = msg.trim_end();
+        for (i, _) in msg.match_indices(':') {
+            let rest = &msg[i + 1..];
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 || !rest[digits..].starts_with(':') {
+                continue;
+            }
+
+            let mut message = rest[digits + 1..].trim_start();
+            for prefix in &["error: ", "warning: ", "info: ", "message: "] {
+                if message.starts_with(prefix) {
+                    message = &message[prefix.len()..];
+                    break;
+                }
+            }
+
+            return Diagnostic {
+                severity,
+                file: Some(msg[..i].to_string()),
+                line: rest[..digits].parse().ok(),
+                message: message.to_string(),
+            };
+        }
+
+        Diagnostic {
+            severity,
+            file: None,
+            line: None,
+            message: msg.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: ", file, line)?,
+            (Some(file), None) => write!(f, "{}: ", file)?,
+            _ => (),
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_diagnostic() {
+        let d = Diagnostic::parse(
+            ErrorLevel::Error,
+            "shaders/noisetest.osl:33: error: No matching function call\n",
+        );
+        assert_eq!(
+            d.file.as_ref().map(|f| f.as_str()),
+            Some("shaders/noisetest.osl")
+        );
+        assert_eq!(d.line, Some(33));
+        assert_eq!(d.message, "No matching function call");
+
+        let d = Diagnostic::parse(ErrorLevel::Warning, "something went wrong");
+        assert_eq!(d.file, None);
+        assert_eq!(d.line, None);
+        assert_eq!(d.message, "something went wrong");
+    }
+
+    #[test]
+    fn compile_noisetest() {
+        let compiled = Compiler::new()
+            .compile_file("osl/noisetest.osl")
+            .expect("Could not compile noisetest.osl");
+        assert!(compiled.oso.starts_with("OpenShadingLanguage"));
+        assert!(compiled.oso.contains("shader noisetest"));
+    }
+
+    #[test]
+    fn compile_error() {
+        let result = Compiler::new().compile_buffer("shader broken() {\n    float x = ;\n}\n");
+        match result {
+            Err(Error::CompileFailed(diagnostics)) => {
+                assert!(diagnostics
+                    .0
+                    .iter()
+                    .any(|d| d.severity == ErrorLevel::Error && d.line == Some(2)));
+            }
+            _ => panic!("Expected compile to fail"),
+        }
+    }
+
+    #[test]
+    fn escape_line_path() {
+        assert_eq!(escape("a/b.osl"), "a/b.osl");
+        assert_eq!(
+            escape(r#"C:\my "shaders"\a.osl"#),
+            r#"C:\\my \"shaders\"\\a.osl"#
+        );
+        assert_eq!(escape("a\nb"), "a\\nb");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compile_file_quoted_path() {
+        let dir = std::env::temp_dir().join("osl \"quoted\" \\dir");
+        std::fs::create_dir_all(&dir).expect("Could not create directory");
+        let path = dir.join("broken.osl");
+        std::fs::write(&path, "shader broken() {\n    float x = ;\n}\n")
+            .expect("Could not write shader");
+
+        let result = Compiler::new().compile_file(&path);
+        std::fs::remove_dir_all(&dir).ok();
+        match result {
+            Err(Error::CompileFailed(diagnostics)) => {
+                let file = path.display().to_string();
+                assert!(diagnostics
+                    .0
+                    .iter()
+                    .any(|d| d.file.as_ref() == Some(&file) && d.line == Some(2)));
+            }
+            _ => panic!("Expected compile to fail"),
+        }
+    }
+}
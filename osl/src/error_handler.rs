@@ -0,0 +1,336 @@
+use crate::ffi;
+use crate::ffi::ErrCode;
+use crate::Error;
+
+use std::cell::RefCell;
+use std::os::raw::{c_char, c_void};
+
+/// The severity of a message reported by OSL.
+#[derive(Debug, Display, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorLevel {
+    #[display(fmt = "debug")]
+    Debug,
+    #[display(fmt = "message")]
+    Message,
+    #[display(fmt = "info")]
+    Info,
+    #[display(fmt = "warning")]
+    Warning,
+    #[display(fmt = "error")]
+    Error,
+    #[display(fmt = "severe")]
+    Severe,
+}
+
+/// How much OSL reports to the error handler. Quiet drops info and plain
+/// messages, Verbose adds info messages to the default Normal output.
+/// Warnings and errors are always reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum VerbosityLevel {
+    Quiet = 0,
+    Normal = 1,
+    Verbose = 2,
+}
+
+impl ErrorLevel {
+    /// Convert an OIIO::ErrorHandler::ErrCode, as passed to error
+    /// handler callbacks, to an ErrorLevel.
+    pub(crate) fn from_code(code: i32) -> ErrorLevel {
+        // The low 16 bits may carry an error number, the level is in the
+        // high bits.
+        let code = code & !0xffff;
+        if code == ErrCode::Debug as i32 {
+            ErrorLevel::Debug
+        } else if code == ErrCode::Info as i32 {
+            ErrorLevel::Info
+        } else if code == ErrCode::Warning as i32 {
+            ErrorLevel::Warning
+        } else if code == ErrCode::Error as i32 {
+            ErrorLevel::Error
+        } else if code == ErrCode::Severe as i32 {
+            ErrorLevel::Severe
+        } else {
+            ErrorLevel::Message
+        }
+    }
+}
+
+type HandlerFn = dyn Fn(ErrorLevel, &str) + Send + Sync;
+
+/// An OIIO::ErrorHandler that forwards every message to a Rust closure.
+pub(crate) struct ErrorHandler {
+    pub(crate) eh: ffi::ErrorHandler,
+    // Boxed twice so that the C++ side can hold a thin pointer to it
+    handler: *mut Box<HandlerFn>,
+}
+
+// The handler itself is Send + Sync, and the C++ object only calls it.
+unsafe impl Send for ErrorHandler {}
+unsafe impl Sync for ErrorHandler {}
+
+impl ErrorHandler {
+    pub(crate) fn new<F>(handler: F) -> ErrorHandler
+    where
+        F: Fn(ErrorLevel, &str) + Send + Sync + 'static,
+    {
+        let handler: *mut Box<HandlerFn> = Box::into_raw(Box::new(Box::new(handler)));
+        let eh = unsafe {
+            ffi::ErrorHandler_create_with_data(Some(call_handler), handler as *mut c_void)
+        };
+        ErrorHandler { eh, handler }
+    }
+
+    pub(crate) fn set_verbosity(&self, verbosity: VerbosityLevel) {
+        unsafe { ffi::ErrorHandler_set_verbosity(self.eh, verbosity as i32) }
+    }
+
+    pub(crate) fn verbosity(&self) -> VerbosityLevel {
+        match unsafe { ffi::ErrorHandler_get_verbosity(self.eh) } {
+            0 => VerbosityLevel::Quiet,
+            1 => VerbosityLevel::Normal,
+            _ => VerbosityLevel::Verbose,
+        }
+    }
+}
+
+impl Drop for ErrorHandler {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::ErrorHandler_destroy(self.eh);
+            drop(Box::from_raw(self.handler));
+        }
+    }
+}
+
+extern "C" fn call_handler(data: *mut c_void, level: i32, msg: *const c_char) {
+    let (handler, msg) = unsafe {
+        (
+            &*(data as *const Box<HandlerFn>),
+            std::ffi::CStr::from_ptr(msg).to_string_lossy(),
+        )
+    };
+    let level = ErrorLevel::from_code(level);
+    let msg = msg.trim_end();
+    record_message(level, msg);
+    // Unwinding into C++ is undefined behaviour, so a panicking handler
+    // just loses the message.
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler(level, msg)));
+}
+
+/// A message reported by OSL during a call that failed, with the location
+/// it refers to if OSL gave one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub level: ErrorLevel,
+    /// The shader the message refers to, if any.
+    pub shader: Option<String>,
+    /// The source file the message refers to, if any.
+    pub file: Option<String>,
+    /// The line in `file` the message refers to, if any.
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+/// All the messages reported during a call that failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Messages(pub Vec<Message>);
+
+thread_local! {
+    /// Buffers for the calls being captured on this thread, innermost last
+    static CAPTURED: RefCell<Vec<Vec<Message>>> = RefCell::new(Vec::new());
+}
+
+fn record_message(level: ErrorLevel, msg: &str) {
+    CAPTURED.with(|captured| {
+        if let Some(messages) = captured.borrow_mut().last_mut() {
+            messages.push(Message::parse(level, msg));
+        }
+    });
+}
+
+/// Run `f`, and if it fails, attach the messages OSL reported on this
+/// thread while it ran to the error. Messages still go to the error
+/// handler as usual.
+pub(crate) fn capture_messages<R, F>(f: F) -> Result<R, Error>
+where
+    F: FnOnce() -> Result<R, Error>,
+{
+    let (result, messages) = collect_messages(f);
+    match result {
+        Err(error) if !messages.is_empty() => match error {
+            Error::Reported(..) => Err(error),
+            error => Err(Error::Reported(Box::new(error), Messages(messages))),
+        },
+        result => result,
+    }
+}
+
+/// Run `f`, which OSL gives no result for, and fail with `error` if OSL
+/// reported an error on this thread while it ran.
+pub(crate) fn check_reported<F: FnOnce()>(error: Error, f: F) -> Result<(), Error> {
+    capture_messages(|| {
+        let ((), messages) = collect_messages(f);
+        if messages.iter().any(|m| m.level >= ErrorLevel::Error) {
+            Err(error)
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Run `f`, returning the messages OSL reported on this thread while it
+/// ran along with its result.
+pub(crate) fn collect_messages<R, F>(f: F) -> (R, Vec<Message>)
+where
+    F: FnOnce() -> R,
+{
+    CAPTURED.with(|captured| captured.borrow_mut().push(Vec::new()));
+    let frame = CaptureFrame;
+    let result = f();
+    let messages = CAPTURED.with(|captured| {
+        let mut captured = captured.borrow_mut();
+        match captured.split_last_mut() {
+            Some((messages, outer)) => {
+                let messages = std::mem::take(messages);
+                // Let an enclosing capture see them too
+                if let Some(outer) = outer.last_mut() {
+                    outer.extend(messages.iter().cloned());
+                }
+                messages
+            }
+            None => Vec::new(),
+        }
+    });
+    drop(frame);
+    (result, messages)
+}
+
+/// Pops the buffer pushed by collect_messages, including when the call
+/// panics, so that a stale buffer isn't left to collect later messages.
+struct CaptureFrame;
+
+impl Drop for CaptureFrame {
+    fn drop(&mut self) {
+        // The thread local may already be gone if the thread is exiting
+        let _ = CAPTURED.try_with(|captured| captured.borrow_mut().pop());
+    }
+}
+
+impl Message {
+    /// Pick out the location from messages such as
+    /// "... at noisetest.osl:33 (group , layer 0 noise, shader noisetest)".
+    fn parse(level: ErrorLevel, msg: &str) -> Message {
+        let mut file = None;
+        let mut line = None;
+        for (i, _) in msg.match_indices(':') {
+            let before = &msg[..i];
+            if !(before.ends_with(".osl") || before.ends_with(".oso")) {
+                continue;
+            }
+            let rest = &msg[i + 1..];
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 {
+                continue;
+            }
+
+            let start = before
+                .rfind(|c: char| c.is_whitespace() || c == '(' || c == '"')
+                .map_or(0, |s| s + 1);
+            file = Some(before[start..].to_string());
+            line = rest[..digits].parse().ok();
+            break;
+        }
+
+        let shader = msg.rfind("shader ").and_then(|i| {
+            let name = &msg[i + "shader ".len()..];
+            let end = name.find(')')?;
+            Some(name[..end].trim().to_string())
+        });
+
+        Message {
+            level,
+            shader,
+            file,
+            line,
+            message: msg.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.level, self.message)
+    }
+}
+
+impl std::fmt::Display for Messages {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, m) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", m)?;
+        }
+        Ok(())
+    }
+}
+
+/// The error handler used by ShadingSystem::new. Messages go to `tracing`
+/// or `log` if either feature is enabled, otherwise they're printed to
+/// stderr.
+pub fn default_error_handler(level: ErrorLevel, msg: &str) {
+    #[cfg(feature = "tracing")]
+    {
+        match level {
+            ErrorLevel::Debug => tracing::debug!(target: "osl", "{}", msg),
+            ErrorLevel::Message | ErrorLevel::Info => tracing::info!(target: "osl", "{}", msg),
+            ErrorLevel::Warning => tracing::warn!(target: "osl", "{}", msg),
+            ErrorLevel::Error | ErrorLevel::Severe => tracing::error!(target: "osl", "{}", msg),
+        }
+    }
+
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    {
+        match level {
+            ErrorLevel::Debug => log::debug!(target: "osl", "{}", msg),
+            ErrorLevel::Message | ErrorLevel::Info => log::info!(target: "osl", "{}", msg),
+            ErrorLevel::Warning => log::warn!(target: "osl", "{}", msg),
+            ErrorLevel::Error | ErrorLevel::Severe => log::error!(target: "osl", "{}", msg),
+        }
+    }
+
+    #[cfg(not(any(feature = "log", feature = "tracing")))]
+    {
+        eprintln!("{}: {}", level.to_string().to_uppercase(), msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_message() {
+        let m = Message::parse(
+            ErrorLevel::Error,
+            "Division by zero at noisetest.osl:33 (group , layer 0 noise, shader noisetest)",
+        );
+        assert_eq!(m.file.as_ref().map(|f| f.as_str()), Some("noisetest.osl"));
+        assert_eq!(m.line, Some(33));
+        assert_eq!(m.shader.as_ref().map(|s| s.as_str()), Some("noisetest"));
+
+        let m = Message::parse(ErrorLevel::Error, "No .oso file could be found");
+        assert_eq!(m.file, None);
+        assert_eq!(m.line, None);
+        assert_eq!(m.shader, None);
+    }
+
+    #[test]
+    fn collect_messages_panic() {
+        let result = std::panic::catch_unwind(|| collect_messages(|| panic!("shading failed")));
+        assert!(result.is_err());
+        // Nothing is left capturing messages on this thread
+        assert!(CAPTURED.with(|captured| captured.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,174 @@
+//! Introspection of shader groups, so that a renderer can e.g. prefetch the
+//! textures a material will use and only compute the globals it reads.
+use crate::ffi;
+use crate::shading_system::{ShaderGroupRef, ShadingSystem};
+use crate::{cstring, Error};
+
+use std::os::raw::c_void;
+
+use oiio::typedesc;
+use oiio::typedesc::TypeDesc;
+use oiio::Ustring;
+
+impl ShadingSystem {
+    /// Query the group, which must have been finished with
+    /// shader_group_end. Queries about what the group needs (textures,
+    /// closures, globals and userdata) optimize the group first if that
+    /// hasn't happened already, so they may be expensive the first time.
+    pub fn group_info<'a>(&'a self, group: &'a ShaderGroupRef) -> GroupInfo<'a> {
+        GroupInfo { ss: self, group }
+    }
+}
+
+/// Typed access to the attributes OSL reports for a shader group, as
+/// returned by ShadingSystem::group_info.
+pub struct GroupInfo<'a> {
+    ss: &'a ShadingSystem,
+    group: &'a ShaderGroupRef,
+}
+
+impl<'a> GroupInfo<'a> {
+    /// The number of layers (shader instances) in the group.
+    pub fn num_layers(&self) -> Result<i32, Error> {
+        self.int("num_layers")
+    }
+
+    /// The names of the layers, in order.
+    pub fn layer_names(&self) -> Result<Vec<Ustring>, Error> {
+        let n = self.num_layers()?;
+        self.ustrings("layer_names", n)
+    }
+
+    /// The number of layers that are marked as entry points.
+    pub fn num_entry_layers(&self) -> Result<i32, Error> {
+        self.int("num_entry_layers")
+    }
+
+    /// The name of the function that initializes the group in the
+    /// compiled code.
+    pub fn group_init_name(&self) -> Result<Ustring, Error> {
+        let values = self.read_ustrings("group_init_name", typedesc::STRING, 1)?;
+        Ok(values.into_iter().next().unwrap())
+    }
+
+    /// The number of textures the group may read.
+    pub fn num_textures_needed(&self) -> Result<i32, Error> {
+        self.int("num_textures_needed")
+    }
+
+    /// The names of the textures the group may read. If
+    /// unknown_textures_needed is true, the group may also read textures
+    /// whose names are only known when it runs.
+    pub fn textures_needed(&self) -> Result<Vec<Ustring>, Error> {
+        self.array("num_textures_needed", "textures_needed")
+    }
+
+    /// Might the group read textures whose names are computed at runtime?
+    pub fn unknown_textures_needed(&self) -> Result<bool, Error> {
+        Ok(self.int("unknown_textures_needed")? != 0)
+    }
+
+    /// The names of the closures the group may create.
+    pub fn closures_needed(&self) -> Result<Vec<Ustring>, Error> {
+        self.array("num_closures_needed", "closures_needed")
+    }
+
+    /// Might the group create closures that couldn't be determined ahead of
+    /// time?
+    pub fn unknown_closures_needed(&self) -> Result<bool, Error> {
+        Ok(self.int("unknown_closures_needed")? != 0)
+    }
+
+    /// The names of the ShaderGlobals the group reads, e.g. "P" or "u".
+    pub fn globals_needed(&self) -> Result<Vec<Ustring>, Error> {
+        self.array("num_globals_needed", "globals_needed")
+    }
+
+    /// The names of the userdata (parameters not locked against the
+    /// geometry) the group may request from the renderer.
+    pub fn userdata_names(&self) -> Result<Vec<Ustring>, Error> {
+        self.array("num_userdata", "userdata_names")
+    }
+
+    /// The types of the userdata, matching userdata_names.
+    pub fn userdata_types(&self) -> Result<Vec<TypeDesc>, Error> {
+        self.array("num_userdata", "userdata_types")
+    }
+
+    fn get(&self, name: &str, td: TypeDesc, val: *mut c_void) -> Result<(), Error> {
+        let cname = cstring(name)?;
+        if unsafe {
+            ffi::ShadingSystem_group_getattribute(
+                self.ss.ss,
+                self.group.group,
+                cname.as_ptr(),
+                td,
+                val,
+            )
+        } {
+            Ok(())
+        } else {
+            Err(Error::GetGroupAttributeFailed(name.into()))
+        }
+    }
+
+    fn int(&self, name: &str) -> Result<i32, Error> {
+        let mut value = 0i32;
+        self.get(name, typedesc::INT32, &mut value as *mut i32 as *mut c_void)?;
+        Ok(value)
+    }
+
+    /// Read an array of `n` strings, which is empty if n <= 0.
+    fn ustrings(&self, name: &str, n: i32) -> Result<Vec<Ustring>, Error> {
+        if n <= 0 {
+            return Ok(Vec::new());
+        }
+        let td = TypeDesc::new(
+            typedesc::STRING.basetype,
+            typedesc::STRING.aggregate,
+            typedesc::STRING.vecsemantics,
+            n,
+        );
+        self.read_ustrings(name, td, n as usize)
+    }
+
+    fn read_ustrings(&self, name: &str, td: TypeDesc, len: usize) -> Result<Vec<Ustring>, Error> {
+        let mut values = Vec::<Ustring>::with_capacity(len);
+        // A null ustring is OIIO's empty string, so the buffer is valid
+        // whatever OSL writes to it
+        unsafe {
+            std::ptr::write_bytes(values.as_mut_ptr(), 0, len);
+            values.set_len(len);
+        }
+        self.get(name, td, values.as_mut_ptr() as *mut c_void)?;
+        Ok(values)
+    }
+
+    /// Copy out an array that OSL returns as a pointer into the group,
+    /// with its length given by the `count` attribute.
+    fn array<T>(&self, count: &str, name: &str) -> Result<Vec<T>, Error> {
+        let n = self.int(count)?;
+        if n <= 0 {
+            return Ok(Vec::new());
+        }
+
+        let cname = cstring(name)?;
+        let mut ptr = std::ptr::null();
+        if !unsafe {
+            ffi::ShadingSystem_group_getattribute_ptr(
+                self.ss.ss,
+                self.group.group,
+                cname.as_ptr(),
+                &mut ptr,
+            )
+        } || ptr.is_null()
+        {
+            return Err(Error::GetGroupAttributeFailed(name.into()));
+        }
+
+        let ptr = ptr as *const T;
+        Ok((0..n as usize)
+            .map(|i| unsafe { std::ptr::read(ptr.add(i)) })
+            .collect())
+    }
+}
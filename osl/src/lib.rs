@@ -1,64 +1,227 @@
+#[cfg(feature = "oslexec")]
 mod ffi;
-use ffi::{ErrCode, PerThreadInfo, RendererServicesWrapper, ShadingContext, VerbosityLevel};
+#[cfg(feature = "oslexec")]
+use ffi::{ErrCode, PerThreadInfo, RendererServicesWrapper, ShadingContext};
 pub mod math;
 pub use math::*;
 
+#[cfg(feature = "oslexec")]
 pub mod renderer_services;
+#[cfg(feature = "oslexec")]
 pub use renderer_services::*;
 
+#[cfg(feature = "oslexec")]
 pub mod shader_globals;
+#[cfg(feature = "oslexec")]
 pub use shader_globals::*;
 
+#[cfg(feature = "oslexec")]
 pub mod shading_system;
+#[cfg(feature = "oslexec")]
 pub use shading_system::*;
 
+#[cfg(feature = "oslexec")]
+pub mod group_info;
+#[cfg(feature = "oslexec")]
+pub use group_info::*;
+
+#[cfg(feature = "oslexec")]
+pub mod options;
+#[cfg(feature = "oslexec")]
+pub use options::*;
+
+#[cfg(feature = "oslexec")]
+pub mod raytypes;
+#[cfg(feature = "oslexec")]
+pub use raytypes::*;
+
+#[cfg(feature = "oslexec")]
 pub mod shading_system_attribute;
+#[cfg(feature = "oslexec")]
 pub use shading_system_attribute::*;
 
+#[cfg(feature = "oslexec")]
 pub mod closure;
+#[cfg(feature = "oslexec")]
 pub use closure::*;
 
-mod test_renderer;
-use test_renderer::TestRenderer;
+#[cfg(feature = "oslexec")]
+pub mod symbol_value;
+#[cfg(feature = "oslexec")]
+pub use symbol_value::*;
+
+#[cfg(feature = "oslexec")]
+pub mod error_handler;
+#[cfg(feature = "oslexec")]
+pub use error_handler::*;
+
+#[cfg(feature = "oslexec")]
+pub mod compiler;
+
+#[cfg(feature = "oslexec")]
+pub mod noise;
+
+pub mod query;
 
-use std::os::raw::{c_char, c_void};
+#[cfg(feature = "oso")]
+pub mod oso;
 
-use std::cell::RefCell;
-use std::sync::Arc;
+#[cfg(all(feature = "batched", not(osl_1_12)))]
+compile_error!("The batched feature requires OSL 1.12 or later");
+#[cfg(feature = "batched")]
+pub mod batched;
+#[cfg(feature = "batched")]
+pub use batched::*;
 
-use oiio::imagebuf::ImageBuf;
-use oiio::imageio::{ImageSpec, ROI};
+#[cfg(all(test, feature = "oslexec"))]
+mod test_renderer;
+
+#[cfg(feature = "oslexec")]
 pub use oiio::typedesc;
+#[cfg(feature = "oslexec")]
 pub use oiio::typedesc::TypeDesc;
+#[cfg(feature = "oslexec")]
 pub use oiio::Ustring;
 
 #[macro_use]
 extern crate derive_more;
 
+/// The version of OSL the crate was built against, e.g. "1.11.7". build.rs
+/// also sets a cfg flag for each version it is at least, e.g. `osl_1_10`.
+#[cfg(feature = "oslexec")]
+pub const OSL_VERSION: &str = env!("OSL_VERSION");
+
 #[derive(Debug, Display)]
 pub enum Error {
     #[display(fmt = "PerThreadInfo creation failed")]
     ThreadInfoFailed,
+    #[display(fmt = "OSL failed to create {}", _0)]
+    NullHandle(&'static str),
+    #[display(fmt = "String {:?} contains a NUL byte", _0)]
+    InvalidString(String),
     #[display(fmt = "Failed to create shader '{}' '{}' '{}'", _0, _1, _2)]
     ShaderFailed(String, String, String),
+    #[display(fmt = "Failed to load compiled shader '{}'", _0)]
+    LoadShaderFailed(String),
+    #[display(fmt = "Failed to end shader group")]
+    ShaderGroupEndFailed,
     #[display(fmt = "Failed to set group attribute '{}' on shading system", _0)]
     SetGroupAttributeFailed(String),
     #[display(fmt = "Failed to set attribute '{}' on shading system", _0)]
     SetAttributeFailed(String),
+    #[display(fmt = "Failed to set attributes {:?} on shading system", _0)]
+    SetAttributesFailed(Vec<String>),
+    #[display(fmt = "Attribute arrays must not be empty")]
+    EmptyArray,
+    #[display(fmt = "Failed to get attribute '{}' from shading system", _0)]
+    GetAttributeFailed(String),
+    #[display(fmt = "Failed to get group attribute '{}'", _0)]
+    GetGroupAttributeFailed(String),
+    #[display(fmt = "Unknown ray type '{}'", _0)]
+    UnknownRayType(String),
+    #[display(fmt = "{} ray types given, OSL supports at most 32", _0)]
+    TooManyRayTypes(usize),
     #[display(fmt = "Symbol '{}' not found", _0)]
     SymbolNotFound(String),
     #[display(fmt = "shade_image failed")]
     ShadeImageFailed,
+    #[display(fmt = "Failed to optimize shader group")]
+    OptimizeFailed,
+    #[display(fmt = "Failed to optimize shader groups")]
+    OptimizeAllFailed,
     #[display(fmt = "Failed to get shading context")]
     GetShadingContextFailed,
     #[display(fmt = "Failed to execute shading group")]
     ExecuteFailed,
+    #[display(fmt = "Failed to initialize shading group for execution")]
+    ExecuteInitFailed,
+    #[display(fmt = "Failed to execute layer {}", _0)]
+    ExecuteLayerFailed(String),
+    #[display(fmt = "Failed to clean up after execution")]
+    ExecuteCleanupFailed,
+    #[display(fmt = "Layer '{}' not found", _0)]
+    LayerNotFound(String),
+    #[cfg(feature = "oslexec")]
+    #[display(fmt = "Symbol of type {:?} cannot be read as {}", _0, _1)]
+    SymbolTypeMismatch(TypeDesc, &'static str),
+    #[display(fmt = "Symbol has no value in this context")]
+    SymbolAddressFailed,
+    #[display(fmt = "Failed to configure batched execution at width {}", _0)]
+    BatchConfigurationFailed(usize),
+    #[display(fmt = "Invalid batch size {} for batch width {}", _0, _1)]
+    InvalidBatchSize(usize, usize),
+    #[cfg(feature = "oslexec")]
+    #[display(fmt = "Shader compilation failed:\n{}", _0)]
+    CompileFailed(compiler::Diagnostics),
+    #[display(fmt = "Could not read '{}': {}", _0, _1)]
+    ReadFailed(String, std::io::Error),
+    #[display(fmt = "Shader query failed: {}", _0)]
+    QueryFailed(String),
+    #[display(fmt = "Could not parse .oso at line {}: {}", _0, _1)]
+    OsoParseFailed(usize, String),
+    /// Another error, with the messages OSL reported while the call that
+    /// failed was running.
+    #[cfg(feature = "oslexec")]
+    #[display(fmt = "{}:\n{}", _0, _1)]
+    Reported(Box<Error>, error_handler::Messages),
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ReadFailed(_, e) => Some(e),
+            #[cfg(feature = "oslexec")]
+            Error::Reported(e, _) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
 }
 
-#[cfg(test)]
+/// Convert a string for passing to OSL, which can't represent strings
+/// containing NUL bytes.
+#[cfg(feature = "oslexec")]
+pub(crate) fn cstring(s: &str) -> Result<std::ffi::CString, Error> {
+    std::ffi::CString::new(s).map_err(|_| Error::InvalidString(s.into()))
+}
+
+/// Copy a string returned by OSL, which may be NULL for an empty string.
+#[cfg(feature = "oslexec")]
+pub(crate) unsafe fn to_string(s: *const std::os::raw::c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        std::ffi::CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+impl Error {
+    /// The messages OSL reported during the call that failed, if any.
+    #[cfg(feature = "oslexec")]
+    pub fn messages(&self) -> &[Message] {
+        match self {
+            Error::Reported(_, messages) => &messages.0,
+            _ => &[],
+        }
+    }
+
+    /// The error without any messages attached.
+    pub fn root(&self) -> &Error {
+        match self {
+            #[cfg(feature = "oslexec")]
+            Error::Reported(error, _) => error.root(),
+            error => error,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "oslexec"))]
 mod tests {
+    use crate::test_renderer::TestRenderer;
     use crate::*;
 
+    use oiio::imageio::ROI;
+    use std::sync::{Arc, Mutex};
+
     use osl_derive::Closure;
 
     #[repr(i32)]
@@ -103,20 +266,20 @@ mod tests {
         unsafe {
             let width = 512;
             let height = 512;
-            let mut renderer = TestRenderer::new(width, height);
-            let c: Arc<RefCell<TestRenderer>> = Arc::clone(&renderer);
-            let rs: Arc<RefCell<dyn RendererServices>> = c;
+            let renderer = TestRenderer::new(width, height);
+            let c: Arc<Mutex<TestRenderer>> = Arc::clone(&renderer);
+            let rs: Arc<dyn RendererServices + Send + Sync> = c;
             let mut ss = ShadingSystem::new(rs);
 
-            renderer.borrow_mut().init_shading_system(&ss);
+            renderer.lock().unwrap().init_shading_system(&ss);
 
             // Register the layout of all closures known to this renderer
             // Any closure used by the shader which is not registered, or
             // registered with a different number of arguments will lead
             // to a runtime error.
-            MicrofacetParams::register_with(&mut ss);
-            DiffuseParams::register_with(&mut ss);
-            EmissionParams::register_with(&mut ss);
+            MicrofacetParams::register_with(&mut ss).expect("Could not register closure");
+            DiffuseParams::register_with(&mut ss).expect("Could not register closure");
+            EmissionParams::register_with(&mut ss).expect("Could not register closure");
 
             // Remember that each shader parameter may optionally have a
             // metadata hint [[int lockgeom=...]], where 0 indicates that the
@@ -167,7 +330,9 @@ mod tests {
 
             // Start the shader group and grab a reference to it.
             let group_name = "";
-            let shadergroup = ss.shader_group_begin(group_name);
+            let shadergroup = ss
+                .shader_group_begin(group_name)
+                .expect("Could not begin shader group");
 
             // Set shader parameters and create shader
             ss.shader(&shadergroup, "surface", "noisetest", "")
@@ -177,10 +342,11 @@ mod tests {
             // ...
 
             // End the group definition
-            ss.shader_group_end(&shadergroup);
+            ss.shader_group_end(&shadergroup)
+                .expect("Could not end shader group");
 
             // Add the shaders to the renderer
-            renderer.borrow_mut().shaders.push(Arc::clone(&shadergroup));
+            renderer.lock().unwrap().shaders.push(Arc::clone(&shadergroup));
 
             // Set up transformations
             // ...
@@ -203,22 +369,13 @@ mod tests {
                     .expect("failed to set entry_layers attribute");
             }
 
-            let per_thread_info = ss
-                .create_thread_info()
-                .expect("Could not create per-thread info");
-            let ctx = ss
-                .get_context(per_thread_info)
-                .expect("Could not create context");
-
-            let sg = ShaderGlobals::new(ctx, renderer.borrow().rsw);
-            // set all the stuff on the shader globals here
-            // ...
-
-            // Because we can only call find_symbol or get_symbol on something that
-            // has been set up to shade (or executed), we call execute() but tell it
-            // not to actually run the shader.
-            ss.execute(ctx, &shadergroup, &sg, false)
-                .expect("Execute failed");
+            // The shading system lazily creates a context for this thread and
+            // keeps it around for later calls from the same thread.
+            // find_symbol only works on an optimized group, so optimize (and
+            // JIT) it up front.
+            ss.with_context(|ctx| ss.optimize_group(&shadergroup, Some(ctx)))
+                .expect("Could not get shading context")
+                .expect("Could not optimize group");
 
             // Find the symbol we want to output
             let sym_cout = ss
@@ -230,22 +387,16 @@ mod tests {
 
             // TODO: We should be taking the outputs in from command line and potentially
             // have many...
-            renderer.borrow_mut().add_output(
+            renderer.lock().unwrap().add_output(
                 "Cout",
                 "Cout.exr",
                 TypeDesc::from_basetype(sym_type.basetype),
                 sym_type.base_values() as i32,
             );
 
-            // release these for now since we've done setting up
-            // FIXME: these should be getting consumed, and have their lifetime tied
-            // to the ShadingSystem if not the ShaderGroup
-            ss.release_context(ctx);
-            ss.destroy_thread_info(per_thread_info);
-
-            renderer.borrow_mut().prepare_render();
+            renderer.lock().unwrap().prepare_render();
 
-            renderer.borrow_mut().warmup();
+            renderer.lock().unwrap().warmup();
 
             let roi = ROI::new(0, width, 0, height);
             let outputs = vec![Ustring::new("Cout")];
@@ -253,24 +404,641 @@ mod tests {
             #[cfg(feature = "optix")]
             renderer.render(width, height);
             #[cfg(not(feature = "optix"))]
-            ss.shade_image(
-                &shadergroup,
-                Some(&sg),
-                &renderer.borrow().output_bufs[0],
-                outputs.as_slice(),
-                0,
-                roi,
-            )
-            .expect("Shade image failed");
+            {
+                let rsw = renderer.lock().unwrap().rsw;
+                ss.with_context(|ctx| {
+                    let sg = ShaderGlobals::new(ctx, rsw);
+                    // set all the stuff on the shader globals here
+                    // ...
+
+                    ss.shade_image(
+                        &shadergroup,
+                        Some(&sg),
+                        &renderer.lock().unwrap().output_bufs[0],
+                        outputs.as_slice(),
+                        0,
+                        roi,
+                    )
+                })
+                .expect("Could not get shading context")
+                .expect("Shade image failed");
+            }
 
             // copy result to host
-            renderer.borrow_mut().finalize_pixel_buffer();
+            renderer.lock().unwrap().finalize_pixel_buffer();
 
             // write image to disk
-            let output_name = renderer.borrow().output_bufs[0].name();
-            renderer.borrow().output_bufs[0]
+            let output_name = renderer.lock().unwrap().output_bufs[0].name();
+            renderer.lock().unwrap().output_bufs[0]
                 .write(&output_name, typedesc::FLOAT)
                 .expect("Could not write image");
         }
     }
+
+    #[test]
+    fn memory_compiled_shader() {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let ss = ShadingSystem::new(rs);
+
+        // Nothing is read from disk: there's no searchpath:shader set and
+        // the shader is registered under a name that has no .oso file.
+        ss.load_memory_compiled_shader("noisetest_in_memory", include_str!("../osl/noisetest.oso"))
+            .expect("Could not load shader from memory");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "noisetest_in_memory", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        ss.optimize_group(&group, None)
+            .expect("Could not optimize group");
+
+        ss.find_symbol(&group, Ustring::new("Cout"))
+            .expect("Could not find Cout symbol");
+    }
+
+    #[test]
+    fn group_info() {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        ss.load_memory_compiled_shader("noisetest", include_str!("../osl/noisetest.oso"))
+            .expect("Could not load shader from memory");
+        // Otherwise the optimizer removes everything, as nothing reads Cout
+        ss.attribute("renderer_outputs", &["Cout"][..])
+            .expect("Failed to set renderer_outputs attribute");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "noisetest", "noise")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        let info = ss.group_info(&group);
+        assert_eq!(info.num_layers().unwrap(), 1);
+        let names = info.layer_names().unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].to_string(), "noise");
+
+        assert!(info.textures_needed().unwrap().is_empty());
+        let globals = info
+            .globals_needed()
+            .unwrap()
+            .iter()
+            .map(|g| g.to_string())
+            .collect::<Vec<_>>();
+        assert!(globals.contains(&"u".to_string()));
+        assert!(globals.contains(&"v".to_string()));
+
+        // A group without layers has no names to read
+        let empty = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader_group_end(&empty)
+            .expect("Could not end shader group");
+        assert!(ss.group_info(&empty).layer_names().unwrap().is_empty());
+    }
+
+    #[test]
+    fn get_attribute() {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+
+        ss.attribute("lockgeom", 0i32)
+            .expect("Could not set lockgeom");
+        ss.attribute("searchpath:shader", "osl")
+            .expect("Could not set searchpath");
+        let mut raytypes = RayTypes::new();
+        raytypes.add("camera").add("shadow");
+        raytypes.apply(&mut ss).expect("Could not set raytypes");
+
+        assert_eq!(ss.get_attribute::<i32>("lockgeom").unwrap(), 0);
+        assert_eq!(
+            ss.get_attribute::<String>("searchpath:shader").unwrap(),
+            "osl"
+        );
+        assert_eq!(
+            ss.get_attribute::<Vec<String>>("raytypes").unwrap(),
+            raytypes.names()
+        );
+        // Arrays are read back at their length, however long
+        assert!(ss
+            .get_attribute::<Vec<String>>("renderer_outputs")
+            .unwrap()
+            .is_empty());
+        let outputs = (0..300).map(|i| format!("aov{}", i)).collect::<Vec<_>>();
+        ss.attribute("renderer_outputs", outputs.as_slice())
+            .expect("Could not set renderer_outputs");
+        assert_eq!(
+            ss.get_attribute::<Vec<String>>("renderer_outputs").unwrap(),
+            outputs
+        );
+        assert!(ss.get_attribute::<i32>("stat:shaders_loaded").is_ok());
+        assert!(ss.get_attribute::<i32>("no_such_attribute").is_err());
+        match ss.attribute("searchpath:shader", "bad\0path") {
+            Err(Error::InvalidString(s)) => assert_eq!(s, "bad\0path"),
+            other => panic!("Expected InvalidString, got {:?}", other),
+        }
+        match ss.attribute("renderer_outputs", Vec::<String>::new()) {
+            Err(Error::EmptyArray) => {}
+            other => panic!("Expected EmptyArray, got {:?}", other),
+        }
+        assert_eq!(
+            ss.get_attribute::<Ustring>("searchpath:shader")
+                .unwrap()
+                .to_string(),
+            "osl"
+        );
+
+        let group = ss
+            .shader_group_begin("named_group")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "noisetest", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+        assert_eq!(
+            ss.get_group_attribute::<String>(&group, "groupname")
+                .unwrap(),
+            "named_group"
+        );
+        assert_eq!(
+            ss.get_group_attribute::<i32>(&group, "num_layers").unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn error_handler() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let m = Arc::clone(&messages);
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let ss = ShadingSystem::with_error_handler(rs, move |level, msg| {
+            m.lock().unwrap().push((level, msg.to_string()));
+        });
+        ss.set_verbosity(VerbosityLevel::Quiet);
+        assert_eq!(ss.verbosity(), VerbosityLevel::Quiet);
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        let err = ss
+            .shader(&group, "surface", "no_such_shader", "")
+            .expect_err("Shader creation should fail");
+        match err.root() {
+            Error::ShaderFailed(..) => (),
+            e => panic!("Unexpected error {}", e),
+        }
+        assert!(err
+            .messages()
+            .iter()
+            .any(|m| m.level == ErrorLevel::Error && m.message.contains("no_such_shader")));
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(level, msg)| *level == ErrorLevel::Error && msg.contains("no_such_shader")));
+    }
+
+    #[test]
+    fn invalid_strings() {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+
+        fn assert_invalid<T>(result: Result<T, Error>, s: &str) {
+            match result.as_ref().err().map(|e| e.root()) {
+                Some(Error::InvalidString(bad)) => assert_eq!(bad, s),
+                other => panic!("Expected InvalidString, got {:?}", other),
+            }
+        }
+
+        assert_invalid(ss.shader_group_begin("bad\0group"), "bad\0group");
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        assert_invalid(
+            ss.shader(&group, "surface", "bad\0shader", ""),
+            "bad\0shader",
+        );
+        assert_invalid(
+            ss.shader(&group, "surface", "noisetest", "bad\0layer"),
+            "bad\0layer",
+        );
+        let params = [ClosureParam {
+            typedesc: typedesc::FLOAT,
+            offset: 0,
+            key: Some("bad\0key".into()),
+            field_size: 4,
+        }];
+        assert_invalid(ss.register_closure("bad_key", 3, &params), "bad\0key");
+
+        // The messages are only attached, the error is still the source
+        let err = Error::Reported(Box::new(Error::ExecuteFailed), Messages(Vec::new()));
+        assert_eq!(
+            std::error::Error::source(&err).map(|e| e.to_string()),
+            Some(Error::ExecuteFailed.to_string())
+        );
+    }
+
+    #[test]
+    fn shader_log() {
+        let compiled = compiler::Compiler::new()
+            .compile_buffer(
+                "shader printer(output color Cout = 0) {\n    printf(\"u is %g\\n\", u);\n    Cout = color(u);\n}\n",
+            )
+            .expect("Could not compile shader");
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        ss.attribute("renderer_outputs", &["Cout"][..])
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("printer", &compiled.oso)
+            .expect("Could not load shader from memory");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "printer", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        let rsw = renderer.lock().unwrap().rsw;
+        let log = ss
+            .with_context(|ctx| {
+                let mut sg = ShaderGlobals::new(ctx, rsw);
+                sg.u = 0.5;
+                ss.execute_with_log(ctx, &group, &mut sg, true)
+            })
+            .expect("Could not get shading context")
+            .expect("Execute failed");
+
+        assert_eq!(log.u, 0.5);
+        assert!(log.messages.iter().any(|m| m.message.contains("u is 0.5")));
+    }
+
+    #[test]
+    fn noise_matches_shader() {
+        use noise::NoiseType;
+        use std::collections::HashMap;
+
+        let kinds = [
+            (NoiseType::UPerlin, "uperlin"),
+            (NoiseType::Perlin, "perlin"),
+            (NoiseType::Cell, "cell"),
+            (NoiseType::Hash, "hash"),
+        ];
+        // The domain of each dimension as arguments to noise(), and a period
+        let domains = [
+            ("P[0]", "3"),
+            ("P[0], P[1]", "3, 5"),
+            ("P", "point(3, 5, 7)"),
+            ("P, time", "point(3, 5, 7), 11"),
+        ];
+
+        // Every type of noise in every dimension, float and vector, plain
+        // and periodic, each both without and with derivatives
+        let mut params = Vec::new();
+        let mut body = String::new();
+        let mut floats = Vec::new();
+        let mut vectors = Vec::new();
+        for (_, kind) in &kinds {
+            for (i, (domain, period)) in domains.iter().enumerate() {
+                for (prefix, ty) in &[("f", "float"), ("v", "vector")] {
+                    let calls = [
+                        ("", format!("noise(\"{}\", {})", kind, domain)),
+                        ("p", format!("pnoise(\"{}\", {}, {})", kind, domain, period)),
+                    ];
+                    for (periodic, call) in &calls {
+                        let name = format!("{}{}_{}_{}", periodic, prefix, kind, i + 1);
+                        let names = ["", "_d", "_dx", "_dy"]
+                            .iter()
+                            .map(|suffix| format!("{}{}", name, suffix))
+                            .collect::<Vec<_>>();
+                        for n in &names {
+                            params.push(format!("output {} {} = 0", ty, n));
+                        }
+                        body += &format!(
+                            "    {0} = {4};\n    {1} = {4};\n    {2} = Dx({1});\n    {3} = Dy({1});\n",
+                            names[0], names[1], names[2], names[3], call
+                        );
+                        if *ty == "float" {
+                            floats.extend(names);
+                        } else {
+                            vectors.extend(names);
+                        }
+                    }
+                }
+            }
+        }
+        let source = format!("shader noises({}) {{\n{}}}\n", params.join(", "), body);
+        let compiled = compiler::Compiler::new()
+            .compile_buffer(&source)
+            .expect("Could not compile shader");
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        let outputs = floats.iter().chain(&vectors).cloned().collect::<Vec<_>>();
+        ss.attribute("renderer_outputs", outputs.as_slice())
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("noises", &compiled.oso)
+            .expect("Could not load shader from memory");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "noises", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        let p = v3f32(0.37, -1.2, 5.81);
+        let dpdx = v3f32(0.01, 0.002, -0.003);
+        let dpdy = v3f32(-0.004, 0.02, 0.001);
+        let time = 0.6;
+        let rsw = renderer.lock().unwrap().rsw;
+        let (floats, vectors) = ss
+            .with_context(|ctx| {
+                let mut sg = ShaderGlobals::new(ctx, rsw);
+                sg.P = p;
+                sg.dPdx = dpdx;
+                sg.dPdy = dpdy;
+                sg.time = time;
+                ss.execute(ctx, &group, &mut sg, true)
+                    .expect("Execute failed");
+                let read = |name: &String| ss.find_symbol(&group, Ustring::new(name)).unwrap();
+                // ctx has just run group
+                unsafe {
+                    let floats = floats
+                        .iter()
+                        .map(|n| (n.clone(), ss.symbol_value::<f32>(ctx, &read(n)).unwrap()))
+                        .collect::<HashMap<_, _>>();
+                    let vectors = vectors
+                        .iter()
+                        .map(|n| (n.clone(), ss.symbol_value::<V3f32>(ctx, &read(n)).unwrap()))
+                        .collect::<HashMap<_, _>>();
+                    (floats, vectors)
+                }
+            })
+            .expect("Could not get shading context");
+
+        let float = |name: String| floats[&name];
+        let vector = |name: String| vectors[&name];
+        let dfloat = |name: String| {
+            Dual2::new(
+                float(format!("{}_d", name)),
+                float(format!("{}_dx", name)),
+                float(format!("{}_dy", name)),
+            )
+        };
+        let dvector = |name: String| {
+            Dual2::new(
+                vector(format!("{}_d", name)),
+                vector(format!("{}_dx", name)),
+                vector(format!("{}_dy", name)),
+            )
+        };
+
+        // The same bits, for the domain without and with derivatives
+        macro_rules! check {
+            ($dims:expr, $p:expr, $dp:expr, $period:expr) => {
+                for &(kind, kname) in &kinds {
+                    let name = |prefix: &str| format!("{}_{}_{}", prefix, kname, $dims);
+                    assert_eq!(float(name("f")), noise::noise(kind, $p), "{}", name("f"));
+                    assert_eq!(dfloat(name("f")), noise::noise(kind, $dp), "{}", name("f"));
+                    assert_eq!(vector(name("v")), noise::vnoise(kind, $p), "{}", name("v"));
+                    assert_eq!(
+                        dvector(name("v")),
+                        noise::vnoise(kind, $dp),
+                        "{}",
+                        name("v")
+                    );
+                    let (f, pf) = (name("pf"), noise::pnoise(kind, $p, $period));
+                    assert_eq!(float(f.clone()), pf, "{}", f);
+                    let pf = noise::pnoise(kind, $dp, $period);
+                    assert_eq!(dfloat(f.clone()), pf, "{}", f);
+                    let (v, pv) = (name("pv"), noise::vpnoise(kind, $p, $period));
+                    assert_eq!(vector(v.clone()), pv, "{}", v);
+                    let pv = noise::vpnoise(kind, $dp, $period);
+                    assert_eq!(dvector(v.clone()), pv, "{}", v);
+                }
+            };
+        }
+
+        check!(1, p.x, Dual2::new(p.x, dpdx.x, dpdy.x), 3.0);
+        check!(
+            2,
+            v2f32(p.x, p.y),
+            Dual2::new(
+                v2f32(p.x, p.y),
+                v2f32(dpdx.x, dpdx.y),
+                v2f32(dpdy.x, dpdy.y)
+            ),
+            v2f32(3.0, 5.0)
+        );
+        check!(3, p, Dual2::new(p, dpdx, dpdy), v3f32(3.0, 5.0, 7.0));
+        // time has no derivatives
+        check!(
+            4,
+            v4f32(p.x, p.y, p.z, time),
+            Dual2::new(
+                v4f32(p.x, p.y, p.z, time),
+                v4f32(dpdx.x, dpdx.y, dpdx.z, 0.0),
+                v4f32(dpdy.x, dpdy.y, dpdy.z, 0.0)
+            ),
+            v4f32(3.0, 5.0, 7.0, 11.0)
+        );
+    }
+
+    #[test]
+    fn gabor_matches_shader() {
+        use noise::{Gabor, GaborAnisotropy, GaborOptions};
+
+        let source = "shader gabors(output float f = 0, output float fdx = 0, output float fdy = 0,\n              output vector v = 0, output vector vdx = 0, output vector vdy = 0,\n              output float pf = 0, output float pfdx = 0, output float pfdy = 0) {\n    f = noise(\"gabor\", P, \"anisotropic\", 2, \"direction\", vector(0, 1, 0), \"bandwidth\", 1.5);\n    fdx = Dx(f);\n    fdy = Dy(f);\n    v = noise(\"gabor\", P[0], P[1], \"anisotropic\", 2, \"direction\", vector(0, 1, 0), \"bandwidth\", 1.5);\n    vdx = Dx(v);\n    vdy = Dy(v);\n    pf = pnoise(\"gabor\", P[0], 3, \"anisotropic\", 2, \"direction\", vector(0, 1, 0), \"bandwidth\", 1.5);\n    pfdx = Dx(pf);\n    pfdy = Dy(pf);\n}\n";
+        let compiled = compiler::Compiler::new()
+            .compile_buffer(source)
+            .expect("Could not compile shader");
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        let floats = ["f", "fdx", "fdy", "pf", "pfdx", "pfdy"];
+        let vectors = ["v", "vdx", "vdy"];
+        let outputs = floats.iter().chain(&vectors).cloned().collect::<Vec<_>>();
+        ss.attribute("renderer_outputs", outputs.as_slice())
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("gabors", &compiled.oso)
+            .expect("Could not load shader from memory");
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "gabors", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        let p = v3f32(0.37, -1.2, 5.81);
+        let dpdx = v3f32(0.01, 0.002, -0.003);
+        let dpdy = v3f32(-0.004, 0.02, 0.001);
+        let rsw = renderer.lock().unwrap().rsw;
+        let (f, v, pf) = ss
+            .with_context(|ctx| {
+                let mut sg = ShaderGlobals::new(ctx, rsw);
+                sg.P = p;
+                sg.dPdx = dpdx;
+                sg.dPdy = dpdy;
+                ss.execute(ctx, &group, &mut sg, true)
+                    .expect("Execute failed");
+                let read = |name: &str| ss.find_symbol(&group, Ustring::new(name)).unwrap();
+                // ctx has just run group
+                unsafe {
+                    let float = |n| ss.symbol_value::<f32>(ctx, &read(n)).unwrap();
+                    let vector = |n| ss.symbol_value::<V3f32>(ctx, &read(n)).unwrap();
+                    (
+                        Dual2::new(float("f"), float("fdx"), float("fdy")),
+                        Dual2::new(vector("v"), vector("vdx"), vector("vdy")),
+                        Dual2::new(float("pf"), float("pfdx"), float("pfdy")),
+                    )
+                }
+            })
+            .expect("Could not get shading context");
+
+        let gabor = Gabor::new(&GaborOptions {
+            anisotropic: GaborAnisotropy::Hybrid,
+            direction: v3f32(0.0, 1.0, 0.0),
+            bandwidth: 1.5,
+            ..Default::default()
+        })
+        .expect("Could not create Gabor");
+        assert_eq!(gabor.noise(Dual2::new(p, dpdx, dpdy)).unwrap(), f);
+        let p2 = Dual2::new(
+            v2f32(p.x, p.y),
+            v2f32(dpdx.x, dpdx.y),
+            v2f32(dpdy.x, dpdy.y),
+        );
+        assert_eq!(gabor.vnoise(p2).unwrap(), v);
+        let p1 = Dual2::new(p.x, dpdx.x, dpdy.x);
+        assert_eq!(gabor.pnoise(p1, 3.0).unwrap(), pf);
+    }
+
+    #[test]
+    fn derivative_callbacks() {
+        let compiled = compiler::Compiler::new()
+            .compile_buffer(
+                "shader callbacks(float userfloat = 0 [[int lockgeom = 0]],\n                 output float f = 0, output float fdx = 0,\n                 output color c = 0, output color cdx = 0) {\n    f = userfloat;\n    fdx = Dx(userfloat);\n    c = texture(\"st\", u, v);\n    cdx = Dx(c);\n}\n",
+            )
+            .expect("Could not compile shader");
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rsw = renderer.lock().unwrap().rsw;
+        unsafe { set_renderer_services::<TestRenderer>(rsw) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        ss.attribute("renderer_outputs", &["f", "fdx", "c", "cdx"][..])
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("callbacks", &compiled.oso)
+            .expect("Could not load shader from memory");
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "callbacks", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        ss.with_context(|ctx| {
+            let mut sg = ShaderGlobals::new(ctx, rsw);
+            sg.u = 0.25;
+            sg.dudx = 0.5;
+            sg.v = 0.75;
+            sg.dvdx = 0.125;
+            ss.execute(ctx, &group, &mut sg, true)
+                .expect("Execute failed");
+            let read = |name: &str| ss.find_symbol(&group, Ustring::new(name)).unwrap();
+            // ctx has just run group
+            unsafe {
+                // userfloat is twice u
+                assert_eq!(ss.symbol_value::<f32>(ctx, &read("f")).unwrap(), 0.5);
+                assert_eq!(ss.symbol_value::<f32>(ctx, &read("fdx")).unwrap(), 1.0);
+                // The texture is (s, t, 0), looked up at (u, v)
+                let c = ss.symbol_value::<V3f32>(ctx, &read("c")).unwrap();
+                assert_eq!(c, v3f32(0.25, 0.75, 0.0));
+                let cdx = ss.symbol_value::<V3f32>(ctx, &read("cdx")).unwrap();
+                assert_eq!(cdx, v3f32(0.5, 0.125, 0.0));
+            }
+        })
+        .expect("Could not get shading context");
+    }
+
+    #[test]
+    fn math_types_round_trip() {
+        let compiled = compiler::Compiler::new()
+            .compile_buffer(
+                "shader layouts(matrix M = matrix(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16),\n               output matrix Mout = 1, output point Pout = 0, output color Cout = 0) {\n    Mout = M;\n    Pout = transform(matrix(1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 5, 6, 7, 1), point(1, 2, 3));\n    Cout = color(0.25, 0.5, 0.75);\n}\n",
+            )
+            .expect("Could not compile shader");
+
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        ss.attribute("renderer_outputs", &["Mout", "Pout", "Cout"][..])
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("layouts", &compiled.oso)
+            .expect("Could not load shader from memory");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "layouts", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+
+        let rsw = renderer.lock().unwrap().rsw;
+        let (m, p, c) = ss
+            .with_context(|ctx| {
+                let mut sg = ShaderGlobals::new(ctx, rsw);
+                ss.execute(ctx, &group, &mut sg, true)
+                    .expect("Execute failed");
+                let m = ss.find_symbol(&group, Ustring::new("Mout")).unwrap();
+                let p = ss.find_symbol(&group, Ustring::new("Pout")).unwrap();
+                let c = ss.find_symbol(&group, Ustring::new("Cout")).unwrap();
+                // ctx has just run group
+                unsafe {
+                    (
+                        ss.symbol_value::<Matrix44>(ctx, &m).unwrap(),
+                        ss.symbol_value::<Vec3>(ctx, &p).unwrap(),
+                        ss.symbol_value::<Color3>(ctx, &c).unwrap(),
+                    )
+                }
+            })
+            .expect("Could not get shading context");
+
+        // The shading language's matrix constructor is row-major too
+        assert_eq!(m.m[0], [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(m.m[3], [13.0, 14.0, 15.0, 16.0]);
+        assert_eq!(M4f32::from(m)[(0, 1)], 2.0);
+
+        let t = Matrix44::translation(Vec3::new(5.0, 6.0, 7.0));
+        assert_eq!(p, Vec3::new(6.0, 8.0, 10.0));
+        assert_eq!(t.transform_point(Vec3::new(1.0, 2.0, 3.0)), p);
+        assert_eq!(c, Color3::new(0.25, 0.5, 0.75));
+
+        // With the default Rec709 colorspace, green weighs the most
+        let green = ss.luminance(Color3::new(0.0, 1.0, 0.0));
+        assert!((green - 0.7152).abs() < 1e-4);
+        assert!((ss.luminance(Color3::new(1.0, 1.0, 1.0)) - 1.0).abs() < 1e-4);
+    }
 }
@@ -1,11 +1,13 @@
 use nalgebra_glm as glm;
 use nalgebra_glm::{
     DMat4x4, DVec2, DVec3, DVec4, IVec2, IVec3, IVec4, Mat4x4, U32Vec2,
-    U32Vec3, U32Vec4, Vec2, Vec3, Vec4,
+    U32Vec3, U32Vec4, Vec2, Vec4,
 };
 
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
 pub type V2f32 = Vec2;
-pub type V3f32 = Vec3;
+pub type V3f32 = glm::Vec3;
 pub type V4f32 = Vec4;
 pub type V2f64 = DVec2;
 pub type V3f64 = DVec3;
@@ -96,4 +98,679 @@ pub fn m4f64_rotation(axis: V3f64, angle: f64) -> M4f64 {
 
 pub fn m4f64_scaling(x: f64, y: f64, z: f64) -> M4f64 {
     M4f64::new_nonuniform_scaling(&v3f64(x, y, z))
-}
\ No newline at end of file
+}
+
+/// A value together with its derivatives in screen x and y, laid out like
+/// OSL's `Dual2<T>`, so that a `Dual2<f32>` or `Dual2<V3f32>` has the same
+/// memory layout as a float or vector symbol that carries derivatives.
+///
+/// Arithmetic on Dual2 values propagates the derivatives by the chain rule,
+/// the same way OSL does for shader code.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Dual2<T> {
+    pub val: T,
+    pub dx: T,
+    pub dy: T,
+}
+
+impl<T> Dual2<T> {
+    pub fn new(val: T, dx: T, dy: T) -> Dual2<T> {
+        Dual2 { val, dx, dy }
+    }
+}
+
+impl<T: Add<Output = T>> Add for Dual2<T> {
+    type Output = Dual2<T>;
+
+    fn add(self, o: Dual2<T>) -> Dual2<T> {
+        Dual2::new(self.val + o.val, self.dx + o.dx, self.dy + o.dy)
+    }
+}
+
+impl<T: Sub<Output = T>> Sub for Dual2<T> {
+    type Output = Dual2<T>;
+
+    fn sub(self, o: Dual2<T>) -> Dual2<T> {
+        Dual2::new(self.val - o.val, self.dx - o.dx, self.dy - o.dy)
+    }
+}
+
+impl<T: Neg<Output = T>> Neg for Dual2<T> {
+    type Output = Dual2<T>;
+
+    fn neg(self) -> Dual2<T> {
+        Dual2::new(-self.val, -self.dx, -self.dy)
+    }
+}
+
+macro_rules! impl_dual2_scalar {
+    ($t:ty) => {
+        impl Dual2<$t> {
+            /// A value that doesn't vary, i.e. has zero derivatives.
+            pub fn constant(val: $t) -> Dual2<$t> {
+                Dual2::new(val, 0.0, 0.0)
+            }
+
+            // The result of a function whose value at self.val is f and
+            // whose derivative there is df
+            fn chain(&self, f: $t, df: $t) -> Dual2<$t> {
+                Dual2::new(f, df * self.dx, df * self.dy)
+            }
+
+            pub fn sqrt(self) -> Dual2<$t> {
+                // As OSL, no derivatives where sqrt isn't differentiable
+                if self.val <= 0.0 {
+                    return Self::constant(self.val.max(0.0).sqrt());
+                }
+                let s = self.val.sqrt();
+                self.chain(s, 0.5 / s)
+            }
+
+            pub fn exp(self) -> Dual2<$t> {
+                let e = self.val.exp();
+                self.chain(e, e)
+            }
+
+            pub fn ln(self) -> Dual2<$t> {
+                self.chain(self.val.ln(), 1.0 / self.val)
+            }
+
+            pub fn sin(self) -> Dual2<$t> {
+                self.chain(self.val.sin(), self.val.cos())
+            }
+
+            pub fn cos(self) -> Dual2<$t> {
+                self.chain(self.val.cos(), -self.val.sin())
+            }
+
+            pub fn tan(self) -> Dual2<$t> {
+                let c = self.val.cos();
+                self.chain(self.val.tan(), 1.0 / (c * c))
+            }
+
+            pub fn asin(self) -> Dual2<$t> {
+                self.chain(self.val.asin(), 1.0 / (1.0 - self.val * self.val).sqrt())
+            }
+
+            pub fn acos(self) -> Dual2<$t> {
+                self.chain(self.val.acos(), -1.0 / (1.0 - self.val * self.val).sqrt())
+            }
+
+            pub fn atan(self) -> Dual2<$t> {
+                self.chain(self.val.atan(), 1.0 / (1.0 + self.val * self.val))
+            }
+
+            /// atan2(self, x), with derivatives from both arguments.
+            pub fn atan2(self, x: Dual2<$t>) -> Dual2<$t> {
+                let d = self.val * self.val + x.val * x.val;
+                let (dy, dx) = if d == 0.0 {
+                    (0.0, 0.0)
+                } else {
+                    (x.val / d, -self.val / d)
+                };
+                Dual2::new(
+                    self.val.atan2(x.val),
+                    dy * self.dx + dx * x.dx,
+                    dy * self.dy + dx * x.dy,
+                )
+            }
+
+            pub fn abs(self) -> Dual2<$t> {
+                if self.val < 0.0 {
+                    -self
+                } else {
+                    self
+                }
+            }
+
+            /// self raised to a constant power.
+            pub fn powf(self, e: $t) -> Dual2<$t> {
+                self.chain(self.val.powf(e), e * self.val.powf(e - 1.0))
+            }
+
+            /// self raised to a varying power.
+            pub fn pow(self, e: Dual2<$t>) -> Dual2<$t> {
+                if self.val <= 0.0 {
+                    return self.powf(e.val);
+                }
+                let p = self.val.powf(e.val);
+                let da = e.val * self.val.powf(e.val - 1.0);
+                let db = p * self.val.ln();
+                Dual2::new(p, da * self.dx + db * e.dx, da * self.dy + db * e.dy)
+            }
+        }
+
+        impl Mul for Dual2<$t> {
+            type Output = Dual2<$t>;
+
+            fn mul(self, o: Dual2<$t>) -> Dual2<$t> {
+                Dual2::new(
+                    self.val * o.val,
+                    self.dx * o.val + self.val * o.dx,
+                    self.dy * o.val + self.val * o.dy,
+                )
+            }
+        }
+
+        impl Div for Dual2<$t> {
+            type Output = Dual2<$t>;
+
+            fn div(self, o: Dual2<$t>) -> Dual2<$t> {
+                let inv = 1.0 / o.val;
+                let q = self.val * inv;
+                Dual2::new(q, (self.dx - q * o.dx) * inv, (self.dy - q * o.dy) * inv)
+            }
+        }
+
+        impl Add<$t> for Dual2<$t> {
+            type Output = Dual2<$t>;
+
+            fn add(self, o: $t) -> Dual2<$t> {
+                Dual2::new(self.val + o, self.dx, self.dy)
+            }
+        }
+
+        impl Sub<$t> for Dual2<$t> {
+            type Output = Dual2<$t>;
+
+            fn sub(self, o: $t) -> Dual2<$t> {
+                Dual2::new(self.val - o, self.dx, self.dy)
+            }
+        }
+
+        impl Mul<$t> for Dual2<$t> {
+            type Output = Dual2<$t>;
+
+            fn mul(self, o: $t) -> Dual2<$t> {
+                Dual2::new(self.val * o, self.dx * o, self.dy * o)
+            }
+        }
+
+        impl Mul<Dual2<$t>> for $t {
+            type Output = Dual2<$t>;
+
+            fn mul(self, o: Dual2<$t>) -> Dual2<$t> {
+                o * self
+            }
+        }
+
+        impl Div<$t> for Dual2<$t> {
+            type Output = Dual2<$t>;
+
+            fn div(self, o: $t) -> Dual2<$t> {
+                self * (1.0 / o)
+            }
+        }
+
+        impl From<$t> for Dual2<$t> {
+            fn from(val: $t) -> Dual2<$t> {
+                Self::constant(val)
+            }
+        }
+    };
+}
+
+impl_dual2_scalar!(f32);
+impl_dual2_scalar!(f64);
+
+macro_rules! impl_dual2_vec3 {
+    ($v:ty, $t:ty) => {
+        impl Dual2<$v> {
+            /// A value that doesn't vary, i.e. has zero derivatives.
+            pub fn constant(val: $v) -> Dual2<$v> {
+                Dual2::new(val, <$v>::zeros(), <$v>::zeros())
+            }
+
+            /// Assemble a vector from its components and their derivatives.
+            pub fn from_components(x: Dual2<$t>, y: Dual2<$t>, z: Dual2<$t>) -> Dual2<$v> {
+                Dual2::new(
+                    <$v>::new(x.val, y.val, z.val),
+                    <$v>::new(x.dx, y.dx, z.dx),
+                    <$v>::new(x.dy, y.dy, z.dy),
+                )
+            }
+
+            pub fn x(&self) -> Dual2<$t> {
+                Dual2::new(self.val.x, self.dx.x, self.dy.x)
+            }
+
+            pub fn y(&self) -> Dual2<$t> {
+                Dual2::new(self.val.y, self.dx.y, self.dy.y)
+            }
+
+            pub fn z(&self) -> Dual2<$t> {
+                Dual2::new(self.val.z, self.dx.z, self.dy.z)
+            }
+
+            pub fn dot(&self, o: &Dual2<$v>) -> Dual2<$t> {
+                Dual2::new(
+                    self.val.dot(&o.val),
+                    self.dx.dot(&o.val) + self.val.dot(&o.dx),
+                    self.dy.dot(&o.val) + self.val.dot(&o.dy),
+                )
+            }
+
+            pub fn cross(&self, o: &Dual2<$v>) -> Dual2<$v> {
+                Dual2::new(
+                    self.val.cross(&o.val),
+                    self.dx.cross(&o.val) + self.val.cross(&o.dx),
+                    self.dy.cross(&o.val) + self.val.cross(&o.dy),
+                )
+            }
+
+            pub fn length(&self) -> Dual2<$t> {
+                self.dot(self).sqrt()
+            }
+
+            /// The unit vector in the direction of self, or zero if self is
+            /// zero.
+            pub fn normalize(&self) -> Dual2<$v> {
+                let len = self.length();
+                if len.val == 0.0 {
+                    return Self::constant(<$v>::zeros());
+                }
+                *self / len
+            }
+        }
+
+        impl Mul<Dual2<$t>> for Dual2<$v> {
+            type Output = Dual2<$v>;
+
+            fn mul(self, o: Dual2<$t>) -> Dual2<$v> {
+                Dual2::new(
+                    self.val * o.val,
+                    self.dx * o.val + self.val * o.dx,
+                    self.dy * o.val + self.val * o.dy,
+                )
+            }
+        }
+
+        impl Mul<$t> for Dual2<$v> {
+            type Output = Dual2<$v>;
+
+            fn mul(self, o: $t) -> Dual2<$v> {
+                Dual2::new(self.val * o, self.dx * o, self.dy * o)
+            }
+        }
+
+        impl Div<Dual2<$t>> for Dual2<$v> {
+            type Output = Dual2<$v>;
+
+            fn div(self, o: Dual2<$t>) -> Dual2<$v> {
+                self * (Dual2::<$t>::constant(1.0) / o)
+            }
+        }
+
+        impl Div<$t> for Dual2<$v> {
+            type Output = Dual2<$v>;
+
+            fn div(self, o: $t) -> Dual2<$v> {
+                self * (1.0 / o)
+            }
+        }
+
+        impl From<$v> for Dual2<$v> {
+            fn from(val: $v) -> Dual2<$v> {
+                Self::constant(val)
+            }
+        }
+    };
+}
+
+impl_dual2_vec3!(V3f32, f32);
+impl_dual2_vec3!(V3f64, f64);
+
+/// A vector laid out like Imath's `V3f`, which is OSL's `Vec3`, for points,
+/// vectors and normals that are passed to or from OSL in memory.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vec3 {
+    pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+}
+
+/// A color laid out like Imath's `C3f`, which is OSL's `Color3`. This is a
+/// separate type from Vec3 so that colors aren't mistaken for vectors,
+/// e.g. when choosing the TypeDesc to give OSL for an attribute.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Color3 {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl Color3 {
+    pub fn new(r: f32, g: f32, b: f32) -> Color3 {
+        Color3 { r, g, b }
+    }
+}
+
+/// A 4x4 matrix laid out like Imath's `M44f`, which is OSL's `Matrix44`.
+///
+/// The layout is row-major, `m[row][col]`. Imath multiplies row vectors by
+/// matrices, `p' = p * M`, so the translation of a transform is in the last
+/// row, `m[3][0..3]`, which is how OSL's `matrix` type and `transform()`
+/// treat it too.
+///
+/// Converting to or from another crate's matrix type copies element
+/// (row, col) to element (row, col), whatever that type's storage order.
+/// nalgebra, glam and mint matrices built for column vectors (with their
+/// translation in the last column) therefore need transposing to describe
+/// the same transform to OSL.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix44 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Default for Matrix44 {
+    fn default() -> Matrix44 {
+        Matrix44::identity()
+    }
+}
+
+impl Matrix44 {
+    pub fn new(m: [[f32; 4]; 4]) -> Matrix44 {
+        Matrix44 { m }
+    }
+
+    pub fn identity() -> Matrix44 {
+        Matrix44::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// A translation by t, in the last row.
+    pub fn translation(t: Vec3) -> Matrix44 {
+        let mut m = Matrix44::identity();
+        m.m[3] = [t.x, t.y, t.z, 1.0];
+        m
+    }
+
+    pub fn transpose(&self) -> Matrix44 {
+        let mut t = Matrix44::identity();
+        for (i, row) in self.m.iter().enumerate() {
+            for (j, v) in row.iter().enumerate() {
+                t.m[j][i] = *v;
+            }
+        }
+        t
+    }
+
+    /// Transform a point, as OSL's `transform(M, point)` does: as a row
+    /// vector with w = 1, followed by the divide by w.
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m;
+        let a = p.x * m[0][0] + p.y * m[1][0] + p.z * m[2][0] + m[3][0];
+        let b = p.x * m[0][1] + p.y * m[1][1] + p.z * m[2][1] + m[3][1];
+        let c = p.x * m[0][2] + p.y * m[1][2] + p.z * m[2][2] + m[3][2];
+        let w = p.x * m[0][3] + p.y * m[1][3] + p.z * m[2][3] + m[3][3];
+        Vec3::new(a / w, b / w, c / w)
+    }
+
+    /// Transform a vector, as OSL's `transform(M, vector)` does, ignoring
+    /// translation.
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            v.x * m[0][0] + v.y * m[1][0] + v.z * m[2][0],
+            v.x * m[0][1] + v.y * m[1][1] + v.z * m[2][1],
+            v.x * m[0][2] + v.y * m[1][2] + v.z * m[2][2],
+        )
+    }
+}
+
+// Passed to the C API as the structs it mirrors, which the shim checks
+// against Imath's
+#[cfg(feature = "oslexec")]
+osl_sys::assert_layout_eq!(Vec3, osl_sys::Vec3);
+#[cfg(feature = "oslexec")]
+osl_sys::assert_layout_eq!(Color3, osl_sys::Color3);
+#[cfg(feature = "oslexec")]
+osl_sys::assert_layout_eq!(Matrix44, osl_sys::Matrix44);
+
+impl From<V3f32> for Vec3 {
+    fn from(v: V3f32) -> Vec3 {
+        Vec3::new(v.x, v.y, v.z)
+    }
+}
+
+impl From<Vec3> for V3f32 {
+    fn from(v: Vec3) -> V3f32 {
+        v3f32(v.x, v.y, v.z)
+    }
+}
+
+impl From<V3f32> for Color3 {
+    fn from(v: V3f32) -> Color3 {
+        Color3::new(v.x, v.y, v.z)
+    }
+}
+
+impl From<Color3> for V3f32 {
+    fn from(c: Color3) -> V3f32 {
+        v3f32(c.r, c.g, c.b)
+    }
+}
+
+impl From<M4f32> for Matrix44 {
+    fn from(m: M4f32) -> Matrix44 {
+        let mut r = Matrix44::identity();
+        for (i, row) in r.m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = m[(i, j)];
+            }
+        }
+        r
+    }
+}
+
+impl From<Matrix44> for M4f32 {
+    fn from(m: Matrix44) -> M4f32 {
+        M4f32::from_fn(|i, j| m.m[i][j])
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<glam::Vec3> for Vec3 {
+    fn from(v: glam::Vec3) -> Vec3 {
+        Vec3::new(v.x, v.y, v.z)
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Vec3> for glam::Vec3 {
+    fn from(v: Vec3) -> glam::Vec3 {
+        glam::Vec3::new(v.x, v.y, v.z)
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<glam::Vec3> for Color3 {
+    fn from(v: glam::Vec3) -> Color3 {
+        Color3::new(v.x, v.y, v.z)
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Color3> for glam::Vec3 {
+    fn from(c: Color3) -> glam::Vec3 {
+        glam::Vec3::new(c.r, c.g, c.b)
+    }
+}
+
+// glam's arrays are of columns, so transpose to keep (row, col) in place
+#[cfg(feature = "glam")]
+impl From<glam::Mat4> for Matrix44 {
+    fn from(m: glam::Mat4) -> Matrix44 {
+        Matrix44::new(m.transpose().to_cols_array_2d())
+    }
+}
+
+#[cfg(feature = "glam")]
+impl From<Matrix44> for glam::Mat4 {
+    fn from(m: Matrix44) -> glam::Mat4 {
+        glam::Mat4::from_cols_array_2d(&m.m).transpose()
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<mint::Vector3<f32>> for Vec3 {
+    fn from(v: mint::Vector3<f32>) -> Vec3 {
+        Vec3::new(v.x, v.y, v.z)
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<Vec3> for mint::Vector3<f32> {
+    fn from(v: Vec3) -> mint::Vector3<f32> {
+        mint::Vector3 {
+            x: v.x,
+            y: v.y,
+            z: v.z,
+        }
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<mint::Point3<f32>> for Vec3 {
+    fn from(p: mint::Point3<f32>) -> Vec3 {
+        Vec3::new(p.x, p.y, p.z)
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<Vec3> for mint::Point3<f32> {
+    fn from(v: Vec3) -> mint::Point3<f32> {
+        mint::Point3 {
+            x: v.x,
+            y: v.y,
+            z: v.z,
+        }
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<mint::Vector3<f32>> for Color3 {
+    fn from(v: mint::Vector3<f32>) -> Color3 {
+        Color3::new(v.x, v.y, v.z)
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<Color3> for mint::Vector3<f32> {
+    fn from(c: Color3) -> mint::Vector3<f32> {
+        mint::Vector3 {
+            x: c.r,
+            y: c.g,
+            z: c.b,
+        }
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<mint::RowMatrix4<f32>> for Matrix44 {
+    fn from(m: mint::RowMatrix4<f32>) -> Matrix44 {
+        Matrix44::new(m.into())
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<Matrix44> for mint::RowMatrix4<f32> {
+    fn from(m: Matrix44) -> mint::RowMatrix4<f32> {
+        m.m.into()
+    }
+}
+
+// mint's column matrices are arrays of columns, so transpose to keep
+// (row, col) in place
+#[cfg(feature = "mint")]
+impl From<mint::ColumnMatrix4<f32>> for Matrix44 {
+    fn from(m: mint::ColumnMatrix4<f32>) -> Matrix44 {
+        let cols: [[f32; 4]; 4] = m.into();
+        Matrix44::new(cols).transpose()
+    }
+}
+
+#[cfg(feature = "mint")]
+impl From<Matrix44> for mint::ColumnMatrix4<f32> {
+    fn from(m: Matrix44) -> mint::ColumnMatrix4<f32> {
+        m.transpose().m.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dual2_chain_rule() {
+        // f(x) = x * sin(x) at x = 2, differentiating with respect to x in
+        // screen x and twice x in screen y
+        let x = Dual2::new(2.0f64, 1.0, 2.0);
+        let f = x * x.sin();
+        let df = 2.0f64.sin() + 2.0 * 2.0f64.cos();
+        assert!((f.dx - df).abs() < 1e-12);
+        assert!((f.dy - 2.0 * df).abs() < 1e-12);
+
+        let q = Dual2::new(1.0f64, 1.0, 0.0) / Dual2::new(2.0, 0.0, 1.0);
+        assert_eq!(q, Dual2::new(0.5, 0.5, -0.25));
+    }
+
+    #[test]
+    fn dual2_vector() {
+        let p = Dual2::new(
+            v3f32(3.0, 4.0, 0.0),
+            v3f32(1.0, 0.0, 0.0),
+            v3f32(0.0, 0.0, 1.0),
+        );
+        let len = p.length();
+        assert_eq!(len.val, 5.0);
+        assert!((len.dx - 0.6).abs() < 1e-6);
+        assert_eq!(len.dy, 0.0);
+
+        let n = p.normalize();
+        assert!((n.val - v3f32(0.6, 0.8, 0.0)).norm() < 1e-6);
+        // A unit vector's length doesn't change
+        assert!(n.dot(&n).dx.abs() < 1e-6);
+        assert_eq!(p.y(), Dual2::new(4.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn matrix44_conversions() {
+        let m = Matrix44::new([
+            [1.0, 2.0, 3.0, 4.0],
+            [5.0, 6.0, 7.0, 8.0],
+            [9.0, 10.0, 11.0, 12.0],
+            [13.0, 14.0, 15.0, 16.0],
+        ]);
+        let n = M4f32::from(m);
+        assert_eq!(n[(0, 1)], 2.0);
+        assert_eq!(n[(3, 0)], 13.0);
+        assert_eq!(Matrix44::from(n), m);
+
+        // A column-vector translation is the transpose of Imath's
+        let t = Matrix44::from(m4f32_translation(1.0, 2.0, 3.0).transpose());
+        assert_eq!(t, Matrix44::translation(Vec3::new(1.0, 2.0, 3.0)));
+        assert_eq!(
+            t.transform_point(Vec3::new(1.0, 1.0, 1.0)),
+            Vec3::new(2.0, 3.0, 4.0)
+        );
+        assert_eq!(
+            t.transform_vector(Vec3::new(1.0, 1.0, 1.0)),
+            Vec3::new(1.0, 1.0, 1.0)
+        );
+    }
+}
@@ -0,0 +1,545 @@
+//! OSL's noise functions, for evaluating on the CPU exactly what a shader
+//! would get from `noise()`, `pnoise()`, `cellnoise()` and friends, e.g. to
+//! displace geometry before shading. These call the same implementation
+//! (OSL's oslnoise.h) that shaders are compiled against, so for the same
+//! inputs the results are the same bits.
+//!
+//! The domain can be 1 to 4 dimensional, as in the shading language, where
+//! the 4D variant is a point and a time: here a V4f32 whose w is the time.
+//! Passing a Dual2 domain also computes derivatives of the result.
+//!
+//! Gabor noise is implemented inside liboslexec rather than in OSL's public
+//! headers, so it can't be called directly. Instead, a Gabor evaluates it by
+//! running a small shader that calls `noise("gabor", ...)`, which is much
+//! slower per point but gives the same bits as any other shader would.
+use crate::compiler::Compiler;
+use crate::ffi;
+use crate::math::*;
+use crate::renderer_services::RendererServices;
+use crate::shader_globals::ShaderGlobals;
+use crate::shading_system::{ShaderGroupRef, ShadingSystem};
+use crate::{Error, Ustring};
+
+use std::sync::Arc;
+
+/// The kind of noise to evaluate, as named in the shading language.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NoiseType {
+    /// "uperlin" or "noise": unsigned Perlin noise, in [0, 1]
+    UPerlin,
+    /// "perlin" or "snoise": signed Perlin noise, in [-1, 1]
+    Perlin,
+    /// "cell": a constant value in [0, 1] for each unit cell
+    Cell,
+    /// "hash": a value in [0, 1] that is different for every input
+    Hash,
+}
+
+impl NoiseType {
+    /// Look up a noise type by the name a shader would pass to `noise()`.
+    /// Returns None for other names, including "gabor", which is evaluated
+    /// with a Gabor instead.
+    pub fn from_name(name: &str) -> Option<NoiseType> {
+        match name {
+            "uperlin" | "noise" => Some(NoiseType::UPerlin),
+            "perlin" | "snoise" => Some(NoiseType::Perlin),
+            "cell" => Some(NoiseType::Cell),
+            "hash" => Some(NoiseType::Hash),
+            _ => None,
+        }
+    }
+
+    fn to_ffi(self) -> i32 {
+        (match self {
+            NoiseType::UPerlin => ffi::OSL_NOISE_UPERLIN,
+            NoiseType::Perlin => ffi::OSL_NOISE_PERLIN,
+            NoiseType::Cell => ffi::OSL_NOISE_CELL,
+            NoiseType::Hash => ffi::OSL_NOISE_HASH,
+        }) as i32
+    }
+}
+
+/// A point that noise can be evaluated at: f32, V2f32, V3f32 or V4f32, or a
+/// Dual2 of one of those to get derivatives as well.
+pub trait NoiseDomain: Copy {
+    /// Result of float noise: f32, or Dual2<f32> for a Dual2 domain.
+    type Float: NoiseResult;
+    /// Result of vector noise: V3f32, or Dual2<V3f32> for a Dual2 domain.
+    type Vector: NoiseResult;
+    /// Period for periodic noise, which has no derivatives.
+    type Period: Copy;
+
+    #[doc(hidden)]
+    const DIMS: usize;
+    #[doc(hidden)]
+    const DUAL: bool;
+
+    /// Write the point as DIMS floats, followed by their x and y
+    /// derivatives if DUAL.
+    #[doc(hidden)]
+    fn write(&self, out: &mut [f32]);
+    #[doc(hidden)]
+    fn write_period(period: &Self::Period, out: &mut [f32]);
+}
+
+/// A value that noise can return. See NoiseDomain.
+pub trait NoiseResult: Sized {
+    #[doc(hidden)]
+    fn read(values: &[f32]) -> Self;
+}
+
+impl NoiseResult for f32 {
+    fn read(values: &[f32]) -> f32 {
+        values[0]
+    }
+}
+
+impl NoiseResult for V3f32 {
+    fn read(values: &[f32]) -> V3f32 {
+        v3f32(values[0], values[1], values[2])
+    }
+}
+
+impl NoiseResult for Dual2<f32> {
+    fn read(values: &[f32]) -> Dual2<f32> {
+        Dual2::new(values[0], values[1], values[2])
+    }
+}
+
+impl NoiseResult for Dual2<V3f32> {
+    fn read(values: &[f32]) -> Dual2<V3f32> {
+        Dual2::new(
+            V3f32::read(&values[0..3]),
+            V3f32::read(&values[3..6]),
+            V3f32::read(&values[6..9]),
+        )
+    }
+}
+
+macro_rules! impl_noise_domain {
+    ($t:ty, $dims:expr) => {
+        impl NoiseDomain for $t {
+            type Float = f32;
+            type Vector = V3f32;
+            type Period = $t;
+
+            const DIMS: usize = $dims;
+            const DUAL: bool = false;
+
+            fn write(&self, out: &mut [f32]) {
+                Self::write_period(self, out);
+            }
+
+            fn write_period(period: &$t, out: &mut [f32]) {
+                out[..$dims].copy_from_slice(period.as_slice());
+            }
+        }
+
+        impl NoiseDomain for Dual2<$t> {
+            type Float = Dual2<f32>;
+            type Vector = Dual2<V3f32>;
+            type Period = $t;
+
+            const DIMS: usize = $dims;
+            const DUAL: bool = true;
+
+            fn write(&self, out: &mut [f32]) {
+                self.val.write(&mut out[0..$dims]);
+                self.dx.write(&mut out[$dims..2 * $dims]);
+                self.dy.write(&mut out[2 * $dims..3 * $dims]);
+            }
+
+            fn write_period(period: &$t, out: &mut [f32]) {
+                period.write(out);
+            }
+        }
+    };
+}
+
+impl_noise_domain!(V2f32, 2);
+impl_noise_domain!(V3f32, 3);
+impl_noise_domain!(V4f32, 4);
+
+impl NoiseDomain for f32 {
+    type Float = f32;
+    type Vector = V3f32;
+    type Period = f32;
+
+    const DIMS: usize = 1;
+    const DUAL: bool = false;
+
+    fn write(&self, out: &mut [f32]) {
+        out[0] = *self;
+    }
+
+    fn write_period(period: &f32, out: &mut [f32]) {
+        out[0] = *period;
+    }
+}
+
+impl NoiseDomain for Dual2<f32> {
+    type Float = Dual2<f32>;
+    type Vector = Dual2<V3f32>;
+    type Period = f32;
+
+    const DIMS: usize = 1;
+    const DUAL: bool = true;
+
+    fn write(&self, out: &mut [f32]) {
+        out[0] = self.val;
+        out[1] = self.dx;
+        out[2] = self.dy;
+    }
+
+    fn write_period(period: &f32, out: &mut [f32]) {
+        out[0] = *period;
+    }
+}
+
+fn eval<P: NoiseDomain, R: NoiseResult>(
+    kind: NoiseType,
+    p: P,
+    period: Option<P::Period>,
+    vresult: bool,
+) -> R {
+    let mut point = [0.0f32; 12];
+    let mut periods = [0.0f32; 4];
+    let mut result = [0.0f32; 9];
+    p.write(&mut point);
+    let period_ptr = match period {
+        Some(period) => {
+            P::write_period(&period, &mut periods);
+            periods.as_ptr()
+        }
+        None => std::ptr::null(),
+    };
+
+    unsafe {
+        if P::DUAL {
+            ffi::Noise_eval_dual(
+                kind.to_ffi(),
+                P::DIMS as i32,
+                point.as_ptr(),
+                period_ptr,
+                vresult,
+                result.as_mut_ptr(),
+            );
+        } else {
+            ffi::Noise_eval(
+                kind.to_ffi(),
+                P::DIMS as i32,
+                point.as_ptr(),
+                period_ptr,
+                vresult,
+                result.as_mut_ptr(),
+            );
+        }
+    }
+    R::read(&result)
+}
+
+/// Float noise of the given type at `p`, like `noise(type, p)` in a shader.
+pub fn noise<P: NoiseDomain>(kind: NoiseType, p: P) -> P::Float {
+    eval(kind, p, None, false)
+}
+
+/// Vector noise of the given type at `p`, like `vector noise(type, p)` in a
+/// shader.
+pub fn vnoise<P: NoiseDomain>(kind: NoiseType, p: P) -> P::Vector {
+    eval(kind, p, None, true)
+}
+
+/// Periodic float noise, like `pnoise(type, p, period)` in a shader. Each
+/// component of the period is rounded to an integer by OSL, and a period
+/// of 0 means not periodic in that dimension.
+pub fn pnoise<P: NoiseDomain>(kind: NoiseType, p: P, period: P::Period) -> P::Float {
+    eval(kind, p, Some(period), false)
+}
+
+/// Periodic vector noise, like `vector pnoise(type, p, period)` in a shader.
+pub fn vpnoise<P: NoiseDomain>(kind: NoiseType, p: P, period: P::Period) -> P::Vector {
+    eval(kind, p, Some(period), true)
+}
+
+/// Signed Perlin noise, like `snoise(p)` in a shader.
+pub fn snoise<P: NoiseDomain>(p: P) -> P::Float {
+    noise(NoiseType::Perlin, p)
+}
+
+/// Cell noise, like `cellnoise(p)` in a shader.
+pub fn cellnoise<P: NoiseDomain>(p: P) -> P::Float {
+    noise(NoiseType::Cell, p)
+}
+
+/// Hash noise, like `hashnoise(p)` in a shader.
+pub fn hashnoise<P: NoiseDomain>(p: P) -> P::Float {
+    noise(NoiseType::Hash, p)
+}
+
+/// How the orientation of Gabor noise's kernels is chosen, as the
+/// "anisotropic" parameter of `noise("gabor", ...)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GaborAnisotropy {
+    /// Random orientations.
+    Isotropic = 0,
+    /// Every kernel is oriented along `direction`.
+    Anisotropic = 1,
+    /// Random orientations in the plane perpendicular to `direction`.
+    Hybrid = 2,
+}
+
+/// The optional parameters of `noise("gabor", ...)`. The default values
+/// are the shading language's.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GaborOptions {
+    pub anisotropic: GaborAnisotropy,
+    pub direction: V3f32,
+    pub bandwidth: f32,
+    pub impulses: f32,
+    /// Filter the noise over the footprint given by the derivatives of the
+    /// domain, which are zero unless it's a Dual2.
+    pub do_filter: bool,
+}
+
+impl Default for GaborOptions {
+    fn default() -> GaborOptions {
+        GaborOptions {
+            anisotropic: GaborAnisotropy::Isotropic,
+            direction: v3f32(1.0, 0.0, 0.0),
+            bandwidth: 1.0,
+            impulses: 16.0,
+            do_filter: true,
+        }
+    }
+}
+
+/// A domain that Gabor noise can be evaluated at: f32, V2f32 or V3f32, or a
+/// Dual2 of one of those. The shading language has no 4D Gabor noise.
+pub trait GaborDomain: NoiseDomain {}
+
+impl GaborDomain for f32 {}
+impl GaborDomain for Dual2<f32> {}
+impl GaborDomain for V2f32 {}
+impl GaborDomain for Dual2<V2f32> {}
+impl GaborDomain for V3f32 {}
+impl GaborDomain for Dual2<V3f32> {}
+
+/// Renderer services that leave everything to OSL's defaults, which is all
+/// the Gabor shader needs.
+struct DefaultRenderer(ffi::RendererServicesWrapper);
+
+// The wrapper has no callbacks and no state of its own.
+unsafe impl Send for DefaultRenderer {}
+unsafe impl Sync for DefaultRenderer {}
+
+impl RendererServices for DefaultRenderer {
+    fn get_wrapper(&self) -> ffi::RendererServicesWrapper {
+        self.0
+    }
+}
+
+impl Drop for DefaultRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::RendererServicesWrapper_destroy(self.0);
+        }
+    }
+}
+
+/// Evaluates Gabor noise, like `noise("gabor", p, ...)` in a shader, for one
+/// set of GaborOptions.
+///
+/// Creating one compiles a shader and sets up a ShadingSystem to run it, so
+/// create it once and share it: it may be used from many threads at once.
+/// Each evaluation executes the shader once.
+///
+/// ```ignore
+/// let gabor = Gabor::new(&GaborOptions::default())?;
+/// let n = gabor.noise(v3f32(0.3, 1.7, -2.2))?;
+/// ```
+pub struct Gabor {
+    // Dropped before the shading system it belongs to
+    group: ShaderGroupRef,
+    ss: ShadingSystem,
+    renderer: Arc<DefaultRenderer>,
+}
+
+const GABOR_OUTPUTS: [&str; 6] = ["F", "Fdx", "Fdy", "V", "Vdx", "Vdy"];
+
+impl Gabor {
+    pub fn new(options: &GaborOptions) -> Result<Gabor, Error> {
+        let renderer = Arc::new(DefaultRenderer(unsafe {
+            ffi::RendererServicesWrapper_create()
+        }));
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        ss.attribute("renderer_outputs", &GABOR_OUTPUTS[..])?;
+
+        let compiled = Compiler::new().compile_buffer(&gabor_source(options))?;
+        ss.load_memory_compiled_shader("gabor_eval", &compiled.oso)?;
+        let group = ss.shader_group_begin("gabor_eval")?;
+        ss.shader(&group, "surface", "gabor_eval", "")?;
+        ss.shader_group_end(&group)?;
+
+        Ok(Gabor {
+            group,
+            ss,
+            renderer,
+        })
+    }
+
+    /// Float Gabor noise at `p`, like `noise("gabor", p)` in a shader.
+    pub fn noise<P: GaborDomain>(&self, p: P) -> Result<P::Float, Error> {
+        self.eval(p, None, false)
+    }
+
+    /// Vector Gabor noise at `p`, like `vector noise("gabor", p)` in a
+    /// shader.
+    pub fn vnoise<P: GaborDomain>(&self, p: P) -> Result<P::Vector, Error> {
+        self.eval(p, None, true)
+    }
+
+    /// Periodic float Gabor noise, like `pnoise("gabor", p, period)` in a
+    /// shader.
+    pub fn pnoise<P: GaborDomain>(&self, p: P, period: P::Period) -> Result<P::Float, Error> {
+        self.eval(p, Some(period), false)
+    }
+
+    /// Periodic vector Gabor noise, like `vector pnoise("gabor", p, period)`
+    /// in a shader.
+    pub fn vpnoise<P: GaborDomain>(&self, p: P, period: P::Period) -> Result<P::Vector, Error> {
+        self.eval(p, Some(period), true)
+    }
+
+    fn eval<P: GaborDomain, R: NoiseResult>(
+        &self,
+        p: P,
+        period: Option<P::Period>,
+        vresult: bool,
+    ) -> Result<R, Error> {
+        let dims = P::DIMS;
+        let mut point = [0.0f32; 9];
+        p.write(&mut point);
+        let mut periods = [0.0f32; 3];
+        if let Some(period) = &period {
+            P::write_period(period, &mut periods);
+        }
+
+        let rsw = self.renderer.get_wrapper();
+        self.ss.with_context(|ctx| {
+            let mut sg = ShaderGlobals::new(ctx, rsw);
+            // The shader reads which variant to run from u, v and time
+            sg.u = dims as f32;
+            sg.v = if vresult { 1.0 } else { 0.0 };
+            sg.time = if period.is_some() { 1.0 } else { 0.0 };
+            sg.N = v3f32(periods[0], periods[1], periods[2]);
+            let mut components = [[0.0f32; 3]; 3];
+            for (i, c) in components.iter_mut().enumerate() {
+                if i == 0 || P::DUAL {
+                    c[..dims].copy_from_slice(&point[i * dims..(i + 1) * dims]);
+                }
+            }
+            sg.P = v3f32(components[0][0], components[0][1], components[0][2]);
+            sg.dPdx = v3f32(components[1][0], components[1][1], components[1][2]);
+            sg.dPdy = v3f32(components[2][0], components[2][1], components[2][2]);
+            self.ss.execute(ctx, &self.group, &mut sg, true)?;
+
+            let names = if vresult {
+                &GABOR_OUTPUTS[3..]
+            } else {
+                &GABOR_OUTPUTS[..3]
+            };
+            let mut result = [0.0f32; 9];
+            for (i, name) in names.iter().enumerate() {
+                let symbol = self.ss.find_symbol(&self.group, Ustring::new(name))?;
+                // ctx has just run the group
+                if vresult {
+                    let value = unsafe { self.ss.symbol_value::<V3f32>(ctx, &symbol)? };
+                    result[i * 3..(i + 1) * 3].copy_from_slice(value.as_slice());
+                } else {
+                    result[i] = unsafe { self.ss.symbol_value::<f32>(ctx, &symbol)? };
+                }
+            }
+            Ok(R::read(&result))
+        })?
+    }
+}
+
+/// The source of a shader that evaluates every variant of Gabor noise with
+/// the given options, choosing one at runtime from the shader globals: u is
+/// the number of dimensions of the domain, which is in P, v is 1 for vector
+/// noise, and time is 1 for periodic noise with the period in N.
+fn gabor_source(options: &GaborOptions) -> String {
+    let d = options.direction;
+    let args = format!(
+        "\"anisotropic\", {}, \"direction\", vector({:?}, {:?}, {:?}), \
+         \"bandwidth\", {:?}, \"impulses\", {:?}, \"do_filter\", {}",
+        options.anisotropic as i32,
+        d.x,
+        d.y,
+        d.z,
+        options.bandwidth,
+        options.impulses,
+        options.do_filter as i32
+    );
+
+    let domains = [("P[0]", "N[0]"), ("P[0], P[1]", "N[0], N[1]"), ("P", "N")];
+    let mut body = String::new();
+    for (i, (domain, period)) in domains.iter().enumerate() {
+        for (result, vresult) in &[("F", 0), ("V", 1)] {
+            body += &format!(
+                "if (dims == {0} && vresult == {1}) {{\n\
+                 if (periodic) {2} = pnoise(\"gabor\", {3}, {4}, {5});\n\
+                 else {2} = noise(\"gabor\", {3}, {5});\n\
+                 }}\n",
+                i + 1,
+                vresult,
+                result,
+                domain,
+                period,
+                args
+            );
+        }
+    }
+
+    format!(
+        "shader gabor_eval(output float F = 0, output float Fdx = 0, output float Fdy = 0,\n\
+         output vector V = 0, output vector Vdx = 0, output vector Vdy = 0)\n\
+         {{\n\
+         int dims = (int) u;\n\
+         int vresult = v != 0;\n\
+         int periodic = time != 0;\n\
+         {}\
+         Fdx = Dx(F);\n\
+         Fdy = Dy(F);\n\
+         Vdx = Dx(V);\n\
+         Vdy = Dy(V);\n\
+         }}\n",
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_ranges() {
+        let p = v3f32(0.3, 1.7, -2.2);
+        let u = noise(NoiseType::UPerlin, p);
+        assert!((0.0..=1.0).contains(&u));
+        assert!((-1.0..=1.0).contains(&snoise(p)));
+
+        // Cell noise is constant within a cell, and so has no derivatives
+        assert_eq!(cellnoise(0.2f32), cellnoise(0.7f32));
+        let d = cellnoise(Dual2::new(p, v3f32(1.0, 0.0, 0.0), v3f32(0.0, 1.0, 0.0)));
+        assert_eq!(d.val, cellnoise(p));
+        assert_eq!((d.dx, d.dy), (0.0, 0.0));
+
+        // Periodic noise repeats
+        assert_eq!(
+            pnoise(NoiseType::Cell, 0.5f32, 4.0),
+            pnoise(NoiseType::Cell, 4.5f32, 4.0)
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! Typed shading system options, as an alternative to setting attributes by
+//! name. With the "serde" feature the options can be read from config files,
+//! and any option left out takes its default.
+//!
+//! ```ignore
+//! let mut options = ShadingSystemOptions::default();
+//! options.searchpath_shader = "shaders".into();
+//! options.raytypes = vec!["camera".into(), "shadow".into()];
+//! options.optimization.level = 1;
+//! options.apply(&mut ss)?;
+//! ```
+use crate::shading_system::ShadingSystem;
+use crate::shading_system_attribute::ShadingSystemAttribute;
+use crate::Error;
+
+/// The options set with ShadingSystem::attribute. The defaults are OSL's
+/// own, see ShadingSystem::attribute for a description of each.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct ShadingSystemOptions {
+    /// "searchpath:shader"
+    pub searchpath_shader: String,
+    pub colorspace: String,
+    pub commonspace: String,
+    /// Not set if empty, see apply.
+    pub raytypes: Vec<String>,
+    /// Not set if empty, see apply.
+    pub renderer_outputs: Vec<String>,
+    /// "statistics:level"
+    pub statistics_level: i32,
+    pub range_checking: bool,
+    pub unknown_coordsys_error: bool,
+    pub connection_error: bool,
+    pub strict_messages: bool,
+    pub error_repeats: bool,
+    pub compile_report: bool,
+    pub max_warnings_per_thread: i32,
+    pub buffer_printf: bool,
+    pub profile: bool,
+    pub no_noise: bool,
+    pub no_pointcloud: bool,
+    pub exec_repeat: i32,
+    pub opt_warnings: bool,
+    pub gpu_opt_error: bool,
+    pub lockgeom: bool,
+    pub userdata_isconnected: bool,
+    pub greedyjit: bool,
+    pub countlayerexecs: bool,
+    pub allow_shader_replacement: bool,
+    pub optimization: OptimizationOptions,
+    pub debug: DebugOptions,
+    pub lazy: LazyOptions,
+}
+
+/// Runtime optimization options. Each of the `bool`s turns a class of
+/// optimization (the attribute named with an "opt_" prefix) on or off.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct OptimizationOptions {
+    /// "optimize"
+    pub level: i32,
+    pub simplify_param: bool,
+    pub constant_fold: bool,
+    pub stale_assign: bool,
+    pub elide_useless_ops: bool,
+    pub elide_unconnected_outputs: bool,
+    pub peephole: bool,
+    pub coalesce_temps: bool,
+    pub assign: bool,
+    pub mix: bool,
+    pub merge_instances: bool,
+    pub merge_instances_with_userdata: bool,
+    pub fold_getattribute: bool,
+    pub middleman: bool,
+    pub texture_handle: bool,
+    pub seed_bblock_aliases: bool,
+    /// "opt_passes"
+    pub passes: i32,
+    pub llvm_optimize: i32,
+    /// "max_local_mem_KB"
+    pub max_local_mem_kb: i32,
+    pub force_derivs: bool,
+    /// "opt_layername"
+    pub layername: String,
+    pub only_groupname: String,
+    pub optimize_nondebug: bool,
+}
+
+/// Options for debugging shaders, and liboslexec itself.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DebugOptions {
+    /// "debug"
+    pub level: i32,
+    pub debug_nan: bool,
+    pub debug_uninit: bool,
+    pub clearmemory: bool,
+    pub llvm_debug: i32,
+    pub llvm_debug_layers: bool,
+    pub llvm_debug_ops: bool,
+    pub llvm_output_bitcode: bool,
+    pub debug_groupname: String,
+    pub debug_layername: String,
+    pub archive_groupname: String,
+    pub archive_filename: String,
+}
+
+/// Options controlling when layers and userdata are evaluated.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct LazyOptions {
+    pub lazylayers: bool,
+    pub lazyglobals: bool,
+    pub lazyunconnected: bool,
+    pub lazy_userdata: bool,
+}
+
+impl Default for ShadingSystemOptions {
+    fn default() -> ShadingSystemOptions {
+        ShadingSystemOptions {
+            searchpath_shader: String::new(),
+            colorspace: "Rec709".into(),
+            commonspace: "world".into(),
+            raytypes: Vec::new(),
+            renderer_outputs: Vec::new(),
+            statistics_level: 0,
+            range_checking: true,
+            unknown_coordsys_error: true,
+            connection_error: true,
+            strict_messages: true,
+            error_repeats: false,
+            compile_report: false,
+            max_warnings_per_thread: 100,
+            buffer_printf: true,
+            profile: false,
+            no_noise: false,
+            no_pointcloud: false,
+            exec_repeat: 1,
+            opt_warnings: false,
+            gpu_opt_error: false,
+            lockgeom: true,
+            userdata_isconnected: false,
+            greedyjit: false,
+            countlayerexecs: false,
+            allow_shader_replacement: false,
+            optimization: OptimizationOptions::default(),
+            debug: DebugOptions::default(),
+            lazy: LazyOptions::default(),
+        }
+    }
+}
+
+impl Default for OptimizationOptions {
+    fn default() -> OptimizationOptions {
+        OptimizationOptions {
+            level: 2,
+            simplify_param: true,
+            constant_fold: true,
+            stale_assign: true,
+            elide_useless_ops: true,
+            elide_unconnected_outputs: true,
+            peephole: true,
+            coalesce_temps: true,
+            assign: true,
+            mix: true,
+            merge_instances: true,
+            merge_instances_with_userdata: true,
+            fold_getattribute: true,
+            middleman: true,
+            texture_handle: true,
+            seed_bblock_aliases: true,
+            passes: 10,
+            llvm_optimize: 0,
+            max_local_mem_kb: 2048,
+            force_derivs: false,
+            layername: String::new(),
+            only_groupname: String::new(),
+            optimize_nondebug: false,
+        }
+    }
+}
+
+impl Default for DebugOptions {
+    fn default() -> DebugOptions {
+        DebugOptions {
+            level: 0,
+            debug_nan: false,
+            debug_uninit: false,
+            clearmemory: false,
+            llvm_debug: 0,
+            llvm_debug_layers: false,
+            llvm_debug_ops: false,
+            llvm_output_bitcode: false,
+            debug_groupname: String::new(),
+            debug_layername: String::new(),
+            archive_groupname: String::new(),
+            archive_filename: String::new(),
+        }
+    }
+}
+
+impl Default for LazyOptions {
+    fn default() -> LazyOptions {
+        LazyOptions {
+            lazylayers: true,
+            lazyglobals: true,
+            lazyunconnected: true,
+            lazy_userdata: false,
+        }
+    }
+}
+
+impl ShadingSystemOptions {
+    /// Set every option as an attribute on the shading system, so that
+    /// applying options undoes any earlier change to the attributes they
+    /// cover. The exception is empty lists, which OSL can't set, so empty
+    /// `raytypes` or `renderer_outputs` leave the attribute as it was.
+    /// Options that a version of OSL doesn't have are only reported as
+    /// failing if they differ from the default. All options are attempted
+    /// even if some fail, and if any do, the error names them all.
+    pub fn apply(&self, ss: &mut ShadingSystem) -> Result<(), Error> {
+        let defaults = ShadingSystemOptions::default();
+        let mut failed = Vec::new();
+        for ((name, value), (_, default)) in
+            self.attributes().into_iter().zip(defaults.attributes())
+        {
+            let result = match value {
+                OptionValue::Int(value) => ss.attribute(name, value),
+                OptionValue::String(value) => ss.attribute(name, value),
+                OptionValue::Strings(value) if value.is_empty() => continue,
+                OptionValue::Strings(value) => ss.attribute(name, value),
+            };
+            if result.is_err() && value != default {
+                failed.push(name.to_string());
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::SetAttributesFailed(failed))
+        }
+    }
+
+    /// Every option, with the name of the attribute it sets.
+    fn attributes(&self) -> Vec<(&'static str, OptionValue<'_>)> {
+        use OptionValue::{Int, String, Strings};
+        let flag = |value: bool| Int(value as i32);
+        let opt = &self.optimization;
+        let debug = &self.debug;
+        let lazy = &self.lazy;
+
+        vec![
+            ("searchpath:shader", String(&self.searchpath_shader)),
+            ("colorspace", String(&self.colorspace)),
+            ("commonspace", String(&self.commonspace)),
+            ("raytypes", Strings(&self.raytypes)),
+            ("renderer_outputs", Strings(&self.renderer_outputs)),
+            ("statistics:level", Int(self.statistics_level)),
+            ("range_checking", flag(self.range_checking)),
+            ("unknown_coordsys_error", flag(self.unknown_coordsys_error)),
+            ("connection_error", flag(self.connection_error)),
+            ("strict_messages", flag(self.strict_messages)),
+            ("error_repeats", flag(self.error_repeats)),
+            ("compile_report", flag(self.compile_report)),
+            ("max_warnings_per_thread", Int(self.max_warnings_per_thread)),
+            ("buffer_printf", flag(self.buffer_printf)),
+            ("profile", flag(self.profile)),
+            ("no_noise", flag(self.no_noise)),
+            ("no_pointcloud", flag(self.no_pointcloud)),
+            ("exec_repeat", Int(self.exec_repeat)),
+            ("opt_warnings", flag(self.opt_warnings)),
+            ("gpu_opt_error", flag(self.gpu_opt_error)),
+            ("lockgeom", flag(self.lockgeom)),
+            ("userdata_isconnected", flag(self.userdata_isconnected)),
+            ("greedyjit", flag(self.greedyjit)),
+            ("countlayerexecs", flag(self.countlayerexecs)),
+            (
+                "allow_shader_replacement",
+                flag(self.allow_shader_replacement),
+            ),
+            ("optimize", Int(opt.level)),
+            ("opt_simplify_param", flag(opt.simplify_param)),
+            ("opt_constant_fold", flag(opt.constant_fold)),
+            ("opt_stale_assign", flag(opt.stale_assign)),
+            ("opt_elide_useless_ops", flag(opt.elide_useless_ops)),
+            (
+                "opt_elide_unconnected_outputs",
+                flag(opt.elide_unconnected_outputs),
+            ),
+            ("opt_peephole", flag(opt.peephole)),
+            ("opt_coalesce_temps", flag(opt.coalesce_temps)),
+            ("opt_assign", flag(opt.assign)),
+            ("opt_mix", flag(opt.mix)),
+            ("opt_merge_instances", flag(opt.merge_instances)),
+            (
+                "opt_merge_instances_with_userdata",
+                flag(opt.merge_instances_with_userdata),
+            ),
+            ("opt_fold_getattribute", flag(opt.fold_getattribute)),
+            ("opt_middleman", flag(opt.middleman)),
+            ("opt_texture_handle", flag(opt.texture_handle)),
+            ("opt_seed_bblock_aliases", flag(opt.seed_bblock_aliases)),
+            ("opt_passes", Int(opt.passes)),
+            ("llvm_optimize", Int(opt.llvm_optimize)),
+            ("max_local_mem_KB", Int(opt.max_local_mem_kb)),
+            ("force_derivs", flag(opt.force_derivs)),
+            ("opt_layername", String(&opt.layername)),
+            ("only_groupname", String(&opt.only_groupname)),
+            ("optimize_nondebug", flag(opt.optimize_nondebug)),
+            ("debug", Int(debug.level)),
+            ("debug_nan", flag(debug.debug_nan)),
+            ("debug_uninit", flag(debug.debug_uninit)),
+            ("clearmemory", flag(debug.clearmemory)),
+            ("llvm_debug", Int(debug.llvm_debug)),
+            ("llvm_debug_layers", flag(debug.llvm_debug_layers)),
+            ("llvm_debug_ops", flag(debug.llvm_debug_ops)),
+            ("llvm_output_bitcode", flag(debug.llvm_output_bitcode)),
+            ("debug_groupname", String(&debug.debug_groupname)),
+            ("debug_layername", String(&debug.debug_layername)),
+            ("archive_groupname", String(&debug.archive_groupname)),
+            ("archive_filename", String(&debug.archive_filename)),
+            ("lazylayers", flag(lazy.lazylayers)),
+            ("lazyglobals", flag(lazy.lazyglobals)),
+            ("lazyunconnected", flag(lazy.lazyunconnected)),
+            ("lazy_userdata", flag(lazy.lazy_userdata)),
+        ]
+    }
+}
+
+/// The value of an option, as the type of attribute it's set as.
+#[derive(Debug, PartialEq)]
+enum OptionValue<'a> {
+    Int(i32),
+    String(&'a str),
+    Strings(&'a [String]),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer_services::RendererServices;
+    use crate::test_renderer::TestRenderer;
+
+    use std::sync::Arc;
+
+    /// Check that every option reads back from the shading system as
+    /// `options` has it. OSL can read back every attribute it can set.
+    fn assert_reads_back(ss: &ShadingSystem, options: &ShadingSystemOptions) {
+        let unreadable = |name: &str, e: Error| -> ! { panic!("Could not read {}: {}", name, e) };
+        for (name, value) in options.attributes() {
+            match value {
+                OptionValue::Int(value) => {
+                    let read = ss
+                        .get_attribute::<i32>(name)
+                        .unwrap_or_else(|e| unreadable(name, e));
+                    assert_eq!(read, value, "{}", name);
+                }
+                OptionValue::String(value) => {
+                    let read = ss
+                        .get_attribute::<String>(name)
+                        .unwrap_or_else(|e| unreadable(name, e));
+                    assert_eq!(read, value, "{}", name);
+                }
+                OptionValue::Strings(value) => {
+                    let read = ss
+                        .get_attribute::<Vec<String>>(name)
+                        .unwrap_or_else(|e| unreadable(name, e));
+                    assert_eq!(read, value, "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn defaults_match_osl() {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let ss = ShadingSystem::new(rs);
+
+        assert_reads_back(&ss, &ShadingSystemOptions::default());
+    }
+
+    #[test]
+    fn apply_options() {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+
+        ShadingSystemOptions::default()
+            .apply(&mut ss)
+            .expect("Could not apply default options");
+
+        // Change every option that can be changed without side effects
+        let d = ShadingSystemOptions::default();
+        let options = ShadingSystemOptions {
+            searchpath_shader: "osl".into(),
+            raytypes: vec!["camera".into(), "shadow".into()],
+            renderer_outputs: vec!["Cout".into()],
+            statistics_level: 1,
+            range_checking: !d.range_checking,
+            unknown_coordsys_error: !d.unknown_coordsys_error,
+            connection_error: !d.connection_error,
+            strict_messages: !d.strict_messages,
+            error_repeats: !d.error_repeats,
+            compile_report: !d.compile_report,
+            max_warnings_per_thread: 10,
+            buffer_printf: !d.buffer_printf,
+            profile: !d.profile,
+            no_noise: !d.no_noise,
+            no_pointcloud: !d.no_pointcloud,
+            exec_repeat: 2,
+            opt_warnings: !d.opt_warnings,
+            gpu_opt_error: !d.gpu_opt_error,
+            lockgeom: !d.lockgeom,
+            userdata_isconnected: !d.userdata_isconnected,
+            greedyjit: !d.greedyjit,
+            countlayerexecs: !d.countlayerexecs,
+            allow_shader_replacement: !d.allow_shader_replacement,
+            optimization: OptimizationOptions {
+                level: 1,
+                simplify_param: !d.optimization.simplify_param,
+                constant_fold: !d.optimization.constant_fold,
+                stale_assign: !d.optimization.stale_assign,
+                elide_useless_ops: !d.optimization.elide_useless_ops,
+                elide_unconnected_outputs: !d.optimization.elide_unconnected_outputs,
+                peephole: !d.optimization.peephole,
+                coalesce_temps: !d.optimization.coalesce_temps,
+                assign: !d.optimization.assign,
+                mix: !d.optimization.mix,
+                merge_instances: !d.optimization.merge_instances,
+                merge_instances_with_userdata: !d.optimization.merge_instances_with_userdata,
+                fold_getattribute: !d.optimization.fold_getattribute,
+                middleman: !d.optimization.middleman,
+                texture_handle: !d.optimization.texture_handle,
+                seed_bblock_aliases: !d.optimization.seed_bblock_aliases,
+                passes: 5,
+                llvm_optimize: d.optimization.llvm_optimize + 1,
+                max_local_mem_kb: 4096,
+                force_derivs: !d.optimization.force_derivs,
+                layername: "layer".into(),
+                only_groupname: "group".into(),
+                optimize_nondebug: !d.optimization.optimize_nondebug,
+            },
+            debug: DebugOptions {
+                level: 1,
+                debug_nan: !d.debug.debug_nan,
+                debug_uninit: !d.debug.debug_uninit,
+                clearmemory: !d.debug.clearmemory,
+                llvm_debug: 1,
+                llvm_debug_layers: !d.debug.llvm_debug_layers,
+                llvm_debug_ops: !d.debug.llvm_debug_ops,
+                llvm_output_bitcode: !d.debug.llvm_output_bitcode,
+                debug_groupname: "group".into(),
+                debug_layername: "layer".into(),
+                // Would write an archive when the group is optimized
+                ..d.debug.clone()
+            },
+            lazy: LazyOptions {
+                lazylayers: !d.lazy.lazylayers,
+                lazyglobals: !d.lazy.lazyglobals,
+                lazyunconnected: !d.lazy.lazyunconnected,
+                lazy_userdata: !d.lazy.lazy_userdata,
+            },
+            ..d
+        };
+
+        options.apply(&mut ss).expect("Could not apply options");
+        assert_reads_back(&ss, &options);
+
+        // Applying the defaults again changes everything back, apart from
+        // the lists, which can't be emptied
+        ShadingSystemOptions::default()
+            .apply(&mut ss)
+            .expect("Could not apply default options");
+        assert_reads_back(
+            &ss,
+            &ShadingSystemOptions {
+                raytypes: options.raytypes.clone(),
+                renderer_outputs: options.renderer_outputs.clone(),
+                ..Default::default()
+            },
+        );
+    }
+}
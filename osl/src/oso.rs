@@ -0,0 +1,581 @@
+//! A pure-Rust reader for compiled shaders (.oso files), for tools that need
+//! to inspect shader signatures without linking liboslexec. Enabled with the
+//! "oso" feature, and usable with the "oslexec" feature turned off.
+//!
+//! ```ignore
+//! let oso = osl::oso::parse(&std::fs::read_to_string("noisetest.oso")?)?;
+//! let info = oso.shader_info();
+//! for param in &info.params {
+//!     println!("{} {:?} = {:?}", param.name, param.typedesc, param.default);
+//! }
+//! ```
+use crate::query::{BaseType, Parameter, ShaderInfo, Value, ValueType};
+use crate::Error;
+
+/// The full contents of a .oso file.
+#[derive(Debug, Clone)]
+pub struct Oso {
+    /// The .oso format version from the header, e.g. (1, 0).
+    pub version: (u32, u32),
+    /// The type of shader, e.g. "surface" or "shader".
+    pub shader_type: String,
+    pub name: String,
+    /// Metadata attached to the shader itself.
+    pub metadata: Vec<Parameter>,
+    /// Every symbol in the shader, in declaration order.
+    pub symbols: Vec<Symbol>,
+    /// The instruction stream of all code sections, in order.
+    pub instructions: Vec<Instruction>,
+}
+
+/// The kind of a symbol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SymType {
+    Param,
+    OutputParam,
+    Local,
+    Temp,
+    Global,
+    Const,
+}
+
+/// A symbol declaration.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub symtype: SymType,
+    /// The type as written in the .oso, e.g. "color", "float[3]" or
+    /// "closure color".
+    pub typespec: String,
+    /// The type as a ValueType. Closures and structs are UNKNOWN.
+    pub typedesc: ValueType,
+    pub is_closure: bool,
+    pub is_struct: bool,
+    /// Is this an array of unspecified length?
+    pub varlen_array: bool,
+    pub name: String,
+    /// The default (for params) or constant value.
+    pub value: Value,
+    /// Does the symbol carry derivatives (%derivs)?
+    pub derivs: bool,
+    /// All hints exactly as written, e.g. "%read{0,1}".
+    pub hints: Vec<String>,
+    /// Metadata declared on the symbol, decoded from %meta hints.
+    pub metadata: Vec<Parameter>,
+}
+
+/// A single op in the instruction stream.
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    /// The code section this op belongs to: "___main___" for the shader
+    /// body, otherwise the name of the parameter it initializes.
+    pub code: String,
+    pub opcode: String,
+    /// Symbol names and jump targets, as written.
+    pub args: Vec<String>,
+    /// All hints exactly as written, e.g. "%argrw{\"wrr\"}".
+    pub hints: Vec<String>,
+    /// Source location, carried over from earlier ops when not given.
+    pub filename: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Parse the text of a .oso file.
+pub fn parse(text: &str) -> Result<Oso, Error> {
+    let mut oso = Oso {
+        version: (0, 0),
+        shader_type: String::new(),
+        name: String::new(),
+        metadata: Vec::new(),
+        symbols: Vec::new(),
+        instructions: Vec::new(),
+    };
+
+    let mut seen_header = false;
+    let mut seen_shader = false;
+    let mut code = String::new();
+    let mut filename = None;
+    let mut line = None;
+
+    for (index, text_line) in text.lines().enumerate() {
+        let line_no = index + 1;
+        let fail = |msg: &str| Error::OsoParseFailed(line_no, msg.into());
+
+        let trimmed = text_line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let tokens = tokenize(trimmed).map_err(|e| fail(&e))?;
+
+        if !seen_header {
+            if tokens.len() != 2 || tokens[0] != "OpenShadingLanguage" {
+                return Err(fail("missing OpenShadingLanguage header"));
+            }
+            oso.version = parse_version(&tokens[1]).ok_or_else(|| fail("bad version"))?;
+            seen_header = true;
+        } else if !seen_shader {
+            if tokens.len() < 2 {
+                return Err(fail("expected shader declaration"));
+            }
+            oso.shader_type = tokens[0].clone();
+            oso.name = tokens[1].clone();
+            for hint in &tokens[2..] {
+                if let Some(meta) = parse_meta(hint).map_err(|e| fail(&e))? {
+                    oso.metadata.push(meta);
+                }
+            }
+            seen_shader = true;
+        } else if tokens[0] == "code" {
+            code = tokens.get(1).cloned().unwrap_or_default();
+        } else if let Some(symtype) = parse_symtype(&tokens[0]) {
+            oso.symbols
+                .push(parse_symbol(symtype, &tokens[1..]).map_err(|e| fail(&e))?);
+        } else if text_line.starts_with(char::is_whitespace) {
+            let split = tokens
+                .iter()
+                .position(|t| t.starts_with('%'))
+                .unwrap_or_else(|| tokens.len());
+            let hints = tokens[split..].to_vec();
+            for hint in &hints {
+                if let Some(f) = hint_contents(hint, "filename") {
+                    filename = Some(unquote(f));
+                } else if let Some(l) = hint_contents(hint, "line") {
+                    line = Some(l.parse().map_err(|_| fail("bad %line hint"))?);
+                }
+            }
+
+            oso.instructions.push(Instruction {
+                code: code.clone(),
+                opcode: tokens[0].clone(),
+                args: tokens[1..split].to_vec(),
+                hints,
+                filename: filename.clone(),
+                line,
+            });
+        } else {
+            return Err(fail(&format!("unexpected '{}'", tokens[0])));
+        }
+    }
+
+    if !seen_shader {
+        return Err(Error::OsoParseFailed(0, "no shader declaration".into()));
+    }
+
+    Ok(oso)
+}
+
+impl Oso {
+    /// Describe the shader's parameters in the same form as
+    /// `ShaderInfo::from_oso`, which uses liboslexec's OSLQuery.
+    pub fn shader_info(&self) -> ShaderInfo {
+        ShaderInfo {
+            shader_type: self.shader_type.clone(),
+            name: self.name.clone(),
+            params: self
+                .symbols
+                .iter()
+                .filter(|s| s.symtype == SymType::Param || s.symtype == SymType::OutputParam)
+                .map(|s| s.to_parameter())
+                .collect(),
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+impl Symbol {
+    fn to_parameter(&self) -> Parameter {
+        let structname = self.hints.iter().find_map(|h| hint_contents(h, "struct"));
+        let fields = self
+            .hints
+            .iter()
+            .find_map(|h| hint_contents(h, "structfields"))
+            .map(|f| split_list(f).into_iter().map(|f| unquote(&f)).collect())
+            .unwrap_or_default();
+
+        Parameter {
+            name: self.name.clone(),
+            typedesc: self.typedesc,
+            is_output: self.symtype == SymType::OutputParam,
+            valid_default: !self.hints.iter().any(|h| h == "%initexpr"),
+            varlen_array: self.varlen_array,
+            is_struct: self.is_struct,
+            is_closure: self.is_closure,
+            default: self.value.clone(),
+            spacename: Vec::new(),
+            fields,
+            structname: structname.map(unquote),
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+fn parse_version(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().unwrap_or("0").parse().ok()?;
+    Some((major, minor))
+}
+
+fn parse_symtype(s: &str) -> Option<SymType> {
+    match s {
+        "param" => Some(SymType::Param),
+        "oparam" => Some(SymType::OutputParam),
+        "local" => Some(SymType::Local),
+        "temp" => Some(SymType::Temp),
+        "global" => Some(SymType::Global),
+        "const" => Some(SymType::Const),
+        _ => None,
+    }
+}
+
+/// The parts of a type spec like "color[3]" or "closure color[]"
+struct TypeSpec {
+    typespec: String,
+    typedesc: ValueType,
+    is_closure: bool,
+    is_struct: bool,
+    varlen_array: bool,
+}
+
+/// Parse the type at the start of `tokens`, returning it and the number of
+/// tokens it took up.
+fn parse_typespec(tokens: &[String]) -> Result<(TypeSpec, usize), String> {
+    let first = tokens.get(0).ok_or("missing type")?;
+    let (prefix, used) = match first.as_str() {
+        "closure" | "struct" => (Some(first.as_str()), 2),
+        _ => (None, 1),
+    };
+    let last = tokens.get(used - 1).ok_or("missing type")?;
+
+    let (base, arraylen) = match last.find('[') {
+        Some(i) => {
+            let len = last[i + 1..].trim_end_matches(']');
+            let len = if len.is_empty() {
+                -1
+            } else {
+                len.parse::<i32>()
+                    .map_err(|_| format!("bad array length in '{}'", last))?
+            };
+            (&last[..i], len)
+        }
+        None => (last.as_str(), 0),
+    };
+
+    let typedesc = match prefix {
+        Some(_) => ValueType::UNKNOWN,
+        None => base_type(base)
+            .ok_or_else(|| format!("unknown type '{}'", base))?
+            .array(arraylen),
+    };
+
+    Ok((
+        TypeSpec {
+            typespec: tokens[..used].join(" "),
+            typedesc,
+            is_closure: prefix == Some("closure"),
+            is_struct: prefix == Some("struct"),
+            varlen_array: arraylen < 0,
+        },
+        used,
+    ))
+}
+
+fn base_type(name: &str) -> Option<ValueType> {
+    match name {
+        "int" => Some(ValueType::INT),
+        "float" => Some(ValueType::FLOAT),
+        "string" => Some(ValueType::STRING),
+        "color" => Some(ValueType::COLOR),
+        "point" => Some(ValueType::POINT),
+        "vector" => Some(ValueType::VECTOR),
+        "normal" => Some(ValueType::NORMAL),
+        "matrix" => Some(ValueType::MATRIX),
+        _ => None,
+    }
+}
+
+fn parse_values(base: &ValueType, tokens: &[String]) -> Result<Value, String> {
+    if tokens.is_empty() {
+        return Ok(Value::None);
+    }
+
+    if base.basetype == BaseType::String {
+        Ok(Value::String(tokens.iter().map(|t| unquote(t)).collect()))
+    } else if base.basetype == BaseType::Int {
+        tokens
+            .iter()
+            .map(|t| {
+                t.parse::<i32>()
+                    .map_err(|_| format!("bad int value '{}'", t))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Int)
+    } else {
+        tokens
+            .iter()
+            .map(|t| {
+                t.parse::<f32>()
+                    .map_err(|_| format!("bad float value '{}'", t))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Float)
+    }
+}
+
+fn parse_symbol(symtype: SymType, tokens: &[String]) -> Result<Symbol, String> {
+    let (ts, used) = parse_typespec(tokens)?;
+    let name = tokens.get(used).ok_or("missing symbol name")?.clone();
+    let rest = &tokens[used + 1..];
+    let split = rest
+        .iter()
+        .position(|t| t.starts_with('%'))
+        .unwrap_or_else(|| rest.len());
+
+    let value = if ts.is_closure || ts.is_struct {
+        Value::None
+    } else {
+        parse_values(&ts.typedesc, &rest[..split])?
+    };
+
+    let hints = rest[split..].to_vec();
+    let mut metadata = Vec::new();
+    for hint in &hints {
+        if let Some(meta) = parse_meta(hint)? {
+            metadata.push(meta);
+        }
+    }
+
+    Ok(Symbol {
+        symtype,
+        typespec: ts.typespec,
+        typedesc: ts.typedesc,
+        is_closure: ts.is_closure,
+        is_struct: ts.is_struct,
+        varlen_array: ts.varlen_array,
+        name,
+        value,
+        derivs: hints.iter().any(|h| h == "%derivs"),
+        hints,
+        metadata,
+    })
+}
+
+/// Decode a %meta{type,name,value...} hint.
+fn parse_meta(hint: &str) -> Result<Option<Parameter>, String> {
+    let contents = match hint_contents(hint, "meta") {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    let items = split_list(contents);
+    if items.len() < 2 {
+        return Err(format!("bad metadata '{}'", hint));
+    }
+    let (ts, _) = parse_typespec(&items[..1])?;
+    let default = parse_values(&ts.typedesc, &items[2..])?;
+
+    Ok(Some(Parameter {
+        name: items[1].clone(),
+        typedesc: ts.typedesc,
+        is_output: false,
+        valid_default: true,
+        varlen_array: ts.varlen_array,
+        is_struct: false,
+        is_closure: false,
+        default,
+        spacename: Vec::new(),
+        fields: Vec::new(),
+        structname: None,
+        metadata: Vec::new(),
+    }))
+}
+
+/// The text between the braces of a hint like %name{...}
+fn hint_contents<'a>(hint: &'a str, name: &str) -> Option<&'a str> {
+    let rest = hint.strip_prefix('%')?.strip_prefix(name)?;
+    rest.strip_prefix('{')?.strip_suffix('}')
+}
+
+/// Split the comma-separated contents of a hint, leaving quoted strings
+/// (which may themselves contain commas) intact.
+fn split_list(s: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escape = false;
+    for c in s.chars() {
+        if in_quotes {
+            current.push(c);
+            if escape {
+                escape = false;
+            } else if c == '\\' {
+                escape = true;
+            } else if c == '"' {
+                in_quotes = false;
+            }
+        } else if c == '"' {
+            in_quotes = true;
+            current.push(c);
+        } else if c == ',' {
+            items.push(std::mem::replace(&mut current, String::new()));
+        } else {
+            current.push(c);
+        }
+    }
+    items.push(current);
+    items
+}
+
+/// Split a line on whitespace, except inside quoted strings and {}-hints.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut escape = false;
+    let mut depth = 0;
+    for c in line.chars() {
+        if in_quotes {
+            current.push(c);
+            if escape {
+                escape = false;
+            } else if c == '\\' {
+                escape = true;
+            } else if c == '"' {
+                in_quotes = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_quotes = true;
+                current.push(c);
+            }
+            '{' => {
+                depth += 1;
+                current.push(c);
+            }
+            '}' => {
+                depth -= 1;
+                current.push(c);
+            }
+            c if c.is_whitespace() && depth == 0 => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::replace(&mut current, String::new()));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("unterminated string".into());
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+/// Remove the quotes from a string token and process its escapes.
+fn unquote(s: &str) -> String {
+    let s = match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(s) => s,
+        None => return s.to_string(),
+    };
+
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_noisetest() {
+        let oso = parse(include_str!("../osl/noisetest.oso")).expect("Could not parse");
+        assert_eq!(oso.version, (1, 0));
+        assert_eq!(oso.shader_type, "shader");
+        assert_eq!(oso.name, "noisetest");
+        assert_eq!(oso.symbols.len(), 7);
+
+        let u = oso.symbols.iter().find(|s| s.name == "u").unwrap();
+        assert_eq!(u.symtype, SymType::Global);
+        assert!(u.derivs);
+
+        let opcodes = oso
+            .instructions
+            .iter()
+            .map(|i| i.opcode.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(opcodes, vec!["mul", "mul", "noise", "end"]);
+        assert_eq!(
+            oso.instructions[2].args,
+            vec!["Cout", "noise_type", "$tmp1", "$tmp2"]
+        );
+        assert_eq!(oso.instructions[2].code, "___main___");
+        assert_eq!(
+            oso.instructions[2].filename.as_ref().unwrap(),
+            "noisetest.osl"
+        );
+        assert_eq!(oso.instructions[2].line, Some(33));
+
+        let info = oso.shader_info();
+        assert_eq!(info.params.len(), 3);
+
+        let noise_type = info.param("noise_type").unwrap();
+        assert!(!noise_type.is_output);
+        assert_eq!(noise_type.default, Value::String(vec!["uperlin".into()]));
+
+        let scale = info.param("scale").unwrap();
+        assert_eq!(scale.default, Value::Float(vec![25.0]));
+
+        let cout = info.param("Cout").unwrap();
+        assert!(cout.is_output);
+        assert_eq!(cout.typedesc, ValueType::COLOR);
+        assert_eq!(cout.default, Value::Float(vec![0.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let oso = parse(
+            "OpenShadingLanguage 1.00\n\
+             surface test %meta{string,help,\"A test, with commas\"}\n\
+             param\tfloat[2]\tweights\t0.5 0.25\t%meta{string,widget,\"number\"} \
+             %meta{float,min,0} %initexpr\n\
+             code ___main___\n\
+             \tend\n",
+        )
+        .expect("Could not parse");
+
+        let info = oso.shader_info();
+        assert_eq!(info.metadata[0].name, "help");
+        assert_eq!(
+            info.metadata[0].default,
+            Value::String(vec!["A test, with commas".into()])
+        );
+
+        let weights = info.param("weights").unwrap();
+        assert_eq!(weights.typedesc, ValueType::FLOAT.array(2));
+        assert!(!weights.valid_default);
+        assert_eq!(weights.default, Value::Float(vec![0.5, 0.25]));
+        assert_eq!(weights.string_metadata("widget"), Some("number"));
+        assert_eq!(
+            weights.metadata("min").unwrap().default,
+            Value::Float(vec![0.0])
+        );
+    }
+}
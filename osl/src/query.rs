@@ -0,0 +1,384 @@
+//! Inspecting the parameters of a compiled shader without building a shader
+//! group, using OSLQuery. The same description can be produced without
+//! liboslexec by the pure-Rust parser in `osl::oso`.
+#[cfg(feature = "oslexec")]
+use crate::ffi;
+#[cfg(feature = "oslexec")]
+use crate::{cstring, to_string, Error};
+
+#[cfg(feature = "oslexec")]
+use oiio::typedesc;
+#[cfg(feature = "oslexec")]
+use oiio::typedesc::TypeDesc;
+
+/// The type of a shader parameter or symbol. This describes the same
+/// things as OIIO's TypeDesc does for OSL, but doesn't need OpenImageIO, so
+/// that the .oso parser can be used without the C++ libraries. With the
+/// "oslexec" feature it converts to and from TypeDesc.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ValueType {
+    pub basetype: BaseType,
+    pub aggregate: Aggregate,
+    pub vecsemantics: VecSemantics,
+    /// 0 for a single value, -1 for an array of unspecified length,
+    /// otherwise the length of the array.
+    pub arraylen: i32,
+}
+
+/// The type of each component of a value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BaseType {
+    /// Closures and structs, which have no TypeDesc of their own.
+    Unknown,
+    Int,
+    Float,
+    String,
+}
+
+/// How many components a value has.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aggregate {
+    Scalar,
+    Vec3,
+    Matrix44,
+}
+
+/// What a Vec3 value means.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VecSemantics {
+    NoSemantics,
+    Color,
+    Point,
+    Vector,
+    Normal,
+}
+
+impl ValueType {
+    pub const UNKNOWN: ValueType = ValueType::scalar(BaseType::Unknown);
+    pub const INT: ValueType = ValueType::scalar(BaseType::Int);
+    pub const FLOAT: ValueType = ValueType::scalar(BaseType::Float);
+    pub const STRING: ValueType = ValueType::scalar(BaseType::String);
+    pub const COLOR: ValueType = ValueType::vec3(VecSemantics::Color);
+    pub const POINT: ValueType = ValueType::vec3(VecSemantics::Point);
+    pub const VECTOR: ValueType = ValueType::vec3(VecSemantics::Vector);
+    pub const NORMAL: ValueType = ValueType::vec3(VecSemantics::Normal);
+    pub const MATRIX: ValueType = ValueType {
+        basetype: BaseType::Float,
+        aggregate: Aggregate::Matrix44,
+        vecsemantics: VecSemantics::NoSemantics,
+        arraylen: 0,
+    };
+
+    const fn scalar(basetype: BaseType) -> ValueType {
+        ValueType {
+            basetype,
+            aggregate: Aggregate::Scalar,
+            vecsemantics: VecSemantics::NoSemantics,
+            arraylen: 0,
+        }
+    }
+
+    const fn vec3(vecsemantics: VecSemantics) -> ValueType {
+        ValueType {
+            basetype: BaseType::Float,
+            aggregate: Aggregate::Vec3,
+            vecsemantics,
+            arraylen: 0,
+        }
+    }
+
+    /// An array of this type, where a length of -1 means unspecified.
+    pub fn array(self, arraylen: i32) -> ValueType {
+        ValueType { arraylen, ..self }
+    }
+
+    /// The type of a single element of this type.
+    pub fn element(self) -> ValueType {
+        self.array(0)
+    }
+
+    pub fn is_array(&self) -> bool {
+        self.arraylen != 0
+    }
+}
+
+/// Types that OSL can't have, e.g. VEC2 or DOUBLE, become UNKNOWN.
+#[cfg(feature = "oslexec")]
+impl From<TypeDesc> for ValueType {
+    fn from(td: TypeDesc) -> ValueType {
+        let base = td.basetype as u8;
+        let basetype = if base == typedesc::INT32.basetype as u8 {
+            BaseType::Int
+        } else if base == typedesc::FLOAT.basetype as u8 {
+            BaseType::Float
+        } else if base == typedesc::STRING.basetype as u8 {
+            BaseType::String
+        } else {
+            BaseType::Unknown
+        };
+
+        let aggregate = match td.aggregate as u8 {
+            1 => Aggregate::Scalar,
+            3 => Aggregate::Vec3,
+            16 => Aggregate::Matrix44,
+            _ => return ValueType::UNKNOWN.array(td.arraylen),
+        };
+
+        let semantics = td.vecsemantics as u8;
+        let vecsemantics = if semantics == typedesc::COLOR.vecsemantics as u8 {
+            VecSemantics::Color
+        } else if semantics == typedesc::POINT.vecsemantics as u8 {
+            VecSemantics::Point
+        } else if semantics == typedesc::VECTOR.vecsemantics as u8 {
+            VecSemantics::Vector
+        } else if semantics == typedesc::NORMAL.vecsemantics as u8 {
+            VecSemantics::Normal
+        } else {
+            VecSemantics::NoSemantics
+        };
+
+        ValueType {
+            basetype,
+            aggregate,
+            vecsemantics,
+            arraylen: td.arraylen,
+        }
+    }
+}
+
+#[cfg(feature = "oslexec")]
+impl From<ValueType> for TypeDesc {
+    fn from(vt: ValueType) -> TypeDesc {
+        let td = match (vt.basetype, vt.aggregate, vt.vecsemantics) {
+            (BaseType::Unknown, _, _) => typedesc::UNKNOWN,
+            (BaseType::Int, _, _) => typedesc::INT32,
+            (BaseType::String, _, _) => typedesc::STRING,
+            (BaseType::Float, Aggregate::Scalar, _) => typedesc::FLOAT,
+            (BaseType::Float, Aggregate::Matrix44, _) => typedesc::MATRIX44,
+            (BaseType::Float, Aggregate::Vec3, VecSemantics::Color) => typedesc::COLOR,
+            (BaseType::Float, Aggregate::Vec3, VecSemantics::Point) => typedesc::POINT,
+            (BaseType::Float, Aggregate::Vec3, VecSemantics::Normal) => typedesc::NORMAL,
+            (BaseType::Float, Aggregate::Vec3, _) => typedesc::VECTOR,
+        };
+        TypeDesc::new(td.basetype, td.aggregate, td.vecsemantics, vt.arraylen)
+    }
+}
+
+/// A description of a compiled shader: its type, name, parameters and any
+/// shader-level metadata.
+#[derive(Debug, Clone)]
+pub struct ShaderInfo {
+    /// The type of shader, e.g. "surface" or "shader".
+    pub shader_type: String,
+    pub name: String,
+    pub params: Vec<Parameter>,
+    /// Metadata attached to the shader itself (as opposed to its params).
+    pub metadata: Vec<Parameter>,
+}
+
+/// A shader parameter, or an item of metadata (which OSL describes in
+/// exactly the same way, with the metadata value as its default).
+#[derive(Debug, Clone)]
+pub struct Parameter {
+    pub name: String,
+    pub typedesc: ValueType,
+    /// Is this an output parameter?
+    pub is_output: bool,
+    /// Is there a default value that can be relied upon? If not, the
+    /// default is computed by code in the shader.
+    pub valid_default: bool,
+    /// Is this an array of unspecified length?
+    pub varlen_array: bool,
+    /// Is this a structure? If so, `fields` names its members, which are
+    /// listed as separate parameters named "structparam.field".
+    pub is_struct: bool,
+    /// Is this a closure?
+    pub is_closure: bool,
+    /// The default value(s), if any.
+    pub default: Value,
+    /// The coordinate space names of point/vector/normal/matrix defaults,
+    /// one per value.
+    pub spacename: Vec<String>,
+    /// The names of the member fields if this is a struct.
+    pub fields: Vec<String>,
+    /// The name of the struct type if this is a struct.
+    pub structname: Option<String>,
+    /// Metadata attached to this parameter, e.g. [[string widget="number"]].
+    pub metadata: Vec<Parameter>,
+}
+
+/// Default values of a parameter (or the value of metadata). All the
+/// components of aggregates and arrays are stored in order, so a color
+/// default is a Float with three values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Int(Vec<i32>),
+    Float(Vec<f32>),
+    String(Vec<String>),
+}
+
+#[cfg(feature = "oslexec")]
+impl ShaderInfo {
+    /// Find the compiled shader `shadername` on the colon-separated
+    /// `searchpath` and read its description.
+    pub fn open(shadername: &str, searchpath: &str) -> Result<ShaderInfo, Error> {
+        let cshadername = cstring(shadername)?;
+        let csearchpath = cstring(searchpath)?;
+        Query::new()
+            .read(|q| unsafe { ffi::OSLQuery_open(q, cshadername.as_ptr(), csearchpath.as_ptr()) })
+    }
+
+    /// Read the description of a shader from the contents of a .oso file.
+    pub fn from_oso(oso: &str) -> Result<ShaderInfo, Error> {
+        let coso = cstring(oso)?;
+        Query::new().read(|q| unsafe { ffi::OSLQuery_open_bytecode(q, coso.as_ptr()) })
+    }
+}
+
+impl ShaderInfo {
+    /// Find the parameter with the given name.
+    pub fn param(&self, name: &str) -> Option<&Parameter> {
+        self.params.iter().find(|p| p.name == name)
+    }
+}
+
+impl Parameter {
+    /// Find the metadata item with the given name.
+    pub fn metadata(&self, name: &str) -> Option<&Parameter> {
+        self.metadata.iter().find(|m| m.name == name)
+    }
+
+    /// The value of a string metadata item, e.g. `widget` for a parameter
+    /// declared with [[string widget="checkBox"]].
+    pub fn string_metadata(&self, name: &str) -> Option<&str> {
+        match self.metadata(name).map(|m| &m.default) {
+            Some(Value::String(v)) => v.first().map(|s| s.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "oslexec")]
+struct Query {
+    query: ffi::OSLQuery,
+}
+
+#[cfg(feature = "oslexec")]
+impl Query {
+    fn new() -> Query {
+        Query {
+            query: unsafe { ffi::OSLQuery_create() },
+        }
+    }
+
+    fn read<F: FnOnce(ffi::OSLQuery) -> bool>(self, open: F) -> Result<ShaderInfo, Error> {
+        if !open(self.query) {
+            let msg = unsafe {
+                let msg = ffi::OSLQuery_geterror(self.query);
+                let s = to_string(msg);
+                ffi::osl_capi_free_string(msg);
+                s
+            };
+            return Err(Error::QueryFailed(msg));
+        }
+
+        unsafe {
+            Ok(ShaderInfo {
+                shader_type: to_string(ffi::OSLQuery_shadertype(self.query)),
+                name: to_string(ffi::OSLQuery_shadername(self.query)),
+                params: (0..ffi::OSLQuery_nparams(self.query))
+                    .map(|i| read_parameter(ffi::OSLQuery_getparam(self.query, i)))
+                    .collect(),
+                metadata: (0..ffi::OSLQuery_nmetadata(self.query))
+                    .map(|i| read_parameter(ffi::OSLQuery_metadata(self.query, i)))
+                    .collect(),
+            })
+        }
+    }
+}
+
+#[cfg(feature = "oslexec")]
+impl Drop for Query {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::OSLQuery_destroy(self.query);
+        }
+    }
+}
+
+#[cfg(feature = "oslexec")]
+unsafe fn read_parameter(p: ffi::OSLQueryParameter) -> Parameter {
+    let mut info: ffi::OSLQueryParameterInfo = std::mem::zeroed();
+    ffi::OSLQueryParameter_info(p, &mut info);
+
+    let default = if info.nsdefault > 0 {
+        Value::String(
+            (0..info.nsdefault)
+                .map(|i| to_string(ffi::OSLQueryParameter_sdefault(p, i)))
+                .collect(),
+        )
+    } else if info.nfdefault > 0 {
+        Value::Float(std::slice::from_raw_parts(info.fdefault, info.nfdefault as usize).to_vec())
+    } else if info.nidefault > 0 {
+        Value::Int(std::slice::from_raw_parts(info.idefault, info.nidefault as usize).to_vec())
+    } else {
+        Value::None
+    };
+
+    let structname = to_string(info.structname);
+
+    Parameter {
+        name: to_string(info.name),
+        typedesc: info.typedesc.into(),
+        is_output: info.isoutput,
+        valid_default: info.validdefault,
+        varlen_array: info.varlenarray,
+        is_struct: info.isstruct,
+        is_closure: info.isclosure,
+        default,
+        spacename: (0..info.nspacename)
+            .map(|i| to_string(ffi::OSLQueryParameter_spacename(p, i)))
+            .collect(),
+        fields: (0..info.nfields)
+            .map(|i| to_string(ffi::OSLQueryParameter_field(p, i)))
+            .collect(),
+        structname: if structname.is_empty() {
+            None
+        } else {
+            Some(structname)
+        },
+        metadata: (0..info.nmetadata)
+            .map(|i| read_parameter(ffi::OSLQueryParameter_metadata(p, i)))
+            .collect(),
+    }
+}
+
+#[cfg(all(test, feature = "oslexec"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_noisetest() {
+        let info = ShaderInfo::from_oso(include_str!("../osl/noisetest.oso"))
+            .expect("Could not query noisetest");
+        assert_eq!(info.shader_type, "shader");
+        assert_eq!(info.name, "noisetest");
+        assert_eq!(info.params.len(), 3);
+
+        let noise_type = info.param("noise_type").unwrap();
+        assert!(!noise_type.is_output);
+        assert_eq!(noise_type.default, Value::String(vec!["uperlin".into()]));
+
+        let scale = info.param("scale").unwrap();
+        assert_eq!(scale.default, Value::Float(vec![25.0]));
+
+        let cout = info.param("Cout").unwrap();
+        assert!(cout.is_output);
+        assert_eq!(cout.typedesc, ValueType::COLOR);
+        assert_eq!(cout.default, Value::Float(vec![0.0, 0.0, 0.0]));
+
+        let td: TypeDesc = ValueType::FLOAT.array(3).into();
+        assert_eq!(ValueType::from(td), ValueType::FLOAT.array(3));
+    }
+}
@@ -0,0 +1,137 @@
+//! Naming the renderer's ray types, so shaders can test for them with
+//! raytype("shadow") and groups can be specialized for them.
+use crate::shader_globals::ShaderGlobals;
+use crate::shading_system::ShadingSystem;
+use crate::Error;
+
+use std::ops::{BitAnd, BitOr, Not};
+
+/// The ray types the renderer traces, in bit order. OSL gives the first
+/// name bit 0 of ShaderGlobals::raytype, the second bit 1 and so on:
+///
+/// ```ignore
+/// let mut raytypes = RayTypes::new();
+/// raytypes.add("camera").add("shadow").add("diffuse").add("glossy");
+/// raytypes.apply(&mut ss)?;
+///
+/// let shadow = raytypes.mask("shadow")?;
+/// ss.optimize_group_for_raytypes(&shadow_group, shadow, RayTypeMask::NONE, None)?;
+/// sg.set_raytype(shadow);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RayTypes {
+    names: Vec<String>,
+}
+
+/// A set of ray type bits, as stored in ShaderGlobals::raytype.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct RayTypeMask(pub i32);
+
+impl RayTypes {
+    pub fn new() -> RayTypes {
+        RayTypes { names: Vec::new() }
+    }
+
+    /// Add the next ray type. There can be at most 32.
+    pub fn add(&mut self, name: &str) -> &mut RayTypes {
+        self.names.push(name.into());
+        self
+    }
+
+    /// The ray type names, in bit order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The bit for the named ray type.
+    pub fn mask(&self, name: &str) -> Result<RayTypeMask, Error> {
+        match self.names.iter().position(|n| n == name) {
+            Some(bit) if bit < 32 => Ok(RayTypeMask(1 << bit)),
+            _ => Err(Error::UnknownRayType(name.into())),
+        }
+    }
+
+    /// The combined bits for all of the named ray types.
+    pub fn mask_of(&self, names: &[&str]) -> Result<RayTypeMask, Error> {
+        names
+            .iter()
+            .try_fold(RayTypeMask::NONE, |mask, name| Ok(mask | self.mask(name)?))
+    }
+
+    /// Set the "raytypes" attribute on the shading system. This must be
+    /// done before any groups are optimized.
+    pub fn apply(&self, ss: &mut ShadingSystem) -> Result<(), Error> {
+        if self.names.len() > 32 {
+            return Err(Error::TooManyRayTypes(self.names.len()));
+        }
+        ss.attribute("raytypes", self.names.as_slice())
+    }
+}
+
+impl RayTypeMask {
+    pub const NONE: RayTypeMask = RayTypeMask(0);
+
+    /// Are all of the bits in `other` set in this mask?
+    pub fn contains(self, other: RayTypeMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for RayTypeMask {
+    type Output = RayTypeMask;
+
+    fn bitor(self, rhs: RayTypeMask) -> RayTypeMask {
+        RayTypeMask(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for RayTypeMask {
+    type Output = RayTypeMask;
+
+    fn bitand(self, rhs: RayTypeMask) -> RayTypeMask {
+        RayTypeMask(self.0 & rhs.0)
+    }
+}
+
+impl Not for RayTypeMask {
+    type Output = RayTypeMask;
+
+    fn not(self) -> RayTypeMask {
+        RayTypeMask(!self.0)
+    }
+}
+
+impl<'c> ShaderGlobals<'c> {
+    /// Set the type of the ray being shaded.
+    pub fn set_raytype(&mut self, raytype: RayTypeMask) {
+        self.raytype = raytype.0;
+    }
+
+    /// The type of the ray being shaded.
+    pub fn raytype_mask(&self) -> RayTypeMask {
+        RayTypeMask(self.raytype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks() {
+        let mut raytypes = RayTypes::new();
+        raytypes.add("camera").add("shadow").add("diffuse");
+
+        assert_eq!(raytypes.mask("camera").unwrap(), RayTypeMask(1));
+        assert_eq!(raytypes.mask("diffuse").unwrap(), RayTypeMask(4));
+        assert_eq!(
+            raytypes.mask_of(&["camera", "diffuse"]).unwrap(),
+            RayTypeMask(5)
+        );
+        assert!(raytypes.mask("glossy").is_err());
+
+        let both = raytypes.mask_of(&["camera", "shadow"]).unwrap();
+        assert!(both.contains(raytypes.mask("shadow").unwrap()));
+        assert!(!both.contains(raytypes.mask("diffuse").unwrap()));
+    }
+}
@@ -1,5 +1,183 @@
 use crate::ffi;
+use crate::math::*;
+use crate::shader_globals::ShaderGlobals;
+use crate::symbol_value::SymbolValue;
+use crate::to_string;
 
+use oiio::typedesc::TypeDesc;
+use std::marker::PhantomData;
+use std::os::raw::{c_char, c_void};
+
+/// Callbacks made by OSL into the renderer while shading. Apart from
+/// get_wrapper, each has a default that does what OSL's own
+/// RendererServices would, so a renderer only implements the ones it
+/// needs. They are only called once routed to the renderer with
+/// set_renderer_services.
 pub trait RendererServices {
     fn get_wrapper(&self) -> ffi::RendererServicesWrapper;
+
+    /// Look up the user data `name`, e.g. an interpolated primitive
+    /// variable, for the point being shaded, as asked for by a shader
+    /// parameter that isn't locked against the geometry. Returns false if
+    /// there is no such data.
+    fn get_userdata(&self, _name: &str, _sg: &ShaderGlobals, _data: &mut UserData) -> bool {
+        false
+    }
+
+    /// Look up `filename` at (s, t), writing each of its channels to
+    /// `result`. If OSL needs the derivatives of the lookup, `dresult` is
+    /// where the derivatives of each channel with respect to s and t go,
+    /// which OSL combines with those of `s` and `t` itself. Returns None to
+    /// leave the lookup to OSL's own texture system.
+    fn texture(
+        &self,
+        _filename: &str,
+        _sg: &ShaderGlobals,
+        _s: Dual2<f32>,
+        _t: Dual2<f32>,
+        _result: &mut [f32],
+        _dresult: Option<(&mut [f32], &mut [f32])>,
+    ) -> Option<bool> {
+        None
+    }
+}
+
+/// Where get_userdata writes the value OSL asked for, followed by its x and
+/// y derivatives if the shader needs them.
+pub struct UserData<'a> {
+    td: TypeDesc,
+    derivatives: bool,
+    val: *mut c_void,
+    _marker: PhantomData<&'a mut c_void>,
+}
+
+impl<'a> UserData<'a> {
+    /// The type OSL asked for.
+    pub fn typedesc(&self) -> TypeDesc {
+        self.td
+    }
+
+    /// Whether the derivatives are wanted as well as the value.
+    pub fn derivatives(&self) -> bool {
+        self.derivatives
+    }
+
+    /// Write a float and its derivatives. Returns false, writing nothing,
+    /// if OSL asked for another type.
+    pub fn set_float(&mut self, value: Dual2<f32>) -> bool {
+        self.set(value)
+    }
+
+    /// Write a point, vector, normal or color and its derivatives. Returns
+    /// false, writing nothing, if OSL asked for another type.
+    pub fn set_vector(&mut self, value: Dual2<V3f32>) -> bool {
+        self.set(value)
+    }
+
+    /// Write an int, which has no derivatives. Returns false, writing
+    /// nothing, if OSL asked for another type.
+    pub fn set_int(&mut self, value: i32) -> bool {
+        if !i32::matches(&self.td) {
+            return false;
+        }
+        unsafe { std::ptr::write_unaligned(self.val as *mut i32, value) };
+        true
+    }
+
+    /// The memory to write any other type to, laid out as OSL's
+    /// `typedesc()`, with room for the derivatives after the value if
+    /// `derivatives()`.
+    pub fn as_mut_ptr(&mut self) -> *mut c_void {
+        self.val
+    }
+
+    fn set<T: SymbolValue + Copy>(&mut self, value: Dual2<T>) -> bool {
+        if !T::matches(&self.td) {
+            return false;
+        }
+        let ptr = self.val as *mut T;
+        unsafe {
+            std::ptr::write_unaligned(ptr, value.val);
+            if self.derivatives {
+                std::ptr::write_unaligned(ptr.add(1), value.dx);
+                std::ptr::write_unaligned(ptr.add(2), value.dy);
+            }
+        }
+        true
+    }
+}
+
+/// Route OSL's get_userdata and texture callbacks through `rsw` to the
+/// RendererServices implementation of `R`.
+///
+/// # Safety
+/// The rust object set on `rsw` with RendererServicesWrapper_set_rust_object
+/// must be an `R`, and must outlive the wrapper.
+pub unsafe fn set_renderer_services<R: RendererServices>(rsw: ffi::RendererServicesWrapper) {
+    ffi::RendererServicesWrapper_setfn_get_userdata(rsw, Some(get_userdata::<R>));
+    ffi::RendererServicesWrapper_setfn_texture(rsw, Some(texture::<R>));
+}
+
+extern "C" fn get_userdata<R: RendererServices>(
+    rs_obj: *mut c_void,
+    derivatives: bool,
+    name: *const c_char,
+    td: TypeDesc,
+    sg: ffi::ShaderGlobalsPtr,
+    val: *mut c_void,
+) -> i32 {
+    let (renderer, name, sg) = unsafe {
+        (
+            &*(rs_obj as *const R),
+            to_string(name),
+            &*(sg as *const ShaderGlobals),
+        )
+    };
+    let mut data = UserData {
+        td,
+        derivatives,
+        val,
+        _marker: PhantomData,
+    };
+    renderer.get_userdata(&name, sg, &mut data) as i32
+}
+
+// Takes what OSL's own texture() does
+#[allow(clippy::too_many_arguments)]
+extern "C" fn texture<R: RendererServices>(
+    rs_obj: *mut c_void,
+    filename: *const c_char,
+    sg: ffi::ShaderGlobalsPtr,
+    s: *const f32,
+    t: *const f32,
+    nchannels: i32,
+    result: *mut f32,
+    dresultds: *mut f32,
+    dresultdt: *mut f32,
+) -> i32 {
+    let nchannels = nchannels as usize;
+    let (renderer, filename, sg, s, t, result, dresult) = unsafe {
+        (
+            &*(rs_obj as *const R),
+            to_string(filename),
+            &*(sg as *const ShaderGlobals),
+            Dual2::new(*s, *s.add(1), *s.add(2)),
+            Dual2::new(*t, *t.add(1), *t.add(2)),
+            std::slice::from_raw_parts_mut(result, nchannels),
+            // OSL asks for both or neither
+            if dresultds.is_null() || dresultdt.is_null() {
+                None
+            } else {
+                Some((
+                    std::slice::from_raw_parts_mut(dresultds, nchannels),
+                    std::slice::from_raw_parts_mut(dresultdt, nchannels),
+                ))
+            },
+        )
+    };
+
+    match renderer.texture(&filename, sg, s, t, result, dresult) {
+        Some(found) => found as i32,
+        None => -1,
+    }
 }
@@ -1,6 +1,9 @@
 use crate::ffi;
 use crate::math::*;
-use ffi::{ErrCode, PerThreadInfo, RendererServicesWrapper, ShadingContext, VerbosityLevel};
+use crate::shading_system::ContextRef;
+use ffi::{ErrCode, PerThreadInfo, RendererServicesWrapper, ShadingContext};
+
+use std::marker::PhantomData;
 
 /// The ShaderGlobals structure represents the state describing a particular
 /// point to be shaded. It serves two primary purposes: (1) it holds the
@@ -17,7 +20,7 @@ use ffi::{ErrCode, PerThreadInfo, RendererServicesWrapper, ShadingContext, Verbo
 /// All points, vectors and normals are given in "common" space.
 ///
 #[repr(C)]
-pub struct ShaderGlobals {
+pub struct ShaderGlobals<'c> {
     /// Surface position (and its x & y differentials).
     pub P: V3f32,
     pub dPdx: V3f32,
@@ -103,7 +106,7 @@ pub struct ShaderGlobals {
     /// energy normalization).
     pub surfacearea: f32,
 
-    /// Bit field of ray type flags.
+    /// Bit field of ray type flags. See RayTypes for naming the bits.
     pub raytype: i32,
 
     /// If nonzero, will flip the result of calculatenormal().
@@ -111,10 +114,21 @@ pub struct ShaderGlobals {
 
     /// If nonzero, we are shading the back side of a surface.
     pub backfacing: i32,
+
+    /// Ties the globals to the context they were made with
+    _context: PhantomData<ContextRef<'c>>,
 }
 
-impl ShaderGlobals {
-    pub fn new(context: ShadingContext, renderer: RendererServicesWrapper) -> ShaderGlobals {
+// Passed to the C API as the ShaderGlobalsApi it declares, which the shim
+// checks against OSL's own ShaderGlobals
+osl_sys::assert_layout_eq!(ShaderGlobals<'static>, ffi::ShaderGlobalsApi);
+
+impl<'c> ShaderGlobals<'c> {
+    pub(crate) fn as_ffi(&mut self) -> ffi::ShaderGlobalsPtr {
+        self as *mut ShaderGlobals as ffi::ShaderGlobalsPtr
+    }
+
+    pub fn new(context: ContextRef<'c>, renderer: RendererServicesWrapper) -> ShaderGlobals<'c> {
         ShaderGlobals {
             P: v3f32(0.0, 0.0, 0.0),
             dPdx: v3f32(0.0, 0.0, 0.0),
@@ -151,7 +165,7 @@ impl ShaderGlobals {
             tracedata: std::ptr::null(),
             objdata: std::ptr::null(),
 
-            context,
+            context: context.ctx,
             renderer,
 
             object2common: std::ptr::null(),
@@ -163,6 +177,68 @@ impl ShaderGlobals {
             raytype: 0,
             flipHandedness: 0,
             backfacing: 0,
+
+            _context: PhantomData,
         }
     }
+
+    /// P and its x and y derivatives.
+    pub fn p_dual(&self) -> Dual2<V3f32> {
+        Dual2::new(self.P, self.dPdx, self.dPdy)
+    }
+
+    /// Set P and its x and y derivatives.
+    pub fn set_p_dual(&mut self, p: Dual2<V3f32>) {
+        self.P = p.val;
+        self.dPdx = p.dx;
+        self.dPdy = p.dy;
+    }
+
+    /// I and its x and y derivatives.
+    pub fn i_dual(&self) -> Dual2<V3f32> {
+        Dual2::new(self.I, self.dIdx, self.dIdy)
+    }
+
+    /// Set I and its x and y derivatives.
+    pub fn set_i_dual(&mut self, i: Dual2<V3f32>) {
+        self.I = i.val;
+        self.dIdx = i.dx;
+        self.dIdy = i.dy;
+    }
+
+    /// u and its x and y derivatives.
+    pub fn u_dual(&self) -> Dual2<f32> {
+        Dual2::new(self.u, self.dudx, self.dudy)
+    }
+
+    /// Set u and its x and y derivatives.
+    pub fn set_u_dual(&mut self, u: Dual2<f32>) {
+        self.u = u.val;
+        self.dudx = u.dx;
+        self.dudy = u.dy;
+    }
+
+    /// v and its x and y derivatives.
+    pub fn v_dual(&self) -> Dual2<f32> {
+        Dual2::new(self.v, self.dvdx, self.dvdy)
+    }
+
+    /// Set v and its x and y derivatives.
+    pub fn set_v_dual(&mut self, v: Dual2<f32>) {
+        self.v = v.val;
+        self.dvdx = v.dx;
+        self.dvdy = v.dy;
+    }
+
+    /// Ps and its x and y derivatives.
+    pub fn ps_dual(&self) -> Dual2<V3f32> {
+        Dual2::new(self.Ps, self.dPsdx, self.dPsdy)
+    }
+
+    /// Set Ps and its x and y derivatives.
+    pub fn set_ps_dual(&mut self, ps: Dual2<V3f32>) {
+        self.Ps = ps.val;
+        self.dPsdx = ps.dx;
+        self.dPsdy = ps.dy;
+    }
 }
@@ -1,50 +1,201 @@
 use crate::closure::ClosureParam;
+use crate::error_handler::{
+    capture_messages, check_reported, collect_messages, default_error_handler, ErrorHandler,
+    ErrorLevel, Message, VerbosityLevel,
+};
 use crate::ffi;
-use crate::ffi::{ErrCode, PerThreadInfo, ShadingContext};
+use crate::ffi::{PerThreadInfo, ShadingContext};
+use crate::math::{Color3, Dual2, V3f32};
+use crate::raytypes::RayTypeMask;
 use crate::renderer_services::RendererServices;
 use crate::shader_globals::ShaderGlobals;
-use crate::shading_system_attribute::ShadingSystemAttribute;
-use crate::Error;
+use crate::shading_system_attribute::{ShadingSystemAttribute, ShadingSystemAttributeValue};
+use crate::symbol_value::SymbolValue;
+use crate::{cstring, Error};
 
 use std::cell::RefCell;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, ThreadId};
 
 use oiio::imagebuf::ImageBuf;
 use oiio::imageio::ROI;
 use oiio::typedesc::TypeDesc;
 use oiio::Ustring;
 
+/// The main entry point to OSL. Apart from setting attributes (which
+/// requires `&mut self`), OSL's ShadingSystem is safe to use from many
+/// threads at once, so it can be shared between renderer worker threads via
+/// an `Arc`. Each thread must use its own ShadingContext, which is most
+/// easily done with `with_context()`.
 pub struct ShadingSystem {
+    pub(crate) ss: ffi::ShadingSystem,
+    renderer: Arc<dyn RendererServices + Send + Sync>,
+    // Dropped after ss, which refers to it
+    error_handler: ErrorHandler,
+    contexts: Arc<ContextPool>,
+}
+
+// The underlying OSL::ShadingSystem does its own locking for everything that
+// can be reached through &self, and the renderer is required to be Send + Sync.
+unsafe impl Send for ShadingSystem {}
+unsafe impl Sync for ShadingSystem {}
+
+/// A PerThreadInfo and the ShadingContext created from it, owned by the
+/// context pool in ShadingSystem.
+struct ThreadContext {
+    tinfo: PerThreadInfo,
+    ctx: ShadingContext,
+}
+
+// A ThreadContext is only ever used by the thread whose id it is stored
+// under, but the pool holding it is shared.
+unsafe impl Send for ThreadContext {}
+
+/// The contexts created by with_context, one per thread. Each thread that
+/// has a context here holds a weak reference to the pool, so that it can
+/// destroy its context when it exits.
+struct ContextPool {
     ss: ffi::ShadingSystem,
-    renderer: Arc<RefCell<dyn RendererServices>>,
-    error_handler: ffi::ErrorHandler,
+    contexts: Mutex<HashMap<ThreadId, ThreadContext>>,
+}
+
+unsafe impl Send for ContextPool {}
+unsafe impl Sync for ContextPool {}
+
+impl ContextPool {
+    fn destroy(&self, tc: ThreadContext) {
+        unsafe {
+            ffi::ShadingSystem_release_context(self.ss, tc.ctx);
+            ffi::ShadingSystem_destroy_thread_info(self.ss, tc.tinfo);
+        }
+    }
+
+    /// Destroy the pooled context of thread `id`, if there is one. This
+    /// happens with the pool locked, so that ShadingSystem::drop can't
+    /// destroy the ShadingSystem under us.
+    fn evict(&self, id: ThreadId) {
+        let mut contexts = self.contexts.lock().unwrap();
+        if let Some(tc) = contexts.remove(&id) {
+            self.destroy(tc);
+        }
+    }
+}
+
+/// The pools the current thread has a context in.
+struct ThreadPools {
+    id: ThreadId,
+    pools: RefCell<Vec<Weak<ContextPool>>>,
+}
+
+impl ThreadPools {
+    fn register(&self, pool: &Arc<ContextPool>) {
+        let mut pools = self.pools.borrow_mut();
+        // Forget about pools whose ShadingSystem has been dropped
+        pools.retain(|p| p.strong_count() > 0);
+        if !pools.iter().any(|p| p.as_ptr() == Arc::as_ptr(pool)) {
+            pools.push(Arc::downgrade(pool));
+        }
+    }
+}
+
+impl Drop for ThreadPools {
+    fn drop(&mut self) {
+        for pool in self.pools.borrow().iter().filter_map(Weak::upgrade) {
+            pool.evict(self.id);
+        }
+    }
+}
+
+thread_local! {
+    static THREAD_POOLS: ThreadPools = ThreadPools {
+        id: thread::current().id(),
+        pools: RefCell::new(Vec::new()),
+    };
+}
+
+/// The calling thread's ShadingContext, lent to the closure given to
+/// ShadingSystem::with_context. It can't outlive the call or be sent to
+/// another thread, and neither can the ShaderGlobals made with it.
+#[derive(Clone, Copy)]
+pub struct ContextRef<'a> {
+    pub(crate) ctx: ShadingContext,
+    _marker: PhantomData<&'a *const ()>,
+}
+
+impl<'a> ContextRef<'a> {
+    /// Borrow a context obtained with ShadingSystem::get_context, for
+    /// renderers that manage their own contexts.
+    ///
+    /// # Safety
+    /// `ctx` must have come from get_context on the ShadingSystem it's
+    /// used with, and must only be used on the thread that got it. It must
+    /// not be released while the returned ContextRef, or anything made
+    /// with it, is still in use.
+    pub unsafe fn from_raw(ctx: ShadingContext) -> ContextRef<'a> {
+        ContextRef {
+            ctx,
+            _marker: PhantomData,
+        }
+    }
 }
 
 impl ShadingSystem {
-    pub fn new(renderer: Arc<RefCell<dyn RendererServices>>) -> ShadingSystem {
-        let error_handler = unsafe { ffi::ErrorHandler_create(handle_errors) };
+    /// Create a shading system that reports its errors and other messages
+    /// with default_error_handler.
+    pub fn new(renderer: Arc<dyn RendererServices + Send + Sync>) -> ShadingSystem {
+        ShadingSystem::with_error_handler(renderer, default_error_handler)
+    }
+
+    /// Create a shading system that reports its errors and other messages
+    /// by calling `handler`, which may be called from any thread that uses
+    /// the shading system.
+    pub fn with_error_handler<F>(
+        renderer: Arc<dyn RendererServices + Send + Sync>,
+        handler: F,
+    ) -> ShadingSystem
+    where
+        F: Fn(ErrorLevel, &str) + Send + Sync + 'static,
+    {
+        let error_handler = ErrorHandler::new(handler);
 
         let ss = unsafe {
-            ffi::ShadingSystem_create_with_error_handler(
-                renderer.borrow().get_wrapper(),
-                error_handler,
-            )
+            ffi::ShadingSystem_create_with_error_handler(renderer.get_wrapper(), error_handler.eh)
         };
 
         ShadingSystem {
             ss,
             renderer,
             error_handler,
+            contexts: Arc::new(ContextPool {
+                ss,
+                contexts: Mutex::new(HashMap::new()),
+            }),
         }
     }
 
-    pub fn register_closure(&mut self, name: &str, id: i32, params: &[ClosureParam]) {
-        let name = std::ffi::CString::new(name).unwrap();
+    /// Set how much is reported to the error handler.
+    pub fn set_verbosity(&self, verbosity: VerbosityLevel) {
+        self.error_handler.set_verbosity(verbosity)
+    }
+
+    pub fn verbosity(&self) -> VerbosityLevel {
+        self.error_handler.verbosity()
+    }
+
+    pub fn register_closure(
+        &mut self,
+        name: &str,
+        id: i32,
+        params: &[ClosureParam],
+    ) -> Result<(), Error> {
+        let name = cstring(name)?;
 
         let closure_params = params
             .iter()
-            .map(|p| ffi::ClosureParam::from(p))
-            .collect::<Vec<_>>();
+            .map(|p| p.to_ffi())
+            .collect::<Result<Vec<_>, _>>()?;
 
         unsafe {
             ffi::ShadingSystem_register_closure(
@@ -54,6 +205,7 @@ impl ShadingSystem {
                 closure_params.as_ptr(),
             );
         }
+        Ok(())
     }
 
     /// Set an attribute controlling the shading system.  Return true
@@ -100,7 +252,7 @@ impl ShadingSystem {
     ///                              being queried (1).
     ///    int error_repeats      If zero, suppress repeats of errors and
     ///                              warnings that are exact duplicates of
-    ///                              earlier ones. (1)
+    ///                              earlier ones. (0)
     ///    int lazylayers         Evaluate shader layers only when their
     ///                              outputs are first needed (1)
     ///    int lazyglobals        Run layers lazily even if they write to
@@ -147,7 +299,7 @@ impl ShadingSystem {
     ///    int llvm_output_bitcode  Output the full bitcode for each group,
     ///                              for debugging. (0)
     ///    int max_local_mem_KB   Error if shader group needs more than this
-    ///                              much local storage to execute (1024K)
+    ///                              much local storage to execute (2048K)
     ///    string debug_groupname Name of shader group -- debug only this one
     ///    string debug_layername Name of shader layer -- debug only this one
     ///    int optimize_nondebug  If 1, fully optimize shaders that are not
@@ -165,11 +317,13 @@ impl ShadingSystem {
         name: &str,
         val: T,
     ) -> Result<(), Error> {
-        if val.set_attribute(name, self.ss) {
-            Ok(())
-        } else {
-            Err(Error::SetAttributeFailed(name.into()))
-        }
+        capture_messages(|| {
+            if val.set_attribute(name, self.ss)? {
+                Ok(())
+            } else {
+                Err(Error::SetAttributeFailed(name.into()))
+            }
+        })
     }
 
     pub fn group_attribute<T: ShadingSystemAttribute>(
@@ -178,27 +332,97 @@ impl ShadingSystem {
         name: &str,
         val: T,
     ) -> Result<(), Error> {
-        if val.set_group_attribute(name, self.ss, group) {
-            Ok(())
-        } else {
-            Err(Error::SetGroupAttributeFailed(name.into()))
-        }
+        capture_messages(|| {
+            if val.set_group_attribute(name, self.ss, group)? {
+                Ok(())
+            } else {
+                Err(Error::SetGroupAttributeFailed(name.into()))
+            }
+        })
     }
 
-    // FIXME: Handle potential null case
-    pub fn shader_group_begin(&self, group_name: &str) -> ShaderGroupRef {
-        let group_name = std::ffi::CString::new(group_name).unwrap();
-        unsafe {
-            Arc::new(ShaderGroup {
-                group: ffi::ShadingSystem_shader_group_begin(self.ss, group_name.as_ptr()),
-            })
-        }
+    /// Read back an attribute of the shading system, e.g.
+    /// `ss.get_attribute::<String>("searchpath:shader")`. As well as the
+    /// attributes that can be set (see `attribute`), statistics such as
+    /// "stat:shaders_loaded" can be read. Arrays are read with `Vec<T>`.
+    pub fn get_attribute<T: ShadingSystemAttributeValue>(&self, name: &str) -> Result<T, Error> {
+        let cname = cstring(name)?;
+        T::get_attribute(
+            || unsafe {
+                ffi::ShadingSystem_getattribute_arraylen(
+                    self.ss,
+                    std::ptr::null_mut(),
+                    cname.as_ptr(),
+                )
+            },
+            |td, val| unsafe { ffi::ShadingSystem_getattribute(self.ss, cname.as_ptr(), td, val) },
+        )
+        .ok_or_else(|| Error::GetAttributeFailed(name.into()))
     }
 
-    pub fn shader_group_end(&self, group: &ShaderGroupRef) {
-        unsafe {
-            ffi::ShadingSystem_shader_group_end(self.ss, group.group);
+    /// Read back an attribute of a shader group, e.g. "groupname" or
+    /// "num_layers". See also `group_info` for typed group queries.
+    pub fn get_group_attribute<T: ShadingSystemAttributeValue>(
+        &self,
+        group: &ShaderGroupRef,
+        name: &str,
+    ) -> Result<T, Error> {
+        let cname = cstring(name)?;
+        T::get_attribute(
+            || unsafe {
+                ffi::ShadingSystem_getattribute_arraylen(self.ss, group.group, cname.as_ptr())
+            },
+            |td, val| unsafe {
+                ffi::ShadingSystem_group_getattribute(self.ss, group.group, cname.as_ptr(), td, val)
+            },
+        )
+        .ok_or_else(|| Error::GetGroupAttributeFailed(name.into()))
+    }
+
+    /// Load compiled shader (oso) from a memory buffer, overriding
+    /// shader lookups in the shader search path. The shader can then be
+    /// used by name in shader() calls exactly as if a file called
+    /// `shadername`.oso had been found on "searchpath:shader".
+    pub fn load_memory_compiled_shader(&self, shadername: &str, buffer: &str) -> Result<(), Error> {
+        capture_messages(|| {
+            let cshadername = cstring(shadername)?;
+            let cbuffer = cstring(buffer)?;
+            if unsafe {
+                ffi::ShadingSystem_load_memory_compiled_shader(
+                    self.ss,
+                    cshadername.as_ptr(),
+                    cbuffer.as_ptr(),
+                )
+            } {
+                Ok(())
+            } else {
+                Err(Error::LoadShaderFailed(shadername.into()))
+            }
+        })
+    }
+
+    /// Begin a new shader group. Shaders are added to it with shader()
+    /// until shader_group_end is called.
+    pub fn shader_group_begin(&self, group_name: &str) -> Result<ShaderGroupRef, Error> {
+        let group_name = cstring(group_name)?;
+        let group = unsafe { ffi::ShadingSystem_shader_group_begin(self.ss, group_name.as_ptr()) };
+        if group.is_null() {
+            return Err(Error::NullHandle("shader group"));
         }
+        Ok(Arc::new(ShaderGroup {
+            group,
+            symbols: RwLock::new(HashMap::new()),
+        }))
+    }
+
+    pub fn shader_group_end(&self, group: &ShaderGroupRef) -> Result<(), Error> {
+        capture_messages(|| {
+            if unsafe { ffi::ShadingSystem_shader_group_end(self.ss, group.group) } {
+                Ok(())
+            } else {
+                Err(Error::ShaderGroupEndFailed)
+            }
+        })
     }
 
     /// Set a parameter of the next shader that will be added to the group,
@@ -220,26 +444,28 @@ impl ShadingSystem {
         shadername: &str,
         layername: &str,
     ) -> Result<(), Error> {
-        let cshaderusage = std::ffi::CString::new(shaderusage).unwrap();
-        let cshadername = std::ffi::CString::new(shadername).unwrap();
-        let clayername = std::ffi::CString::new(layername).unwrap();
-        if unsafe {
-            ffi::ShadingSystem_shader(
-                self.ss,
-                group.group,
-                cshaderusage.as_ptr(),
-                cshadername.as_ptr(),
-                clayername.as_ptr(),
-            )
-        } {
-            Ok(())
-        } else {
-            Err(Error::ShaderFailed(
-                shaderusage.into(),
-                shadername.into(),
-                layername.into(),
-            ))
-        }
+        capture_messages(|| {
+            let cshaderusage = cstring(shaderusage)?;
+            let cshadername = cstring(shadername)?;
+            let clayername = cstring(layername)?;
+            if unsafe {
+                ffi::ShadingSystem_shader(
+                    self.ss,
+                    group.group,
+                    cshaderusage.as_ptr(),
+                    cshadername.as_ptr(),
+                    clayername.as_ptr(),
+                )
+            } {
+                Ok(())
+            } else {
+                Err(Error::ShaderFailed(
+                    shaderusage.into(),
+                    shadername.into(),
+                    layername.into(),
+                ))
+            }
+        })
     }
 
     /// Create a per-thread data needed for shader execution.  It's very
@@ -268,7 +494,8 @@ impl ShadingSystem {
     /// more than one thread.  The 'threadinfo' parameter should be a
     /// thread-specific pointer created by create_thread_info.  The context
     /// can be used to shade many points; a typical usage is to allocate
-    /// just one context per thread and use it for the whole run.
+    /// just one context per thread and use it for the whole run. Shading
+    /// with it goes through ContextRef::from_raw.
     pub fn get_context(&self, tinfo: PerThreadInfo) -> Result<ShadingContext, Error> {
         let ctx = unsafe { ffi::ShadingSystem_get_context(self.ss, tinfo) };
         if ctx.is_null() {
@@ -279,12 +506,144 @@ impl ShadingSystem {
     }
 
     /// Return a ShadingContext to the pool.
-    pub fn release_context(&self, context: ShadingContext) {
-        unsafe {
-            ffi::ShadingSystem_release_context(self.ss, context);
+    ///
+    /// # Safety
+    /// `context` must have come from get_context, and no ContextRef made
+    /// from it may be used afterwards.
+    pub unsafe fn release_context(&self, context: ShadingContext) {
+        ffi::ShadingSystem_release_context(self.ss, context);
+    }
+
+    /// Run `f` with the ShadingContext belonging to the calling thread.
+    ///
+    /// The first call on each thread lazily creates a PerThreadInfo and a
+    /// ShadingContext for it, which are then kept in a pool and reused by
+    /// every subsequent call from that thread until the thread exits or the
+    /// ShadingSystem is dropped. This makes it possible to shade from e.g.
+    /// rayon workers without managing thread infos by hand:
+    ///
+    /// ```ignore
+    /// (0..height).into_par_iter().for_each(|y| {
+    ///     ss.with_context(|ctx| {
+    ///         // ... ss.execute(ctx, &group, &mut sg, true) ...
+    ///     }).unwrap();
+    /// });
+    /// ```
+    ///
+    /// Calls may be nested, in which case the inner call gets a context of
+    /// its own.
+    pub fn with_context<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(ContextRef<'_>) -> R,
+    {
+        let id = thread::current().id();
+        let pooled = self.contexts.contexts.lock().unwrap().remove(&id);
+        let tc = match pooled {
+            Some(tc) => tc,
+            None => {
+                let tc = self.create_thread_context()?;
+                THREAD_POOLS.with(|pools| pools.register(&self.contexts));
+                tc
+            }
+        };
+
+        // Hand the context back to the pool even if `f` panics
+        let guard = ContextGuard {
+            pool: &self.contexts,
+            id,
+            tc: Some(tc),
+        };
+        // The context stays valid until the guard hands it back
+        let ctx = unsafe { ContextRef::from_raw(guard.tc.as_ref().unwrap().ctx) };
+        Ok(f(ctx))
+    }
+
+    fn create_thread_context(&self) -> Result<ThreadContext, Error> {
+        let tinfo = self.create_thread_info()?;
+        match self.get_context(tinfo) {
+            Ok(ctx) => Ok(ThreadContext { tinfo, ctx }),
+            Err(e) => {
+                self.destroy_thread_info(tinfo);
+                Err(e)
+            }
         }
     }
 
+    /// Optimize and JIT the group now, rather than lazily on its first
+    /// execution, so that it's ready for find_symbol and the first shade
+    /// doesn't pay for compilation. If a context is given, it's used for
+    /// scratch space, otherwise OSL gets a temporary one of its own. Fails
+    /// if OSL reports an error while optimizing, e.g. if the group needs
+    /// more than "max_local_mem_KB".
+    pub fn optimize_group(
+        &self,
+        group: &ShaderGroupRef,
+        context: Option<ContextRef<'_>>,
+    ) -> Result<(), Error> {
+        self.optimize_group_for_raytypes(group, RayTypeMask::NONE, RayTypeMask::NONE, context)
+    }
+
+    /// Like optimize_group, but specialize the group for rays whose type
+    /// bits are known to be set (`raytypes_on`) or known to be clear
+    /// (`raytypes_off`), so that raytype() calls on those bits fold to
+    /// constants. Executing the group for a ray that doesn't match is then
+    /// undefined as far as those calls are concerned. See RayTypes for
+    /// getting the masks by name.
+    pub fn optimize_group_for_raytypes(
+        &self,
+        group: &ShaderGroupRef,
+        raytypes_on: RayTypeMask,
+        raytypes_off: RayTypeMask,
+        context: Option<ContextRef<'_>>,
+    ) -> Result<(), Error> {
+        check_reported(Error::OptimizeFailed, || unsafe {
+            ffi::ShadingSystem_optimize_group(
+                self.ss,
+                group.group,
+                raytypes_on.0,
+                raytypes_off.0,
+                context.map_or(std::ptr::null_mut(), |c| c.ctx),
+            );
+        })
+    }
+
+    /// Optimize and JIT every group that has been created, using `nthreads`
+    /// threads (0 means as many as there are cores). Useful as a warmup
+    /// step before rendering starts. Fails if OSL reports an error while
+    /// optimizing any of the groups.
+    pub fn optimize_all_groups(&self, nthreads: i32) -> Result<(), Error> {
+        let nthreads = if nthreads > 0 {
+            nthreads
+        } else {
+            thread::available_parallelism().map_or(1, |n| n.get() as i32)
+        };
+
+        // OSL would spawn threads of its own, where errors can't be told
+        // apart from those of any other thread. Instead give each of ours
+        // its share of the groups, so check_reported sees what it reports.
+        let ss = self.ss as usize;
+        let workers = (0..nthreads)
+            .map(|mythread| {
+                thread::spawn(move || {
+                    check_reported(Error::OptimizeAllFailed, || unsafe {
+                        ffi::ShadingSystem_optimize_all_groups(
+                            ss as ffi::ShadingSystem,
+                            1,
+                            mythread,
+                            nthreads,
+                        );
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        // Wait for every thread before failing
+        let results = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>();
+        results.into_iter().collect()
+    }
+
     /// Execute the shader group in this context. If ctx is NULL, then
     /// execute will request one (based on the running thread) on its own
     /// and then return it when it's done.  This is just a wrapper around
@@ -293,23 +652,140 @@ impl ShadingSystem {
     /// setup, don't actually run the shader.
     pub fn execute(
         &self,
-        context: ShadingContext,
+        context: ContextRef<'_>,
         group: &ShaderGroupRef,
-        sg: &ShaderGlobals,
+        sg: &mut ShaderGlobals,
         run: bool,
     ) -> Result<(), Error> {
-        if unsafe {
-            ffi::ShadingSystem_execute(
-                self.ss,
-                context,
-                group.group,
-                sg as *const ShaderGlobals,
-                run,
-            )
-        } {
-            Ok(())
+        let context = context.ctx;
+        capture_messages(|| {
+            if unsafe {
+                ffi::ShadingSystem_execute(self.ss, context, group.group, sg.as_ffi(), run)
+            } {
+                Ok(())
+            } else {
+                Err(Error::ExecuteFailed)
+            }
+        })
+    }
+
+    /// Like execute, but also collect everything the shader reported with
+    /// printf, warning and error while it ran, for showing per-point shader
+    /// output in a debugging tool. printf output is only reported if the
+    /// verbosity is not Quiet, and warnings are subject to the
+    /// "max_warnings_per_thread" limit.
+    ///
+    /// OSL doesn't say which context a message came from, so the log is
+    /// everything reported on the calling thread during the call rather
+    /// than everything reported by `context`. Messages from other threads
+    /// are never included, but anything else OSL reports on this thread
+    /// while the call runs is, such as errors from optimizing and JITing
+    /// the group on its first execution.
+    pub fn execute_with_log(
+        &self,
+        context: ContextRef<'_>,
+        group: &ShaderGroupRef,
+        sg: &mut ShaderGlobals,
+        run: bool,
+    ) -> Result<ShaderLog, Error> {
+        let (result, messages) = collect_messages(|| self.execute(context, group, sg, run));
+        result.map(|_| ShaderLog {
+            P: sg.P,
+            u: sg.u,
+            v: sg.v,
+            messages,
+        })
+    }
+
+    /// Bind a shader group and globals to the context, in preparation to
+    /// execute, including optimization and JIT of the group (if it has not
+    /// already been done). If run==false, just do the binding and setup,
+    /// don't actually run the shader. After execute_init, any number of
+    /// layers may be run with execute_layer, and the context must then be
+    /// finished with execute_cleanup.
+    pub fn execute_init(
+        &self,
+        context: ContextRef<'_>,
+        group: &ShaderGroupRef,
+        sg: &mut ShaderGlobals,
+        run: bool,
+    ) -> Result<(), Error> {
+        let context = context.ctx;
+        capture_messages(|| {
+            if unsafe {
+                ffi::ShadingSystem_execute_init(self.ss, context, group.group, sg.as_ffi(), run)
+            } {
+                Ok(())
+            } else {
+                Err(Error::ExecuteInitFailed)
+            }
+        })
+    }
+
+    /// Execute the given layer of the group that has been bound to the
+    /// context with execute_init. The layer may be given by its index, by
+    /// its name, or by a symbol (as returned by find_symbol) in which case
+    /// the layer containing that symbol is run. Layers that the requested
+    /// one depends on are run lazily as needed, and a layer that has
+    /// already been run will not run again until the next execute_init.
+    pub fn execute_layer<'a, L: Into<Layer<'a>>>(
+        &self,
+        context: ContextRef<'_>,
+        sg: &mut ShaderGlobals,
+        layer: L,
+    ) -> Result<(), Error> {
+        let context = context.ctx;
+        capture_messages(|| {
+            let sg = sg.as_ffi();
+            let layer = layer.into();
+            let result = unsafe {
+                match layer {
+                    Layer::Index(index) => {
+                        ffi::ShadingSystem_execute_layer_index(self.ss, context, sg, index)
+                    }
+                    Layer::Name(ref name) => {
+                        ffi::ShadingSystem_execute_layer_name(self.ss, context, sg, name.ptr)
+                    }
+                    Layer::Symbol(symbol) => {
+                        ffi::ShadingSystem_execute_layer_symbol(self.ss, context, sg, symbol.symbol)
+                    }
+                }
+            };
+
+            if result {
+                Ok(())
+            } else {
+                Err(Error::ExecuteLayerFailed(match layer {
+                    Layer::Index(index) => index.to_string(),
+                    Layer::Name(name) => format!("'{}'", name),
+                    Layer::Symbol(_) => "containing symbol".into(),
+                }))
+            }
+        })
+    }
+
+    /// Signify that the context is done with the current execution of the
+    /// group that was kicked off by execute_init and one or more calls to
+    /// execute_layer.
+    pub fn execute_cleanup(&self, context: ContextRef<'_>) -> Result<(), Error> {
+        let context = context.ctx;
+        capture_messages(|| {
+            if unsafe { ffi::ShadingSystem_execute_cleanup(self.ss, context) } {
+                Ok(())
+            } else {
+                Err(Error::ExecuteCleanupFailed)
+            }
+        })
+    }
+
+    /// Find the index of the named layer in the shader group, suitable for
+    /// passing to execute_layer.
+    pub fn find_layer(&self, group: &ShaderGroupRef, layername: Ustring) -> Result<i32, Error> {
+        let layer = unsafe { ffi::ShadingSystem_find_layer(self.ss, group.group, layername.ptr) };
+        if layer < 0 {
+            Err(Error::LayerNotFound(layername.to_string()))
         } else {
-            Err(Error::ExecuteFailed)
+            Ok(layer)
         }
     }
 
@@ -325,19 +801,40 @@ impl ShadingSystem {
     /// separately, or by concatenating "layername.symbolname", but note
     /// that the latter will involve string manipulation inside find_symbol
     /// and is much more expensive than specifying them separately.
-    pub fn find_symbol(
+    ///
+    /// Symbols that are found are cached on the group, so repeated lookups
+    /// of the same name (e.g. AOVs fetched per shading point) only cost a
+    /// hash lookup.
+    pub fn find_symbol<'g>(
         &self,
-        group: &ShaderGroupRef,
+        group: &'g ShaderGroupRef,
         symbolname: Ustring,
-    ) -> Result<ShaderSymbol, Error> {
-        let symbol =
-            unsafe { ffi::ShadingSystem_find_symbol(self.ss, group.group, symbolname.ptr) };
+    ) -> Result<ShaderSymbol<'g>, Error> {
+        group
+            .cached_symbol(None, &symbolname, || unsafe {
+                ffi::ShadingSystem_find_symbol(self.ss, group.group, symbolname.ptr)
+            })
+            .ok_or_else(|| Error::SymbolNotFound(symbolname.to_string()))
+    }
 
-        if symbol.is_null() {
-            Err(Error::SymbolNotFound(symbolname.to_string()))
-        } else {
-            Ok(ShaderSymbol { symbol })
-        }
+    /// Search for an output symbol by name in the named layer of the
+    /// optimized shader group. Results are cached as for find_symbol.
+    pub fn find_symbol_in_layer<'g>(
+        &self,
+        group: &'g ShaderGroupRef,
+        layername: Ustring,
+        symbolname: Ustring,
+    ) -> Result<ShaderSymbol<'g>, Error> {
+        group
+            .cached_symbol(Some(&layername), &symbolname, || unsafe {
+                ffi::ShadingSystem_find_symbol_in_layer(
+                    self.ss,
+                    group.group,
+                    layername.ptr,
+                    symbolname.ptr,
+                )
+            })
+            .ok_or_else(|| Error::SymbolNotFound(format!("{}.{}", layername, symbolname)))
     }
 
     /// Given an opaque ShaderSymbol*, return the TypeDesc describing it.
@@ -353,10 +850,79 @@ impl ShadingSystem {
     /// prior for this context, but it is a very inexpensive operation.
     pub fn symbol_address(
         &self,
-        ctx: ShadingContext,
+        ctx: ContextRef<'_>,
         symbol: ShaderSymbol,
     ) -> *const std::ffi::c_void {
-        unsafe { ffi::ShadingSystem_symbol_address(self.ss, ctx, symbol.symbol) }
+        unsafe { ffi::ShadingSystem_symbol_address(self.ss, ctx.ctx, symbol.symbol) }
+    }
+
+    /// Copy the value of a symbol out of a context that has executed the
+    /// group it belongs to, checking that the symbol's type can be read as
+    /// `T`. See the SymbolValue trait for the supported types.
+    ///
+    /// # Safety
+    /// The last execution in `ctx` must have been of the group `symbol`
+    /// was found in. OSL only knows where the symbol lives in that group's
+    /// heap, so reading it from a context that ran another group, or
+    /// nothing at all, reads stale or out-of-bounds memory.
+    pub unsafe fn symbol_value<T: SymbolValue>(
+        &self,
+        ctx: ContextRef<'_>,
+        symbol: &ShaderSymbol,
+    ) -> Result<T, Error> {
+        let td = ffi::ShadingSystem_symbol_typedesc(self.ss, symbol.symbol);
+        let ptr = self.checked_symbol_address::<T>(ctx.ctx, symbol, &td)?;
+        Ok(T::read(ptr, &td))
+    }
+
+    /// The luminance of a color, using the coefficients of the shading
+    /// system's "colorspace" as the shading language's `luminance()` does.
+    pub fn luminance(&self, c: Color3) -> f32 {
+        unsafe { ffi::ShadingSystem_luminance(self.ss, &c as *const Color3 as *const ffi::Color3) }
+    }
+
+    /// Like symbol_value, but also return the x and y derivatives of the
+    /// symbol, which OSL stores after the value in the same layout as
+    /// Dual2.
+    ///
+    /// # Safety
+    /// As for symbol_value. In addition, OSL does not report whether a
+    /// symbol carries derivatives, so the
+    /// caller must know that it does: either because it's a global or
+    /// output that the compiled shader flagged with %derivs, or because
+    /// the "force_derivs" attribute is set. Otherwise the derivatives read
+    /// are whatever happens to follow the value in the context's heap.
+    pub unsafe fn symbol_derivs<T: SymbolValue>(
+        &self,
+        ctx: ContextRef<'_>,
+        symbol: &ShaderSymbol,
+    ) -> Result<Dual2<T>, Error> {
+        let td = ffi::ShadingSystem_symbol_typedesc(self.ss, symbol.symbol);
+        let ptr = self.checked_symbol_address::<T>(ctx.ctx, symbol, &td)?;
+        let size = T::size(&td);
+        Ok(Dual2::new(
+            T::read(ptr, &td),
+            T::read(ptr.add(size), &td),
+            T::read(ptr.add(2 * size), &td),
+        ))
+    }
+
+    fn checked_symbol_address<T: SymbolValue>(
+        &self,
+        ctx: ShadingContext,
+        symbol: &ShaderSymbol,
+        td: &TypeDesc,
+    ) -> Result<*const u8, Error> {
+        if !T::matches(td) {
+            return Err(Error::SymbolTypeMismatch(*td, T::NAME));
+        }
+
+        let ptr = unsafe { ffi::ShadingSystem_symbol_address(self.ss, ctx, symbol.symbol) };
+        if ptr.is_null() {
+            Err(Error::SymbolAddressFailed)
+        } else {
+            Ok(ptr as *const u8)
+        }
     }
 
     pub fn shade_image(
@@ -368,9 +934,9 @@ impl ShadingSystem {
         shadelocations: i32,
         roi: ROI,
     ) -> Result<(), Error> {
-        unsafe {
+        capture_messages(|| unsafe {
             let defaultsg = if let Some(sg) = defaultsg {
-                sg as *const ShaderGlobals
+                sg as *const ShaderGlobals as ffi::ConstShaderGlobalsPtr
             } else {
                 std::ptr::null()
             };
@@ -378,8 +944,8 @@ impl ShadingSystem {
                 self.ss,
                 group.group,
                 defaultsg,
-                imagebuf.buf,
-                outputs.as_ptr(),
+                imagebuf.buf as ffi::ImageBufPtr,
+                outputs.as_ptr() as *const ffi::ustring,
                 outputs.len() as i32,
                 shadelocations,
                 roi,
@@ -388,22 +954,93 @@ impl ShadingSystem {
             } else {
                 Err(Error::ShadeImageFailed)
             }
-        }
+        })
     }
 }
 
 impl Drop for ShadingSystem {
     fn drop(&mut self) {
+        // Thread infos must be destroyed before the ShadingSystem is. Keep
+        // the pool locked until then, in case a thread is exiting.
+        let mut contexts = self.contexts.contexts.lock().unwrap();
+        for (_, tc) in contexts.drain() {
+            self.contexts.destroy(tc);
+        }
+
         unsafe {
             ffi::ShadingSystem_destroy(self.ss);
         }
     }
 }
 
+struct ContextGuard<'a> {
+    pool: &'a ContextPool,
+    id: ThreadId,
+    tc: Option<ThreadContext>,
+}
+
+impl<'a> Drop for ContextGuard<'a> {
+    fn drop(&mut self) {
+        let tc = self.tc.take().unwrap();
+        let previous = self.pool.contexts.lock().unwrap().insert(self.id, tc);
+        // A nested with_context() on this thread returned its context first,
+        // keep ours and throw that one away.
+        if let Some(previous) = previous {
+            self.pool.destroy(previous);
+        }
+    }
+}
+
 pub struct ShaderGroup {
     pub group: ffi::ShaderGroupRef,
+    /// Symbols found by name so far, keyed on the (layer, symbol) ustring
+    /// pointers. Layer is null when searching all layers.
+    symbols: RwLock<HashMap<(usize, usize), ffi::ShaderSymbolPtr>>,
+}
+
+impl ShaderGroup {
+    fn cached_symbol<F>(
+        &self,
+        layername: Option<&Ustring>,
+        symbolname: &Ustring,
+        find: F,
+    ) -> Option<ShaderSymbol>
+    where
+        F: FnOnce() -> ffi::ShaderSymbolPtr,
+    {
+        let key = (
+            layername.map_or(0, |l| l.ptr as usize),
+            symbolname.ptr as usize,
+        );
+
+        // Lookups of cached symbols from many threads at once only need to
+        // share the lock
+        let cached = self.symbols.read().unwrap().get(&key).copied();
+        let symbol = match cached {
+            Some(symbol) => symbol,
+            None => {
+                let symbol = find();
+                // Don't remember misses, the group may not have been
+                // optimized yet
+                if symbol.is_null() {
+                    return None;
+                }
+                *self.symbols.write().unwrap().entry(key).or_insert(symbol)
+            }
+        };
+
+        Some(ShaderSymbol {
+            symbol,
+            group: PhantomData,
+        })
+    }
 }
 
+// OSL guards the mutable parts of a ShaderGroup (optimization and JIT) with
+// its own mutex, so groups may be shared between threads.
+unsafe impl Send for ShaderGroup {}
+unsafe impl Sync for ShaderGroup {}
+
 impl Drop for ShaderGroup {
     fn drop(&mut self) {
         unsafe {
@@ -414,28 +1051,160 @@ impl Drop for ShaderGroup {
 
 pub type ShaderGroupRef = Arc<ShaderGroup>;
 
-pub struct ShaderSymbol {
-    symbol: ffi::ShaderSymbolPtr,
+/// An opaque handle to a symbol in an optimized shader group, as returned
+/// by find_symbol. It is cheap to copy and remains valid for as long as the
+/// group it was found in.
+#[derive(Copy, Clone)]
+pub struct ShaderSymbol<'g> {
+    pub(crate) symbol: ffi::ShaderSymbolPtr,
+    group: PhantomData<&'g ShaderGroup>,
 }
 
-pub extern "C" fn handle_errors(level: i32, msg: *const std::os::raw::c_char) {
-    let msg = unsafe {
-        std::ffi::CStr::from_ptr(msg)
-            .to_string_lossy()
-            .into_owned()
-            .to_string()
-    };
-    if level == ErrCode::Debug as i32 {
-        println!("DEBUG: {}", msg);
-    } else if level == ErrCode::Info as i32 {
-        println!("INFO: {}", msg);
-    } else if level == ErrCode::Message as i32 {
-        println!("MESSAGE: {}", msg);
-    } else if level == ErrCode::Warning as i32 {
-        println!("WARNING: {}", msg);
-    } else if level == ErrCode::Error as i32 {
-        println!("ERROR: {}", msg);
-    } else if level == ErrCode::Severe as i32 {
-        println!("SEVERE: {}", msg);
+// Symbols are never modified once the group has been optimized
+unsafe impl<'g> Send for ShaderSymbol<'g> {}
+unsafe impl<'g> Sync for ShaderSymbol<'g> {}
+
+/// What a shader reported while running for one shading point, as returned
+/// by ShadingSystem::execute_with_log.
+#[derive(Debug, Clone)]
+pub struct ShaderLog {
+    /// The shading point's position and surface parameters, to correlate
+    /// the messages with e.g. a pixel.
+    pub P: V3f32,
+    pub u: f32,
+    pub v: f32,
+    /// Everything the shader printed, in order.
+    pub messages: Vec<Message>,
+}
+
+/// A layer of a shader group to run with ShadingSystem::execute_layer
+pub enum Layer<'a> {
+    /// The layer at this index in the group
+    Index(i32),
+    /// The layer with this name
+    Name(Ustring),
+    /// The layer that contains this symbol
+    Symbol(ShaderSymbol<'a>),
+}
+
+impl<'a> From<i32> for Layer<'a> {
+    fn from(index: i32) -> Layer<'a> {
+        Layer::Index(index)
+    }
+}
+
+impl<'a> From<Ustring> for Layer<'a> {
+    fn from(name: Ustring) -> Layer<'a> {
+        Layer::Name(name)
+    }
+}
+
+impl<'a> From<ShaderSymbol<'a>> for Layer<'a> {
+    fn from(symbol: ShaderSymbol<'a>) -> Layer<'a> {
+        Layer::Symbol(symbol)
+    }
+}
+
+impl<'a, 'b> From<&'b ShaderSymbol<'a>> for Layer<'a> {
+    fn from(symbol: &'b ShaderSymbol<'a>) -> Layer<'a> {
+        Layer::Symbol(*symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_renderer::TestRenderer;
+
+    fn noisetest() -> (Arc<Mutex<TestRenderer>>, ShadingSystem, ShaderGroupRef) {
+        let renderer = unsafe { TestRenderer::new(1, 1) };
+        let rs: Arc<dyn RendererServices + Send + Sync> = renderer.clone();
+        let mut ss = ShadingSystem::new(rs);
+        ss.attribute("renderer_outputs", &["Cout"][..])
+            .expect("Failed to set renderer_outputs attribute");
+        ss.load_memory_compiled_shader("noisetest", include_str!("../osl/noisetest.oso"))
+            .expect("Could not load shader from memory");
+
+        let group = ss
+            .shader_group_begin("")
+            .expect("Could not begin shader group");
+        ss.shader(&group, "surface", "noisetest", "")
+            .expect("Shader creation failed");
+        ss.shader_group_end(&group)
+            .expect("Could not end shader group");
+        (renderer, ss, group)
+    }
+
+    fn pooled(ss: &ShadingSystem) -> usize {
+        ss.contexts.contexts.lock().unwrap().len()
+    }
+
+    #[test]
+    fn with_context_threads() {
+        let (renderer, ss, group) = noisetest();
+        let ss = Arc::new(ss);
+
+        let threads = (0..4)
+            .map(|i| {
+                let ss = Arc::clone(&ss);
+                let group = Arc::clone(&group);
+                let renderer = Arc::clone(&renderer);
+                thread::spawn(move || {
+                    let rsw = renderer.lock().unwrap().rsw;
+                    let mut used = Vec::new();
+                    for _ in 0..8 {
+                        ss.with_context(|ctx| {
+                            let mut sg = ShaderGlobals::new(ctx, rsw);
+                            sg.u = i as f32 / 4.0;
+                            ss.execute(ctx, &group, &mut sg, true)
+                                .expect("Execute failed");
+                            used.push(ctx.ctx as usize);
+                        })
+                        .expect("Could not get shading context");
+                    }
+                    // Every call on a thread gets the same context
+                    assert!(used.iter().all(|c| *c == used[0]));
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // The threads have exited, taking their contexts with them
+        assert_eq!(pooled(&ss), 0);
+    }
+
+    #[test]
+    fn nested_with_context() {
+        let (_renderer, ss, _group) = noisetest();
+
+        let (outer, inner) = ss
+            .with_context(|outer| {
+                let inner = ss
+                    .with_context(|inner| inner.ctx as usize)
+                    .expect("Could not get inner shading context");
+                (outer.ctx as usize, inner)
+            })
+            .expect("Could not get outer shading context");
+        assert_ne!(outer, inner);
+
+        // Only the outer context is kept for this thread
+        assert_eq!(pooled(&ss), 1);
+        let again = ss
+            .with_context(|ctx| ctx.ctx as usize)
+            .expect("Could not get shading context");
+        assert_eq!(again, outer);
+        assert_eq!(pooled(&ss), 1);
+    }
+
+    #[test]
+    fn optimize_all_groups_threads() {
+        let (_renderer, ss, group) = noisetest();
+        ss.optimize_all_groups(2)
+            .expect("Could not optimize groups");
+        ss.find_symbol(&group, Ustring::new("Cout"))
+            .expect("Could not find Cout symbol");
     }
 }
@@ -1,325 +1,321 @@
-use std::os::raw::c_void;
+use std::os::raw::{c_char, c_void};
 
 use oiio::typedesc;
 use oiio::typedesc::TypeDesc;
+use oiio::Ustring;
 
 use crate::ffi;
+use crate::math::{v3f32, Color3, M4f32, Matrix44, V3f32, Vec3};
 use crate::shading_system::ShaderGroupRef;
+use crate::{cstring, to_string, Error};
 
+/// Types that can be given as the value of an attribute with
+/// ShadingSystem::attribute and group_attribute.
 pub trait ShadingSystemAttribute {
-    const TYPEDESC: TypeDesc;
+    /// Call `set` with the TypeDesc of the value and a pointer to it, laid
+    /// out as OSL expects. Fails if the value can't be given to OSL, e.g. a
+    /// string containing a NUL byte or an empty array.
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error>;
+
+    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> Result<bool, Error> {
+        let name = cstring(name)?;
+        self.with_value(|td, val| unsafe {
+            ffi::ShadingSystem_attribute(ss, name.as_ptr(), td, val)
+        })
+    }
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool;
     fn set_group_attribute(
         &self,
         name: &str,
         ss: ffi::ShadingSystem,
         group: &ShaderGroupRef,
-    ) -> bool;
+    ) -> Result<bool, Error> {
+        let name = cstring(name)?;
+        self.with_value(|td, val| unsafe {
+            ffi::ShadingSystem_group_attribute(ss, group.group, name.as_ptr(), td, val)
+        })
+    }
 }
 
-impl ShadingSystemAttribute for i32 {
-    const TYPEDESC: TypeDesc = typedesc::INT32;
+/// A single attribute value of a type OSL knows. Every AttributeType is
+/// also a ShadingSystemAttribute, as are slices and Vecs of them.
+pub trait AttributeType {
+    const TYPEDESC: TypeDesc;
+    /// The value as OSL lays it out in memory
+    type Raw;
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                Self::TYPEDESC,
-                self as *const i32 as *const c_void,
-            )
+    fn to_raw(&self) -> Self::Raw;
+}
+
+/// A V3f32 to be given to OSL as a color rather than a vector.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Color(pub V3f32);
+
+/// A V3f32 to be given to OSL as a point rather than a vector.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Point(pub V3f32);
+
+/// Implement AttributeType and ShadingSystemAttribute for a type, given its
+/// TypeDesc and, if it isn't laid out as OSL expects, the type and function
+/// to convert it to one that is.
+macro_rules! attribute_type {
+    ($t:ty, $td:expr) => {
+        attribute_type!($t, $td, $t, |v: &$t| *v);
+    };
+    ($t:ty, $td:expr, $raw:ty, $to_raw:expr) => {
+        impl AttributeType for $t {
+            const TYPEDESC: TypeDesc = $td;
+            type Raw = $raw;
+
+            fn to_raw(&self) -> $raw {
+                ($to_raw)(self)
+            }
         }
-    }
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                Self::TYPEDESC,
-                self as *const i32 as *const c_void,
-            )
+        impl ShadingSystemAttribute for $t {
+            fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(
+                &self,
+                set: F,
+            ) -> Result<R, Error> {
+                let raw = self.to_raw();
+                Ok(set(Self::TYPEDESC, &raw as *const $raw as *const c_void))
+            }
         }
-    }
+    };
 }
 
-impl ShadingSystemAttribute for &[i32] {
-    const TYPEDESC: TypeDesc = typedesc::INT32;
+attribute_type!(i32, typedesc::INT32);
+attribute_type!(f32, typedesc::FLOAT);
+attribute_type!(f64, typedesc::DOUBLE);
+attribute_type!(V3f32, typedesc::VECTOR, [f32; 3], |v: &V3f32| [
+    v.x, v.y, v.z
+]);
+attribute_type!(Color, typedesc::COLOR, [f32; 3], |c: &Color| [
+    c.0.x, c.0.y, c.0.z
+]);
+attribute_type!(Point, typedesc::POINT, [f32; 3], |p: &Point| [
+    p.0.x, p.0.y, p.0.z
+]);
+// OSL matrices are Imath's, which are row-major
+attribute_type!(M4f32, typedesc::MATRIX44, [f32; 16], |m: &M4f32| {
+    let mut raw = [0f32; 16];
+    raw.copy_from_slice(m.transpose().as_slice());
+    raw
+});
+attribute_type!(Vec3, typedesc::VECTOR);
+attribute_type!(Color3, typedesc::COLOR);
+attribute_type!(Matrix44, typedesc::MATRIX44);
+attribute_type!(Ustring, typedesc::STRING, *const c_char, |u: &Ustring| u
+    .ptr);
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                self.as_ptr() as *const i32 as *const c_void,
-            )
-        }
+impl<T: AttributeType> ShadingSystemAttribute for &[T] {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        let raw = self.iter().map(|v| v.to_raw()).collect::<Vec<_>>();
+        Ok(set(
+            array_typedesc(T::TYPEDESC, self.len())?,
+            raw.as_ptr() as *const c_void,
+        ))
     }
+}
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                self.as_ptr() as *const i32 as *const c_void,
-            )
-        }
+impl<T: AttributeType> ShadingSystemAttribute for Vec<T> {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        self.as_slice().with_value(set)
     }
 }
 
-impl ShadingSystemAttribute for f32 {
-    const TYPEDESC: TypeDesc = typedesc::FLOAT;
+impl ShadingSystemAttribute for &str {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        with_strings(&[*self], |_, val| set(typedesc::STRING, val))
+    }
+}
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                Self::TYPEDESC,
-                self as *const f32 as *const c_void,
-            )
-        }
+impl ShadingSystemAttribute for String {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        self.as_str().with_value(set)
     }
+}
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                Self::TYPEDESC,
-                self as *const f32 as *const c_void,
-            )
-        }
+impl ShadingSystemAttribute for &[&str] {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        with_strings(self, set)
     }
 }
 
-impl ShadingSystemAttribute for &[f32] {
-    const TYPEDESC: TypeDesc = typedesc::FLOAT;
+impl ShadingSystemAttribute for &[String] {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        with_strings(self, set)
+    }
+}
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                self.as_ptr() as *const f32 as *const c_void,
-            )
-        }
+impl ShadingSystemAttribute for Vec<&str> {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        with_strings(self, set)
     }
+}
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                self.as_ptr() as *const f32 as *const c_void,
-            )
-        }
+impl ShadingSystemAttribute for Vec<String> {
+    fn with_value<R, F: FnOnce(TypeDesc, *const c_void) -> R>(&self, set: F) -> Result<R, Error> {
+        with_strings(self, set)
     }
 }
 
-impl ShadingSystemAttribute for &str {
-    const TYPEDESC: TypeDesc = typedesc::STRING;
+/// OSL takes strings as an array of *char
+fn with_strings<S, R, F>(values: &[S], set: F) -> Result<R, Error>
+where
+    S: AsRef<str>,
+    F: FnOnce(TypeDesc, *const c_void) -> R,
+{
+    let values = values
+        .iter()
+        .map(|v| cstring(v.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let value_ptrs = values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
+    Ok(set(
+        array_typedesc(typedesc::STRING, values.len())?,
+        value_ptrs.as_ptr() as *const c_void,
+    ))
+}
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        let value = std::ffi::CString::new(*self).unwrap();
-        let value = [value.as_ptr()]; // OSL expects a **char
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                Self::TYPEDESC,
-                value.as_ptr() as *const c_void,
-            )
-        }
+/// The type of an array of `len` elements. There are no empty arrays, as
+/// OSL would take an arraylen of 0 to mean a single value.
+fn array_typedesc(element: TypeDesc, len: usize) -> Result<TypeDesc, Error> {
+    if len == 0 {
+        return Err(Error::EmptyArray);
     }
+    Ok(TypeDesc::new(
+        element.basetype,
+        element.aggregate,
+        element.vecsemantics,
+        len as i32,
+    ))
+}
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        let value = std::ffi::CString::new(*self).unwrap();
-        let value = [value.as_ptr()]; // OSL expects a **char
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                Self::TYPEDESC,
-                value.as_ptr() as *const c_void,
-            )
-        }
-    }
+/// Types that attributes can be read back as with
+/// ShadingSystem::get_attribute and get_group_attribute. These are the
+/// owned equivalents of the ShadingSystemAttribute types.
+pub trait ShadingSystemAttributeValue: Sized {
+    const TYPEDESC: TypeDesc;
+
+    /// Read the value, using `get` to ask OSL to fill in a buffer of the
+    /// given type. Arrays call `arraylen` for the number of elements there
+    /// are, which is negative if the attribute isn't an array.
+    fn get_attribute<L, F>(arraylen: L, get: F) -> Option<Self>
+    where
+        L: FnOnce() -> i32,
+        F: FnOnce(TypeDesc, *mut c_void) -> bool;
 }
 
-impl ShadingSystemAttribute for &[String] {
-    const TYPEDESC: TypeDesc = typedesc::STRING;
+/// Implement ShadingSystemAttributeValue for a single value, given its
+/// TypeDesc, the type OSL writes it as with an initial value for that, and
+/// the function to convert from it.
+macro_rules! attribute_value {
+    ($t:ty, $td:expr, $raw:ty, $zero:expr, $from_raw:expr) => {
+        impl ShadingSystemAttributeValue for $t {
+            const TYPEDESC: TypeDesc = $td;
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        let values = self
-            .iter()
-            .map(|v| std::ffi::CString::new(v.as_str()).unwrap())
-            .collect::<Vec<_>>();
-        let value_ptrs = values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
-
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                value_ptrs.as_ptr() as *const c_void,
-            )
+            fn get_attribute<L, F>(_: L, get: F) -> Option<$t>
+            where
+                L: FnOnce() -> i32,
+                F: FnOnce(TypeDesc, *mut c_void) -> bool,
+            {
+                let mut raw: $raw = $zero;
+                if get(Self::TYPEDESC, &mut raw as *mut $raw as *mut c_void) {
+                    Some(($from_raw)(raw))
+                } else {
+                    None
+                }
+            }
         }
+    };
+}
+
+attribute_value!(i32, typedesc::INT32, i32, 0, |v| v);
+attribute_value!(f32, typedesc::FLOAT, f32, 0.0, |v| v);
+attribute_value!(f64, typedesc::DOUBLE, f64, 0.0, |v| v);
+attribute_value!(
+    V3f32,
+    typedesc::VECTOR,
+    [f32; 3],
+    [0.0; 3],
+    |v: [f32; 3]| v3f32(v[0], v[1], v[2])
+);
+// OSL matrices are Imath's, which are row-major
+attribute_value!(
+    M4f32,
+    typedesc::MATRIX44,
+    [f32; 16],
+    [0.0; 16],
+    |m: [f32; 16]| M4f32::from_row_slice(&m)
+);
+attribute_value!(Ustring, typedesc::STRING, Ustring, Ustring::new(""), |u| u);
+// OSL writes a ustring, i.e. a *char
+attribute_value!(
+    String,
+    typedesc::STRING,
+    *const c_char,
+    std::ptr::null(),
+    |s| unsafe { to_string(s) }
+);
+
+impl ShadingSystemAttributeValue for Vec<i32> {
+    const TYPEDESC: TypeDesc = typedesc::INT32;
+
+    fn get_attribute<L, F>(arraylen: L, get: F) -> Option<Vec<i32>>
+    where
+        L: FnOnce() -> i32,
+        F: FnOnce(TypeDesc, *mut c_void) -> bool,
+    {
+        get_array(Self::TYPEDESC, 0i32, arraylen(), get)
     }
+}
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        let values = self
-            .iter()
-            .map(|v| std::ffi::CString::new(v.as_str()).unwrap())
-            .collect::<Vec<_>>();
-        let value_ptrs = values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
-
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                value_ptrs.as_ptr() as *const c_void,
-            )
-        }
+impl ShadingSystemAttributeValue for Vec<f32> {
+    const TYPEDESC: TypeDesc = typedesc::FLOAT;
+
+    fn get_attribute<L, F>(arraylen: L, get: F) -> Option<Vec<f32>>
+    where
+        L: FnOnce() -> i32,
+        F: FnOnce(TypeDesc, *mut c_void) -> bool,
+    {
+        get_array(Self::TYPEDESC, 0f32, arraylen(), get)
     }
 }
 
-impl ShadingSystemAttribute for &[&str] {
+impl ShadingSystemAttributeValue for Vec<String> {
     const TYPEDESC: TypeDesc = typedesc::STRING;
 
-    fn set_attribute(&self, name: &str, ss: ffi::ShadingSystem) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        let values = self
-            .iter()
-            .map(|v| std::ffi::CString::new(*v).unwrap())
-            .collect::<Vec<_>>();
-        let value_ptrs = values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
-
-        unsafe {
-            ffi::ShadingSystem_attribute(
-                ss,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                value_ptrs.as_ptr() as *const c_void,
-            )
-        }
+    fn get_attribute<L, F>(arraylen: L, get: F) -> Option<Vec<String>>
+    where
+        L: FnOnce() -> i32,
+        F: FnOnce(TypeDesc, *mut c_void) -> bool,
+    {
+        get_array(Self::TYPEDESC, std::ptr::null::<c_char>(), arraylen(), get)
+            .map(|values| values.iter().map(|v| unsafe { to_string(*v) }).collect())
     }
+}
 
-    fn set_group_attribute(
-        &self,
-        name: &str,
-        ss: ffi::ShadingSystem,
-        group: &ShaderGroupRef,
-    ) -> bool {
-        let name = std::ffi::CString::new(name).unwrap();
-        let values = self
-            .iter()
-            .map(|v| std::ffi::CString::new(*v).unwrap())
-            .collect::<Vec<_>>();
-        let value_ptrs = values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
-
-        unsafe {
-            ffi::ShadingSystem_group_attribute(
-                ss,
-                group.group,
-                name.as_ptr(),
-                TypeDesc::new(
-                    Self::TYPEDESC.basetype,
-                    Self::TYPEDESC.aggregate,
-                    Self::TYPEDESC.vecsemantics,
-                    self.len() as i32,
-                ),
-                value_ptrs.as_ptr() as *const c_void,
-            )
+/// Read an array attribute of `len` elements.
+fn get_array<T: Copy, F: FnOnce(TypeDesc, *mut c_void) -> bool>(
+    element: TypeDesc,
+    zero: T,
+    len: i32,
+    get: F,
+) -> Option<Vec<T>> {
+    match len {
+        len if len < 0 => None,
+        // An arraylen of 0 would ask for a scalar
+        0 => Some(Vec::new()),
+        len => {
+            let mut values = vec![zero; len as usize];
+            if get(
+                array_typedesc(element, len as usize).ok()?,
+                values.as_mut_ptr() as *mut c_void,
+            ) {
+                Some(values)
+            } else {
+                None
+            }
         }
     }
 }
@@ -0,0 +1,190 @@
+use oiio::typedesc;
+use oiio::typedesc::TypeDesc;
+use oiio::Ustring;
+
+use crate::math::*;
+
+// TypeDesc::AGGREGATE values, as in OIIO's typedesc.h
+const AGGREGATE_SCALAR: u8 = 1;
+const AGGREGATE_VEC3: u8 = 3;
+const AGGREGATE_MATRIX44: u8 = 16;
+
+/// A Rust type that the value of a shader symbol can be copied into after
+/// execution. See ShadingSystem::symbol_value.
+pub trait SymbolValue: Sized {
+    /// Human-readable name of the type, used in error messages.
+    const NAME: &'static str;
+
+    /// Returns true if a symbol described by `td` can be read as this type.
+    fn matches(td: &TypeDesc) -> bool;
+
+    /// Size in bytes of the symbol's value (not counting derivatives).
+    fn size(td: &TypeDesc) -> usize;
+
+    /// Copy a value out of symbol memory. `ptr` must point to valid data of
+    /// a type for which `matches(td)` returned true.
+    unsafe fn read(ptr: *const u8, td: &TypeDesc) -> Self;
+}
+
+fn is_scalar(td: &TypeDesc, base: &TypeDesc, aggregate: u8) -> bool {
+    td.basetype as u8 == base.basetype as u8 && td.aggregate as u8 == aggregate && td.arraylen == 0
+}
+
+impl SymbolValue for f32 {
+    const NAME: &'static str = "f32";
+
+    fn matches(td: &TypeDesc) -> bool {
+        is_scalar(td, &typedesc::FLOAT, AGGREGATE_SCALAR)
+    }
+
+    fn size(_td: &TypeDesc) -> usize {
+        std::mem::size_of::<f32>()
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> f32 {
+        std::ptr::read_unaligned(ptr as *const f32)
+    }
+}
+
+impl SymbolValue for i32 {
+    const NAME: &'static str = "i32";
+
+    fn matches(td: &TypeDesc) -> bool {
+        is_scalar(td, &typedesc::INT32, AGGREGATE_SCALAR)
+    }
+
+    fn size(_td: &TypeDesc) -> usize {
+        std::mem::size_of::<i32>()
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> i32 {
+        std::ptr::read_unaligned(ptr as *const i32)
+    }
+}
+
+/// Matches any of OSL's point, vector, normal and color types.
+impl SymbolValue for V3f32 {
+    const NAME: &'static str = "V3f32";
+
+    fn matches(td: &TypeDesc) -> bool {
+        is_scalar(td, &typedesc::FLOAT, AGGREGATE_VEC3)
+    }
+
+    fn size(_td: &TypeDesc) -> usize {
+        3 * std::mem::size_of::<f32>()
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> V3f32 {
+        let v = std::ptr::read_unaligned(ptr as *const [f32; 3]);
+        v3f32(v[0], v[1], v[2])
+    }
+}
+
+impl SymbolValue for M4f32 {
+    const NAME: &'static str = "M4f32";
+
+    fn matches(td: &TypeDesc) -> bool {
+        is_scalar(td, &typedesc::FLOAT, AGGREGATE_MATRIX44)
+    }
+
+    fn size(_td: &TypeDesc) -> usize {
+        16 * std::mem::size_of::<f32>()
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> M4f32 {
+        // OSL matrices are Imath's, which are row-major
+        let m = std::ptr::read_unaligned(ptr as *const [f32; 16]);
+        M4f32::from_row_slice(&m)
+    }
+}
+
+/// Like V3f32, matches any of OSL's point, vector, normal and color types.
+impl SymbolValue for Vec3 {
+    const NAME: &'static str = "Vec3";
+
+    fn matches(td: &TypeDesc) -> bool {
+        V3f32::matches(td)
+    }
+
+    fn size(td: &TypeDesc) -> usize {
+        V3f32::size(td)
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> Vec3 {
+        std::ptr::read_unaligned(ptr as *const Vec3)
+    }
+}
+
+/// Like V3f32, matches any of OSL's point, vector, normal and color types.
+impl SymbolValue for Color3 {
+    const NAME: &'static str = "Color3";
+
+    fn matches(td: &TypeDesc) -> bool {
+        V3f32::matches(td)
+    }
+
+    fn size(td: &TypeDesc) -> usize {
+        V3f32::size(td)
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> Color3 {
+        std::ptr::read_unaligned(ptr as *const Color3)
+    }
+}
+
+impl SymbolValue for Matrix44 {
+    const NAME: &'static str = "Matrix44";
+
+    fn matches(td: &TypeDesc) -> bool {
+        M4f32::matches(td)
+    }
+
+    fn size(td: &TypeDesc) -> usize {
+        M4f32::size(td)
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> Matrix44 {
+        std::ptr::read_unaligned(ptr as *const Matrix44)
+    }
+}
+
+impl SymbolValue for Ustring {
+    const NAME: &'static str = "Ustring";
+
+    fn matches(td: &TypeDesc) -> bool {
+        is_scalar(td, &typedesc::STRING, AGGREGATE_SCALAR)
+    }
+
+    fn size(_td: &TypeDesc) -> usize {
+        std::mem::size_of::<Ustring>()
+    }
+
+    unsafe fn read(ptr: *const u8, _td: &TypeDesc) -> Ustring {
+        std::ptr::read_unaligned(ptr as *const Ustring)
+    }
+}
+
+/// Matches fixed-length arrays of any of the other value types.
+impl<T: SymbolValue> SymbolValue for Vec<T> {
+    const NAME: &'static str = "Vec";
+
+    fn matches(td: &TypeDesc) -> bool {
+        td.arraylen > 0 && T::matches(&element_type(td))
+    }
+
+    fn size(td: &TypeDesc) -> usize {
+        td.arraylen as usize * T::size(&element_type(td))
+    }
+
+    unsafe fn read(ptr: *const u8, td: &TypeDesc) -> Vec<T> {
+        let element = element_type(td);
+        let stride = T::size(&element);
+        (0..td.arraylen as usize)
+            .map(|i| T::read(ptr.add(i * stride), &element))
+            .collect()
+    }
+}
+
+fn element_type(td: &TypeDesc) -> TypeDesc {
+    TypeDesc::new(td.basetype, td.aggregate, td.vecsemantics, 0)
+}
@@ -1,5 +1,7 @@
 use crate::ffi;
-use crate::renderer_services::RendererServices;
+use crate::math::Dual2;
+use crate::renderer_services::{RendererServices, UserData};
+use crate::shader_globals::ShaderGlobals;
 use crate::shading_system::{ShaderGroupRef, ShadingSystem};
 
 use oiio::imagebuf::ImageBuf;
@@ -7,8 +9,7 @@ use oiio::imageio::ImageSpec;
 use oiio::typedesc::TypeDesc;
 use std::os::raw::{c_char, c_void};
 
-use std::cell::RefCell;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 pub struct TestRenderer {
     pub rsw: ffi::RendererServicesWrapper,
@@ -19,7 +20,7 @@ pub struct TestRenderer {
     pub output_bufs: Vec<ImageBuf>,
 }
 
-pub extern "C" fn renderer_services_supports(rs_obj: *const c_void, service: *const c_char) -> i32 {
+pub extern "C" fn renderer_services_supports(rs_obj: *mut c_void, service: *const c_char) -> i32 {
     let service = unsafe {
         std::ffi::CStr::from_ptr(service)
             .to_string_lossy()
@@ -32,10 +33,14 @@ pub extern "C" fn renderer_services_supports(rs_obj: *const c_void, service: *co
     renderer.supports(&service)
 }
 
+// The wrapper is only handed to OSL, which may call back into us from any
+// thread.
+unsafe impl Send for TestRenderer {}
+
 impl TestRenderer {
-    pub unsafe fn new(width: i32, height: i32) -> Arc<RefCell<TestRenderer>> {
+    pub unsafe fn new(width: i32, height: i32) -> Arc<Mutex<TestRenderer>> {
         let rsw = ffi::RendererServicesWrapper_create();
-        let tr = Arc::new(RefCell::new(TestRenderer {
+        let tr = Arc::new(Mutex::new(TestRenderer {
             rsw,
             shaders: Vec::new(),
             width,
@@ -45,9 +50,9 @@ impl TestRenderer {
         }));
         ffi::RendererServicesWrapper_set_rust_object(
             rsw,
-            &(*tr.borrow()) as *const TestRenderer as *const c_void,
+            &(*tr.lock().unwrap()) as *const TestRenderer as *mut c_void,
         );
-        ffi::RendererServicesWrapper_setfn_supports(rsw, renderer_services_supports);
+        ffi::RendererServicesWrapper_setfn_supports(rsw, Some(renderer_services_supports));
         tr
     }
 
@@ -75,4 +80,41 @@ impl RendererServices for TestRenderer {
     fn get_wrapper(&self) -> ffi::RendererServicesWrapper {
         self.rsw
     }
+
+    fn get_userdata(&self, name: &str, sg: &ShaderGlobals, data: &mut UserData) -> bool {
+        // Twice u, so that it has derivatives
+        name == "userfloat" && data.set_float(Dual2::new(2.0 * sg.u, 2.0 * sg.dudx, 2.0 * sg.dudy))
+    }
+
+    fn texture(
+        &self,
+        filename: &str,
+        _sg: &ShaderGlobals,
+        s: Dual2<f32>,
+        t: Dual2<f32>,
+        result: &mut [f32],
+        dresult: Option<(&mut [f32], &mut [f32])>,
+    ) -> Option<bool> {
+        if filename != "st" {
+            return None;
+        }
+
+        // The texture is (s, t, 0)
+        for (i, r) in result.iter_mut().enumerate() {
+            *r = [s.val, t.val, 0.0].get(i).copied().unwrap_or(0.0);
+        }
+        if let Some((ds, dt)) = dresult {
+            for (i, (ds, dt)) in ds.iter_mut().zip(dt.iter_mut()).enumerate() {
+                *ds = if i == 0 { 1.0 } else { 0.0 };
+                *dt = if i == 1 { 1.0 } else { 0.0 };
+            }
+        }
+        Some(true)
+    }
+}
+
+impl RendererServices for Mutex<TestRenderer> {
+    fn get_wrapper(&self) -> ffi::RendererServicesWrapper {
+        self.lock().unwrap().rsw
+    }
 }
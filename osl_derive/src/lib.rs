@@ -65,7 +65,7 @@ pub fn closure(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
                         field_size: std::mem::align_of::<#ident>(),
                     });
 
-                    ss.register_closure(#closure_name,  #closure_id, &closure_params);
+                    ss.register_closure(#closure_name, #closure_id, &closure_params)
                 }
             }
             _ => panic!("Can only work on named fields"),
@@ -75,7 +75,7 @@ pub fn closure(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
 
     let expanded = quote! {
         impl #ident #generics #where_clause {
-            pub fn register_with(ss: &mut ShadingSystem) {
+            pub fn register_with(ss: &mut ShadingSystem) -> Result<(), Error> {
                 #register_fn
             }
         }